@@ -1,5 +1,6 @@
 #[macro_use]
 extern crate criterion;
+extern crate serde_json;
 extern crate tinytemplate;
 #[macro_use]
 extern crate serde_derive;
@@ -7,7 +8,7 @@ extern crate serde_derive;
 use criterion::Criterion;
 use tinytemplate::TinyTemplate;
 
-static TABLE_SOURCE: &'static str = "<html>
+static TABLE_SOURCE: &str = "<html>
     {{ for row in table }}
         <tr>{{ for value in row }}<td>{value}</td>{{ endfor }}</tr>
     {{ endfor }}
@@ -18,6 +19,16 @@ struct TableContext {
     table: Vec<Vec<usize>>,
 }
 
+static SUMMARY_SOURCE: &str = "<h1>{title}</h1>
+{{ for i in range(0, 10) }}<p>{title}: {summary.0} {summary.1}</p>{{ endfor }}";
+
+#[derive(Serialize)]
+struct SummaryContext {
+    title: String,
+    summary: Vec<usize>,
+    table: TableContext,
+}
+
 fn make_table_context(size: usize) -> TableContext {
     let mut table = Vec::with_capacity(size);
     for _ in 0..size {
@@ -39,6 +50,7 @@ fn parse(criterion: &mut Criterion) {
     });
 }
 
+#[allow(deprecated)]
 fn render(criterion: &mut Criterion) {
     let mut tt = TinyTemplate::new();
     tt.add_template("table", TABLE_SOURCE).unwrap();
@@ -54,5 +66,73 @@ fn render(criterion: &mut Criterion) {
     );
 }
 
-criterion_group!(benchmarks, parse, render);
+#[allow(deprecated)]
+fn render_value(criterion: &mut Criterion) {
+    let mut tt = TinyTemplate::new();
+    tt.add_template("table", TABLE_SOURCE).unwrap();
+
+    criterion.bench_function_over_inputs(
+        "render-table-value",
+        move |b, size| {
+            let data = serde_json::to_value(make_table_context(*size)).unwrap();
+
+            b.iter(|| tt.render_value("table", &data).unwrap());
+        },
+        vec![1usize, 5, 10, 50, 100, 200],
+    );
+}
+
+// The summary only looks at a small part of a large context.
+#[allow(deprecated)]
+fn render_summary(criterion: &mut Criterion) {
+    let mut tt = TinyTemplate::new();
+    tt.add_template("summary", SUMMARY_SOURCE).unwrap();
+
+    criterion.bench_function_over_inputs(
+        "render-summary",
+        move |b, size| {
+            let data = make_summary_context(*size);
+
+            b.iter(|| tt.render("summary", &data).unwrap());
+        },
+        vec![10usize, 100, 200],
+    );
+}
+
+// The same, converting the whole context to a serde_json::Value first for comparison.
+#[allow(deprecated)]
+fn render_summary_to_value(criterion: &mut Criterion) {
+    let mut tt = TinyTemplate::new();
+    tt.add_template("summary", SUMMARY_SOURCE).unwrap();
+
+    criterion.bench_function_over_inputs(
+        "render-summary-to-value",
+        move |b, size| {
+            let data = make_summary_context(*size);
+
+            b.iter(|| {
+                let value = serde_json::to_value(&data).unwrap();
+                tt.render_value("summary", &value).unwrap()
+            });
+        },
+        vec![10usize, 100, 200],
+    );
+}
+
+fn make_summary_context(size: usize) -> SummaryContext {
+    SummaryContext {
+        title: "Table".to_string(),
+        summary: vec![size, size * size],
+        table: make_table_context(size),
+    }
+}
+
+criterion_group!(
+    benchmarks,
+    parse,
+    render,
+    render_value,
+    render_summary,
+    render_summary_to_value
+);
 criterion_main!(benchmarks);
@@ -1,4 +1,6 @@
 #![allow(deprecated)]
+// str::strip_prefix and matches! are newer than our minimum supported Rust version.
+#![allow(clippy::manual_strip, clippy::match_like_matches_macro)]
 
 /// The compiler module houses the code which parses and compiles templates. TinyTemplate implements
 /// a simple bytecode interpreter (see the [instruction] module for more details) to render templates.
@@ -6,104 +8,267 @@
 /// template strings and generating the appropriate bytecode instructions.
 use error::Error::*;
 use error::{get_offset, Error, Result};
-use instruction::{Instruction, Path, PathStep};
+use instruction::{Comparison, FormatterCall, Instruction, NamedArgument, Operand, Path, PathStep};
+use serde_json;
+use std::collections::HashMap;
+use Delimiters;
 
 /// The end point of a branch or goto instruction is not known.
+#[allow(clippy::legacy_numeric_constants)]
 const UNKNOWN: usize = ::std::usize::MAX;
 
 /// The compiler keeps a stack of the open blocks so that it can ensure that blocks are closed in
 /// the right order. The Block type is a simple enumeration of the kinds of blocks that could be
 /// open. It may contain the instruction index corresponding to the start of the block.
-enum Block {
-    Branch(usize),
+///
+/// Branch blocks hold the conditional jumps which should be pointed at the next elif/else arm (or
+/// the endif, if there is none) when the condition is false. Branch and Else blocks also carry the
+/// indexes of the gotos at the end of each completed arm of an if/elif/else chain, which all need
+/// to be pointed at the endif once it is found. ForElse blocks carry the index of the goto which
+/// skips the else section of a for loop. Overridable blocks carry their name and the index of their
+/// Block instruction.
+enum Block<'template> {
+    Branch(Vec<usize>, Vec<usize>),
+    Else(Vec<usize>),
     For(usize),
+    ForElse(usize),
     With,
+    Overridable(&'template str, usize),
+    Macro(&'template str, Vec<&'template str>, usize),
 }
 
+/// The result of compiling a template. Along with the bytecode, this holds the name of the template
+/// which this one extends (if any) and the range of instructions making up each overridable block,
+/// which are needed to render templates that use inheritance. It also holds the parameters that
+/// the template declares (if any), which named arguments are checked against, and the macros that
+/// it defines or imports from other templates.
+pub(crate) struct CompiledTemplate<'template> {
+    pub instructions: Vec<Instruction<'template>>,
+    pub extends: Option<&'template str>,
+    pub blocks: HashMap<&'template str, (usize, usize)>,
+    pub params: Option<Vec<&'template str>>,
+    pub macros: HashMap<&'template str, MacroDefinition<'template>>,
+    pub imports: Vec<&'template str>,
+}
+
+/// A macro defined inside a template, consisting of its parameters and the range of instructions
+/// making up its body.
+pub(crate) struct MacroDefinition<'template> {
+    pub params: Vec<&'template str>,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The kinds of tags, each of which is marked by its own pair of delimiters.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TagKind {
+    Block,
+    Value,
+    Comment,
+}
+
+/// Parsed form of the condition of an if or elif tag. Conditions are compiled into a sequence of
+/// Branch and Compare instructions which jump as soon as the result is known, so the right-hand
+/// side of an `and` or `or` is only evaluated when it is needed.
+enum Condition<'template> {
+    Truthy(Operand<'template>),
+    Compare(Operand<'template>, Comparison, Operand<'template>),
+    Not(Box<Condition<'template>>),
+    And(Box<Condition<'template>>, Box<Condition<'template>>),
+    Or(Box<Condition<'template>>, Box<Condition<'template>>),
+}
+
+/// Words which are read as literals, operators or parts of tags, so they cannot be used as the names
+/// of variables, loop values, parameters or macros.
+static RESERVED_WORDS: [&str; 30] = [
+    "true", "false", "null", "and", "or", "not", "if", "elif", "else", "endif", "with", "as",
+    "endwith", "for", "in", "endfor", "let", "set", "extends", "params", "block", "endblock",
+    "super", "macro", "endmacro", "import", "include", "call", "raw", "endraw",
+];
+
 /// List of the known @-keywords so that we can error if the user spells them wrong.
-static KNOWN_KEYWORDS: [&str; 4] = ["@index", "@first", "@last", "@root"];
+static KNOWN_KEYWORDS: [&str; 10] = [
+    "@index",
+    "@index1",
+    "@revindex",
+    "@length",
+    "@first",
+    "@last",
+    "@odd",
+    "@even",
+    "@key",
+    "@root",
+];
+
+/// Characters which are part of the condition syntax, such as comparison operators. A path in a
+/// condition containing one of these is most likely a mistyped comparison, so it is rejected.
+static OPERATOR_CHARACTERS: &str = "=!<>()\"";
 
 /// The TemplateCompiler struct is responsible for parsing a template string and generating bytecode
-/// instructions based on it. The parser is a simple hand-written pattern-matching parser with no
-/// recursion, which makes it relatively easy to read.
+/// instructions based on it. The parser is a simple hand-written pattern-matching parser which only
+/// recurses to handle nested conditions, which makes it relatively easy to read.
 pub(crate) struct TemplateCompiler<'template> {
     original_text: &'template str,
     remaining_text: &'template str,
     instructions: Vec<Instruction<'template>>,
-    block_stack: Vec<(&'template str, Block)>,
+    block_stack: Vec<(&'template str, Block<'template>)>,
+    extends: Option<&'template str>,
+    blocks: HashMap<&'template str, (usize, usize)>,
+    params: Option<Vec<&'template str>>,
+    macros: HashMap<&'template str, MacroDefinition<'template>>,
+    imports: Vec<&'template str>,
+    /// The number of let tags in the current section of each open block, and at the top level of
+    /// the template. Their contexts are popped when the section ends.
+    let_counts: Vec<usize>,
 
     /// When we see a `{foo -}` or similar, we need to remember to left-trim the next text block we
     /// encounter.
     trim_next: bool,
+    /// With trim_blocks, we need to remember to remove the newline at the start of the next text
+    /// block after a block tag or comment.
+    trim_next_newline: bool,
+    trim_blocks: bool,
+    lstrip_blocks: bool,
+
+    delimiters: Delimiters,
+    /// The start delimiters to search for to find the end of a text block. Start delimiters which
+    /// begin with another start delimiter, like `{{` begins with `{`, can never be found first, so
+    /// they are left out.
+    text_ends: Vec<String>,
 }
 impl<'template> TemplateCompiler<'template> {
-    /// Create a new template compiler to parse and compile the given template.
-    pub fn new(text: &'template str) -> TemplateCompiler<'template> {
+    /// Create a new template compiler to parse and compile the given template, which uses the given
+    /// delimiters.
+    pub fn new(text: &'template str, delimiters: &Delimiters) -> TemplateCompiler<'template> {
+        let starts = [
+            &delimiters.block.0,
+            &delimiters.value.0,
+            &delimiters.comment.0,
+        ];
+        let text_ends = starts
+            .iter()
+            .filter(|start| {
+                !starts
+                    .iter()
+                    .any(|other| other.len() < start.len() && start.starts_with(other.as_str()))
+            })
+            .map(|start| start.to_string())
+            .collect();
         TemplateCompiler {
             original_text: text,
             remaining_text: text,
             instructions: vec![],
             block_stack: vec![],
+            extends: None,
+            blocks: HashMap::new(),
+            params: None,
+            macros: HashMap::new(),
+            imports: vec![],
+            let_counts: vec![0],
             trim_next: false,
+            trim_next_newline: false,
+            trim_blocks: false,
+            lstrip_blocks: false,
+            delimiters: delimiters.clone(),
+            text_ends,
         }
     }
 
+    /// Set whether to remove the first newline after each block tag or comment (trim_blocks), and
+    /// whether to strip the spaces and tabs between the start of a line and a block tag or comment
+    /// (lstrip_blocks).
+    pub fn block_whitespace(
+        mut self,
+        trim_blocks: bool,
+        lstrip_blocks: bool,
+    ) -> TemplateCompiler<'template> {
+        self.trim_blocks = trim_blocks;
+        self.lstrip_blocks = lstrip_blocks;
+        self
+    }
+
     /// Consume the template compiler to parse the template and return the generated bytecode.
-    pub fn compile(mut self) -> Result<Vec<Instruction<'template>>> {
+    pub fn compile(mut self) -> Result<CompiledTemplate<'template>> {
         while !self.remaining_text.is_empty() {
+            let tag_kind = self.next_tag_kind();
             // Comment, denoted by {# comment text #}
-            if self.remaining_text.starts_with("{#") {
+            if tag_kind == Some(TagKind::Comment) {
                 self.trim_next = false;
+                self.trim_next_newline = false;
 
-                let tag = self.consume_tag("#}")?;
-                let comment = tag[2..(tag.len() - 2)].trim();
-                if comment.starts_with('-') {
-                    self.trim_last_whitespace();
-                }
-                if comment.ends_with('-') {
-                    self.trim_next_whitespace();
-                }
+                let comment = self.consume_tag(TagKind::Comment)?.trim();
+                self.trim_around_tag(comment);
             // Block tag. Block tags are wrapped in {{ }} and always have one word at the start
             // to identify which kind of tag it is. Depending on the tag type there may be more.
-            } else if self.remaining_text.starts_with("{{") {
+            } else if tag_kind == Some(TagKind::Block) {
                 self.trim_next = false;
+                self.trim_next_newline = false;
 
                 let (discriminant, rest) = self.consume_block()?;
                 match discriminant {
                     "if" => {
-                        let (path, negated) = if rest.starts_with("not") {
-                            (self.parse_path(&rest[4..])?, true)
-                        } else {
-                            (self.parse_path(rest)?, false)
-                        };
+                        let condition = self.parse_condition(rest)?;
+                        let jumps = self.compile_condition(&condition, false);
                         self.block_stack
-                            .push((discriminant, Block::Branch(self.instructions.len())));
-                        self.instructions
-                            .push(Instruction::Branch(path, !negated, UNKNOWN));
+                            .push((discriminant, Block::Branch(jumps, vec![])));
+                        self.let_counts.push(0);
                     }
-                    "else" => {
+                    "elif" => {
+                        self.compile_elif(discriminant, rest)?;
+                    }
+                    "else" if starts_with_keyword(rest, "if") => {
+                        self.compile_elif(discriminant, rest[2..].trim())?;
+                    }
+                    "else" if self.in_for_block() => {
                         self.expect_empty(rest)?;
-                        let num_instructions = self.instructions.len() + 1;
-                        self.close_branch(num_instructions, discriminant)?;
+                        self.pop_lets();
+                        // End the loop body as endfor would, then skip over the else section. The
+                        // Iterate instruction jumps past that skip if the array was empty.
+                        let num_instructions = self.instructions.len();
+                        let goto_target = self.close_for(
+                            num_instructions + 1,
+                            num_instructions + 3,
+                            discriminant,
+                        )?;
+                        self.instructions.push(Instruction::Goto(goto_target));
+                        self.instructions.push(Instruction::PopContext);
+                        let goto_index = self.instructions.len();
+                        self.instructions.push(Instruction::Goto(UNKNOWN));
+                        self.instructions.push(Instruction::PopContext);
                         self.block_stack
-                            .push((discriminant, Block::Branch(self.instructions.len())));
-                        self.instructions.push(Instruction::Goto(UNKNOWN))
+                            .push((discriminant, Block::ForElse(goto_index)));
+                    }
+                    "else" => {
+                        self.expect_empty(rest)?;
+                        self.pop_lets();
+                        let goto_index = self.instructions.len();
+                        self.instructions.push(Instruction::Goto(UNKNOWN));
+                        let num_instructions = self.instructions.len();
+                        let mut exits = self.close_branch(num_instructions, discriminant)?;
+                        exits.push(goto_index);
+                        self.block_stack.push((discriminant, Block::Else(exits)));
                     }
                     "endif" => {
                         self.expect_empty(rest)?;
+                        self.pop_lets();
                         let num_instructions = self.instructions.len();
-                        self.close_branch(num_instructions, discriminant)?;
+                        let exits = self.close_branch(num_instructions, discriminant)?;
+                        self.patch_jumps(&exits, num_instructions);
+                        self.let_counts.pop();
                     }
                     "with" => {
                         let (path, name) = self.parse_with(rest)?;
                         let instruction = Instruction::PushNamedContext(path, name);
                         self.instructions.push(instruction);
                         self.block_stack.push((discriminant, Block::With));
+                        self.let_counts.push(0);
                     }
                     "endwith" => {
                         self.expect_empty(rest)?;
+                        self.pop_lets();
                         if let Some((_, Block::With)) = self.block_stack.pop() {
-                            self.instructions.push(Instruction::PopContext)
+                            self.instructions.push(Instruction::PopContext);
+                            self.let_counts.pop();
                         } else {
                             return Err(self.parse_error(
                                 discriminant,
@@ -112,23 +277,189 @@ impl<'template> TemplateCompiler<'template> {
                         }
                     }
                     "for" => {
-                        let (path, name) = self.parse_for(rest)?;
-                        self.instructions
-                            .push(Instruction::PushIterationContext(path, name));
+                        let instruction = self.parse_for(rest)?;
+                        self.instructions.push(instruction);
                         self.block_stack
                             .push((discriminant, Block::For(self.instructions.len())));
-                        self.instructions.push(Instruction::Iterate(UNKNOWN));
+                        self.instructions
+                            .push(Instruction::Iterate(UNKNOWN, UNKNOWN));
+                        self.let_counts.push(0);
+                    }
+                    "endfor" if self.in_for_else_block() => {
+                        self.expect_empty(rest)?;
+                        self.pop_lets();
+                        if let Some((_, Block::ForElse(goto_index))) = self.block_stack.pop() {
+                            let num_instructions = self.instructions.len();
+                            self.patch_jumps(&[goto_index], num_instructions);
+                            self.let_counts.pop();
+                        }
                     }
                     "endfor" => {
                         self.expect_empty(rest)?;
+                        self.pop_lets();
                         let num_instructions = self.instructions.len() + 1;
-                        let goto_target = self.close_for(num_instructions, discriminant)?;
+                        let goto_target =
+                            self.close_for(num_instructions, num_instructions, discriminant)?;
                         self.instructions.push(Instruction::Goto(goto_target));
                         self.instructions.push(Instruction::PopContext);
+                        self.let_counts.pop();
+                    }
+                    "let" | "set" => {
+                        let (name, operand, formatters) = self.parse_let(rest)?;
+                        self.instructions
+                            .push(Instruction::PushLetContext(name, operand, formatters));
+                        *self.let_counts.last_mut().unwrap() += 1;
+                    }
+                    "extends" => {
+                        if self.extends.is_some() || !self.at_template_start() {
+                            return Err(self.parse_error(
+                                discriminant,
+                                "The extends tag must be the first tag in the template."
+                                    .to_string(),
+                            ));
+                        }
+                        self.extends = Some(self.parse_template_name(rest)?);
+                    }
+                    "params" => {
+                        if self.params.is_some() || !self.at_template_start() {
+                            return Err(self.parse_error(
+                                discriminant,
+                                "The params tag must come before any other text in the template."
+                                    .to_string(),
+                            ));
+                        }
+                        self.params = Some(self.parse_params(rest)?);
+                    }
+                    "block" => {
+                        if !is_valid_name(rest) {
+                            return Err(self.parse_error(
+                                discriminant,
+                                format!("Invalid block name '{}'", rest),
+                            ));
+                        }
+                        if self.in_macro() {
+                            return Err(self.parse_error(
+                                discriminant,
+                                "Found a block inside of a macro.".to_string(),
+                            ));
+                        }
+                        if self.blocks.contains_key(rest) || self.in_overridable_block(rest) {
+                            return Err(self.parse_error(
+                                rest,
+                                format!("Found a second block named '{}'", rest),
+                            ));
+                        }
+                        let index = self.instructions.len();
+                        self.instructions.push(Instruction::Block(rest, UNKNOWN));
+                        self.block_stack
+                            .push((discriminant, Block::Overridable(rest, index)));
+                        self.let_counts.push(0);
+                    }
+                    "endblock" => {
+                        self.pop_lets();
+                        match self.block_stack.pop() {
+                            Some((_, Block::Overridable(name, index)))
+                                if rest.is_empty() || rest == name =>
+                            {
+                                let num_instructions = self.instructions.len();
+                                if let Instruction::Block(_, end) = &mut self.instructions[index] {
+                                    *end = num_instructions;
+                                }
+                                self.blocks.insert(name, (index + 1, num_instructions));
+                                self.let_counts.pop();
+                            }
+                            _ => {
+                                return Err(self.parse_error(
+                                    discriminant,
+                                    "Found a closing endblock that doesn't match with a preceeding block.".to_string()
+                                ));
+                            }
+                        }
+                    }
+                    "super" => {
+                        self.expect_empty(rest)?;
+                        let name = self
+                            .block_stack
+                            .iter()
+                            .rev()
+                            .filter_map(|block| match block {
+                                (_, Block::Overridable(name, _)) => Some(*name),
+                                _ => None,
+                            })
+                            .next();
+                        match name {
+                            Some(name) => self.instructions.push(Instruction::Super(name)),
+                            None => {
+                                return Err(self.parse_error(
+                                    discriminant,
+                                    "Found a super tag outside of a block.".to_string(),
+                                ))
+                            }
+                        }
+                    }
+                    "macro" => {
+                        if !self.block_stack.is_empty() {
+                            return Err(self.parse_error(
+                                discriminant,
+                                "Macros must be defined outside of any other block.".to_string(),
+                            ));
+                        }
+                        let (name, params) = self.parse_macro(rest)?;
+                        // The body of the macro is skipped over where it is defined and only
+                        // rendered when the macro is called.
+                        let index = self.instructions.len();
+                        self.instructions.push(Instruction::Goto(UNKNOWN));
+                        self.block_stack
+                            .push((discriminant, Block::Macro(name, params, index)));
+                        self.let_counts.push(0);
+                    }
+                    "endmacro" => {
+                        self.expect_empty(rest)?;
+                        self.pop_lets();
+                        match self.block_stack.pop() {
+                            Some((_, Block::Macro(name, params, index))) => {
+                                let num_instructions = self.instructions.len();
+                                self.instructions[index] = Instruction::Goto(num_instructions);
+                                self.macros.insert(
+                                    name,
+                                    MacroDefinition {
+                                        params,
+                                        start: index + 1,
+                                        end: num_instructions,
+                                    },
+                                );
+                                self.let_counts.pop();
+                            }
+                            _ => {
+                                return Err(self.parse_error(
+                                    discriminant,
+                                    "Found a closing endmacro that doesn't match with a preceeding macro.".to_string()
+                                ));
+                            }
+                        }
+                    }
+                    "import" => {
+                        if !self.at_template_start() {
+                            return Err(self.parse_error(
+                                discriminant,
+                                "The import tag must come before any other text in the template."
+                                    .to_string(),
+                            ));
+                        }
+                        let name = self.parse_template_name(rest)?;
+                        self.imports.push(name);
+                    }
+                    "include" => {
+                        let name = self.parse_template_name(rest)?;
+                        self.instructions.push(Instruction::Include(name));
                     }
                     "call" => {
-                        let (name, path) = self.parse_call(rest)?;
-                        self.instructions.push(Instruction::Call(name, path));
+                        let instruction = self.parse_call(rest)?;
+                        self.instructions.push(instruction);
+                    }
+                    "raw" => {
+                        self.expect_empty(rest)?;
+                        self.compile_raw(discriminant)?;
                     }
                     _ => {
                         return Err(self.parse_error(
@@ -137,27 +468,26 @@ impl<'template> TemplateCompiler<'template> {
                         ));
                     }
                 }
-            // Values, of the form { dotted.path.to.value.in.context }
+            // Values, of the form { dotted.path.to.value.in.context } or { "literal" }
             // Note that it is not (currently) possible to escape curly braces in the templates to
             // prevent them from being interpreted as values.
-            } else if self.remaining_text.starts_with('{') {
+            } else if tag_kind == Some(TagKind::Value) {
                 self.trim_next = false;
+                self.trim_next_newline = false;
 
-                let (path, name) = self.consume_value()?;
-                let instruction = match name {
-                    Some(name) => Instruction::FormattedValue(path, name),
-                    None => Instruction::Value(path),
+                let (operand, formatters) = self.consume_value()?;
+                let instruction = if formatters.is_empty() {
+                    Instruction::Value(operand)
+                } else {
+                    Instruction::FormattedValue(operand, formatters)
                 };
                 self.instructions.push(instruction);
             // All other text - just consume characters until we see a {
             } else {
                 let mut escaped = false;
                 loop {
-                    let mut text = self.consume_text(escaped);
-                    if self.trim_next {
-                        text = text.trim_left();
-                        self.trim_next = false;
-                    }
+                    let consumed = self.consume_text(escaped);
+                    let mut text = self.trim_text_start(consumed);
                     escaped = text.ends_with('\\');
                     if escaped {
                         text = &text[..text.len() - 1];
@@ -171,7 +501,7 @@ impl<'template> TemplateCompiler<'template> {
                     if escaped && self.remaining_text.is_empty() {
                         return Err(self.parse_error(
                             text,
-                            "Found an escape that doesn't escape any character.".to_string()
+                            "Found an escape that doesn't escape any character.".to_string(),
                         ));
                     }
                 }
@@ -185,24 +515,250 @@ impl<'template> TemplateCompiler<'template> {
             ));
         }
 
-        Ok(self.instructions)
+        Ok(CompiledTemplate {
+            instructions: self.instructions,
+            extends: self.extends,
+            blocks: self.blocks,
+            params: self.params,
+            macros: self.macros,
+            imports: self.imports,
+        })
     }
 
     /// Splits a string into a list of named segments which can later be used to look up values in the
     /// context.
     fn parse_path(&self, text: &'template str) -> Result<Path<'template>> {
-        if !text.starts_with('@') {
-            Ok(text
-                .split('.')
-                .map(|s| match s.parse::<usize>() {
-                    Ok(n) => PathStep::Index(s, n),
-                    Err(_) => PathStep::Name(s),
-                })
-                .collect::<Vec<_>>())
-        } else if KNOWN_KEYWORDS.iter().any(|k| *k == text) {
-            Ok(vec![PathStep::Name(text)])
+        let path = text
+            .split('.')
+            .map(|s| match s.parse::<usize>() {
+                Ok(n) => PathStep::Index(s, n),
+                Err(_) => PathStep::Name(s),
+            })
+            .collect::<Vec<_>>();
+        let first_is_keyword = path[0].starts_with('@');
+        for (i, step) in path.iter().enumerate() {
+            let step: &str = step;
+            if !step.starts_with('@') {
+                continue;
+            }
+            if !KNOWN_KEYWORDS.contains(&step) {
+                return Err(self.parse_error(step, format!("Invalid keyword name '{}'", step)));
+            }
+            // @root may be followed by a path into the root object. The loop keywords end the
+            // path, and may follow the name of a loop value to refer to that loop's state.
+            let is_last = i == path.len() - 1;
+            let valid = if step == "@root" {
+                i == 0
+            } else {
+                is_last && (i == 0 || (i == 1 && !first_is_keyword))
+            };
+            if !valid {
+                return Err(self.parse_error(
+                    step,
+                    format!(
+                        "Invalid keyword path '{}'. Only @root can be followed by a path, and loop keywords can only follow the name of a loop value, as in 'item.@index'",
+                        text
+                    ),
+                ));
+            }
+        }
+        Ok(path)
+    }
+
+    /// Parse the condition of an if or elif tag. `or` binds more loosely than `and`, which binds
+    /// more loosely than `not`, and parentheses can be used to group conditions.
+    fn parse_condition(&self, text: &'template str) -> Result<Condition<'template>> {
+        let mut parts = self.split_condition(text, "or")?.into_iter();
+        let mut condition = self.parse_and_condition(parts.next().unwrap())?;
+        for part in parts {
+            let right = self.parse_and_condition(part)?;
+            condition = Condition::Or(Box::new(condition), Box::new(right));
+        }
+        Ok(condition)
+    }
+
+    /// Parse a sequence of conditions joined by `and`.
+    fn parse_and_condition(&self, text: &'template str) -> Result<Condition<'template>> {
+        let mut parts = self.split_condition(text, "and")?.into_iter();
+        let mut condition = self.parse_unary_condition(parts.next().unwrap())?;
+        for part in parts {
+            let right = self.parse_unary_condition(part)?;
+            condition = Condition::And(Box::new(condition), Box::new(right));
+        }
+        Ok(condition)
+    }
+
+    /// Parse a negated condition, a parenthesized condition, a comparison or a single path.
+    fn parse_unary_condition(&self, text: &'template str) -> Result<Condition<'template>> {
+        let text = text.trim();
+        if starts_with_keyword(text, "not") {
+            let inner = self.parse_unary_condition(&text[3..])?;
+            Ok(Condition::Not(Box::new(inner)))
+        } else if text.starts_with('(') && find_closing_paren(text) == Some(text.len() - 1) {
+            self.parse_condition(&text[1..(text.len() - 1)])
+        } else if text.is_empty() || contains_unquoted(text, b'(') || contains_unquoted(text, b')')
+        {
+            Err(self.parse_error(text, format!("Unable to parse condition '{}'", text)))
+        } else if let Some((index, comparison)) = find_comparison(text) {
+            let left_text = text[..index].trim();
+            let left = self.expect_condition_operand(left_text, self.parse_operand(left_text)?)?;
+            let right_text = text[(index + comparison.as_str().len())..].trim();
+            let right =
+                self.expect_condition_operand(right_text, self.parse_operand(right_text)?)?;
+            Ok(Condition::Compare(left, comparison, right))
+        } else {
+            let operand = self.expect_condition_operand(text, self.parse_value_operand(text)?)?;
+            Ok(Condition::Truthy(operand))
+        }
+    }
+
+    /// Check an operand of a condition. Paths in conditions cannot contain whitespace or operator
+    /// characters, so that mistyped comparisons such as `a = b` are reported when compiling.
+    fn expect_condition_operand(
+        &self,
+        text: &'template str,
+        operand: Operand<'template>,
+    ) -> Result<Operand<'template>> {
+        if let Operand::Path(_) = operand {
+            let invalid = text
+                .find(|c| OPERATOR_CHARACTERS.contains(c))
+                .or_else(|| text.find(char::is_whitespace));
+            if let Some(index) = invalid {
+                let c = text[index..].chars().next().unwrap();
+                return Err(self.parse_error(
+                    &text[index..],
+                    format!(
+                        "Unexpected '{}' in path '{}'. Paths in conditions cannot contain \
+                         whitespace or operators.",
+                        c, text
+                    ),
+                ));
+            }
+        }
+        Ok(operand)
+    }
+
+    /// Split the text of a condition on the given keyword, ignoring any occurrences inside
+    /// parentheses or quoted strings. Returns an error if the parentheses are unbalanced.
+    fn split_condition(&self, text: &'template str, keyword: &str) -> Result<Vec<&'template str>> {
+        let bytes = text.as_bytes();
+        let mut parts = vec![];
+        let mut part_start = 0;
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' if in_string => i += 1,
+                b'"' => in_string = !in_string,
+                _ if in_string => {}
+                b'(' => depth += 1,
+                b')' if depth == 0 => {
+                    return Err(self.parse_error(
+                        &text[i..],
+                        "Found a ')' without a matching '('.".to_string(),
+                    ))
+                }
+                b')' => depth -= 1,
+                _ if depth == 0
+                    && (i == 0 || is_condition_delimiter(bytes[i - 1]))
+                    && starts_with_keyword(&text[i..], keyword) =>
+                {
+                    parts.push(&text[part_start..i]);
+                    part_start = i + keyword.len();
+                    i = part_start;
+                    continue;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        if depth > 0 {
+            return Err(self.parse_error(text, "Found a '(' without a matching ')'.".to_string()));
+        }
+        parts.push(&text[part_start..]);
+        Ok(parts)
+    }
+
+    /// Compile a condition into Branch and Compare instructions which jump when the condition
+    /// evaluates to `jump_if` and fall through otherwise. Returns the indexes of the jump
+    /// instructions, whose targets are left unknown for the caller to fill in.
+    fn compile_condition(&mut self, condition: &Condition<'template>, jump_if: bool) -> Vec<usize> {
+        match condition {
+            Condition::Truthy(operand) => {
+                self.instructions
+                    .push(Instruction::Branch(operand.clone(), !jump_if, UNKNOWN));
+                vec![self.instructions.len() - 1]
+            }
+            Condition::Compare(left, comparison, right) => {
+                self.instructions.push(Instruction::Compare(
+                    left.clone(),
+                    *comparison,
+                    right.clone(),
+                    !jump_if,
+                    UNKNOWN,
+                ));
+                vec![self.instructions.len() - 1]
+            }
+            Condition::Not(inner) => self.compile_condition(inner, !jump_if),
+            Condition::And(left, right) => self.compile_logical(left, right, false, jump_if),
+            Condition::Or(left, right) => self.compile_logical(left, right, true, jump_if),
+        }
+    }
+
+    /// Compile an `and` (if `short_circuit` is false) or an `or` (if it is true). If the left side
+    /// evaluates to `short_circuit` then the result is already known and we jump straight away.
+    /// Otherwise, the result is the result of the right side.
+    fn compile_logical(
+        &mut self,
+        left: &Condition<'template>,
+        right: &Condition<'template>,
+        short_circuit: bool,
+        jump_if: bool,
+    ) -> Vec<usize> {
+        if short_circuit == jump_if {
+            let mut jumps = self.compile_condition(left, jump_if);
+            jumps.extend(self.compile_condition(right, jump_if));
+            jumps
+        } else {
+            let skips = self.compile_condition(left, short_circuit);
+            let jumps = self.compile_condition(right, jump_if);
+            let num_instructions = self.instructions.len();
+            self.patch_jumps(&skips, num_instructions);
+            jumps
+        }
+    }
+
+    /// Parse an operand, which may be a literal or a path. Literals use JSON syntax, so strings
+    /// must be double-quoted. This is used where only literals were accepted before, such as in
+    /// comparisons and formatter arguments.
+    fn parse_operand(&self, text: &'template str) -> Result<Operand<'template>> {
+        let is_literal = match text.chars().next() {
+            Some(c) => c == '"' || c == '-' || c.is_ascii_digit(),
+            None => {
+                return Err(
+                    self.parse_error(text, "Expected a path or a literal value.".to_string())
+                )
+            }
+        };
+        if is_literal || text == "true" || text == "false" || text == "null" {
+            match serde_json::from_str(text) {
+                Ok(value) => Ok(Operand::Literal(text, value)),
+                Err(_) => Err(self.parse_error(text, format!("Invalid literal value '{}'", text))),
+            }
+        } else {
+            Ok(Operand::Path(self.parse_path(text)?))
+        }
+    }
+
+    /// Parse an operand where only a path was accepted before literals were added, which is in
+    /// value tags, truth tests and the context of a call tag. Only strings are literals there, so
+    /// that numbers, `true`, `false` and `null` are still looked up in the context as they were.
+    fn parse_value_operand(&self, text: &'template str) -> Result<Operand<'template>> {
+        if text.is_empty() || text.starts_with('"') {
+            self.parse_operand(text)
         } else {
-            Err(self.parse_error(text, format!("Invalid keyword name '{}'", text)))
+            Ok(Operand::Path(self.parse_path(text)?))
         }
     }
 
@@ -223,41 +779,174 @@ impl<'template> TemplateCompiler<'template> {
         }
     }
 
-    /// Close the branch that is on top of the block stack by setting its target instruction
-    /// and popping it from the stack. Returns an error if the top of the block stack is not a
-    /// branch.
-    fn close_branch(&mut self, new_target: usize, discriminant: &str) -> Result<()> {
-        let branch_block = self.block_stack.pop();
-        if let Some((_, Block::Branch(index))) = branch_block {
-            match &mut self.instructions[index] {
-                Instruction::Branch(_, _, target) => {
-                    *target = new_target;
-                    Ok(())
+    /// Names which are bound by a tag use this to raise an error if the name is a reserved word.
+    fn expect_unreserved(&self, name: &str) -> Result<()> {
+        if RESERVED_WORDS.contains(&name) {
+            Err(self.parse_error(
+                name,
+                format!("'{}' is a reserved word and cannot be used as a name", name),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Compile the contents of a raw block, which are emitted as a single literal without parsing
+    /// any tags or values in them, and then consume the endraw tag.
+    fn compile_raw(&mut self, discriminant: &'template str) -> Result<()> {
+        let mut search_start = 0;
+        let position = loop {
+            let (start, end) = (&self.delimiters.block.0, &self.delimiters.block.1);
+            match self.remaining_text[search_start..].find(start.as_str()) {
+                Some(offset)
+                    if is_endraw_tag(
+                        &self.remaining_text[(search_start + offset)..],
+                        start,
+                        end,
+                    ) =>
+                {
+                    break search_start + offset
                 }
-                Instruction::Goto(target) => {
-                    *target = new_target;
-                    Ok(())
+                Some(offset) => search_start += offset + start.len(),
+                None => {
+                    return Err(self.parse_error(
+                        discriminant,
+                        "Expected a closing endraw tag, but reached the end of input.".to_string(),
+                    ))
                 }
-                _ => panic!(),
             }
-        } else {
-            Err(self.parse_error(
+        };
+        let (text, remaining) = self.remaining_text.split_at(position);
+        self.remaining_text = remaining;
+        let text = self.trim_text_start(text);
+        self.instructions.push(Instruction::Literal(text));
+        self.consume_block()?;
+        Ok(())
+    }
+
+    /// Compile an elif tag (or the equivalent `else if`). This ends the current arm of the if
+    /// chain with a goto to the endif, points the previous condition at the new arm and then
+    /// starts a new conditional branch.
+    fn compile_elif(
+        &mut self,
+        discriminant: &'template str,
+        condition: &'template str,
+    ) -> Result<()> {
+        let condition = self.parse_condition(condition)?;
+        self.pop_lets();
+        let goto_index = self.instructions.len();
+        self.instructions.push(Instruction::Goto(UNKNOWN));
+        let num_instructions = self.instructions.len();
+        let mut exits = self.close_branch(num_instructions, discriminant)?;
+        exits.push(goto_index);
+        let jumps = self.compile_condition(&condition, false);
+        self.block_stack
+            .push((discriminant, Block::Branch(jumps, exits)));
+        Ok(())
+    }
+
+    /// End the current section of a block by popping the contexts pushed by its let tags.
+    fn pop_lets(&mut self) {
+        let count = ::std::mem::replace(self.let_counts.last_mut().unwrap(), 0);
+        for _ in 0..count {
+            self.instructions.push(Instruction::PopContext);
+        }
+    }
+
+    /// Close the branch that is on top of the block stack by setting the target instruction of its
+    /// conditional jump and popping it from the stack. Returns the gotos from the end of the
+    /// earlier arms of the chain, which the caller must point at the endif. Returns an error if
+    /// the top of the block stack is not a branch, or if an else block is continued by another arm.
+    fn close_branch(&mut self, new_target: usize, discriminant: &str) -> Result<Vec<usize>> {
+        match self.block_stack.pop() {
+            Some((_, Block::Branch(jumps, exits))) => {
+                self.patch_jumps(&jumps, new_target);
+                Ok(exits)
+            }
+            Some((_, Block::Else(exits))) if discriminant == "endif" => Ok(exits),
+            Some((_, Block::Else(_))) => Err(self.parse_error(
                 discriminant,
-                "Found a closing endif or else which doesn't match with a preceding if."
-                    .to_string(),
-            ))
+                "Found an elif or else which follows the else block of an if.".to_string(),
+            )),
+            _ => Err(self.parse_error(
+                discriminant,
+                "Found an elif, else or endif which doesn't match with a preceding if.".to_string(),
+            )),
+        }
+    }
+
+    /// Set the target of each of the given branch or goto instructions.
+    fn patch_jumps(&mut self, jumps: &[usize], new_target: usize) {
+        for index in jumps {
+            match &mut self.instructions[*index] {
+                Instruction::Branch(_, _, target) => *target = new_target,
+                Instruction::Compare(_, _, _, _, target) => *target = new_target,
+                Instruction::Goto(target) => *target = new_target,
+                _ => panic!(),
+            }
+        }
+    }
+
+    /// Check whether the block on top of the block stack is the body of a for loop.
+    fn in_for_block(&self) -> bool {
+        match self.block_stack.last() {
+            Some((_, Block::For(_))) => true,
+            _ => false,
+        }
+    }
+
+    /// Check whether nothing but whitespace and comments has been compiled so far, as is required
+    /// before the extends and params tags.
+    fn at_template_start(&self) -> bool {
+        self.block_stack.is_empty()
+            && self
+                .instructions
+                .iter()
+                .all(|instruction| match instruction {
+                    Instruction::Literal(text) => text.trim().is_empty(),
+                    _ => false,
+                })
+    }
+
+    /// Check whether a macro definition is open.
+    fn in_macro(&self) -> bool {
+        self.block_stack.iter().any(|block| match block {
+            (_, Block::Macro(..)) => true,
+            _ => false,
+        })
+    }
+
+    /// Check whether an overridable block with the given name is open.
+    fn in_overridable_block(&self, name: &str) -> bool {
+        self.block_stack.iter().any(|block| match block {
+            (_, Block::Overridable(other, _)) => *other == name,
+            _ => false,
+        })
+    }
+
+    /// Check whether the block on top of the block stack is the else section of a for loop.
+    fn in_for_else_block(&self) -> bool {
+        match self.block_stack.last() {
+            Some((_, Block::ForElse(_))) => true,
+            _ => false,
         }
     }
 
-    /// Close the for loop that is on top of the block stack by setting its target instruction and
+    /// Close the for loop that is on top of the block stack by setting its target instructions and
     /// popping it from the stack. Returns an error if the top of the stack is not a for loop.
     /// Returns the index of the loop's Iterate instruction for further processing.
-    fn close_for(&mut self, new_target: usize, discriminant: &str) -> Result<usize> {
+    fn close_for(
+        &mut self,
+        new_target: usize,
+        new_empty_target: usize,
+        discriminant: &str,
+    ) -> Result<usize> {
         let branch_block = self.block_stack.pop();
         if let Some((_, Block::For(index))) = branch_block {
             match &mut self.instructions[index] {
-                Instruction::Iterate(target) => {
+                Instruction::Iterate(target, empty_target) => {
                     *target = new_target;
+                    *empty_target = new_empty_target;
                     Ok(index)
                 }
                 _ => panic!(),
@@ -270,32 +959,37 @@ impl<'template> TemplateCompiler<'template> {
         }
     }
 
-    /// Advance the cursor to the next { and return the consumed text. If `escaped` is true, skips
-    /// a { at the start of the text.
+    /// Advance the cursor to the next start delimiter and return the consumed text. If `escaped` is
+    /// true, skips the first character of a start delimiter at the start of the text.
     fn consume_text(&mut self, escaped: bool) -> &'template str {
-        let search_substr = if escaped {
-            &self.remaining_text[1..]
-        } else {
+        let skipped = if escaped {
             self.remaining_text
+                .chars()
+                .next()
+                .map(char::len_utf8)
+                .unwrap_or(0)
+        } else {
+            0
         };
+        let search_substr = &self.remaining_text[skipped..];
 
-        let mut position = search_substr
-            .find('{')
-            .unwrap_or_else(|| search_substr.len());
-        if escaped {
-            position += 1;
-        }
+        let position = self
+            .text_ends
+            .iter()
+            .filter_map(|start| search_substr.find(start.as_str()))
+            .min()
+            .unwrap_or(search_substr.len())
+            + skipped;
 
         let (text, remaining) = self.remaining_text.split_at(position);
         self.remaining_text = remaining;
         text
     }
 
-    /// Advance the cursor to the end of the value tag and return the value's path and optional
-    /// formatter name.
-    fn consume_value(&mut self) -> Result<(Path<'template>, Option<&'template str>)> {
-        let tag = self.consume_tag("}")?;
-        let mut tag = tag[1..(tag.len() - 1)].trim();
+    /// Advance the cursor to the end of the value tag and return the value's operand and the
+    /// formatters to apply to it, if any.
+    fn consume_value(&mut self) -> Result<(Operand<'template>, Vec<FormatterCall<'template>>)> {
+        let mut tag = self.consume_tag(TagKind::Value)?.trim();
         if tag.starts_with('-') {
             tag = tag[1..].trim();
             self.trim_last_whitespace();
@@ -305,14 +999,51 @@ impl<'template> TemplateCompiler<'template> {
             self.trim_next_whitespace();
         }
 
-        if let Some(index) = tag.find('|') {
-            let (path_str, name_str) = tag.split_at(index);
-            let name = name_str[1..].trim();
-            let path = self.parse_path(path_str.trim())?;
-            Ok((path, Some(name)))
-        } else {
-            Ok((self.parse_path(tag)?, None))
+        let (operand, formatters) = self.parse_formatter_chain(tag)?;
+        Ok((self.parse_value_operand(operand)?, formatters))
+    }
+
+    /// Split a value which may be followed by a chain of formatters, separated by '|', into the
+    /// text of the value and the parsed formatters.
+    fn parse_formatter_chain(
+        &self,
+        text: &'template str,
+    ) -> Result<(&'template str, Vec<FormatterCall<'template>>)> {
+        let mut parts = split_unquoted(text, b'|').into_iter();
+        let operand = parts.next().unwrap().trim();
+        let mut formatters = vec![];
+        for part in parts {
+            formatters.push(self.parse_formatter_call(part.trim())?);
+        }
+        Ok((operand, formatters))
+    }
+
+    /// Parse one step of a formatter chain, which is a formatter name optionally followed by a
+    /// parenthesized, comma-separated list of arguments.
+    fn parse_formatter_call(&self, text: &'template str) -> Result<FormatterCall<'template>> {
+        let (name, arguments) = match text.find('(') {
+            Some(index) if text.ends_with(')') => {
+                let arguments_str = text[(index + 1)..(text.len() - 1)].trim();
+                let mut arguments = vec![];
+                if !arguments_str.is_empty() {
+                    for argument in split_unquoted(arguments_str, b',') {
+                        arguments.push(self.parse_operand(argument.trim())?);
+                    }
+                }
+                (text[..index].trim(), arguments)
+            }
+            Some(_) => {
+                return Err(self.parse_error(
+                    text,
+                    format!("Expected a closing ')' after the arguments in '{}'", text),
+                ))
+            }
+            None => (text, vec![]),
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(self.parse_error(text, format!("Invalid formatter name '{}'", name)));
         }
+        Ok((name, arguments))
     }
 
     /// Right-trim whitespace from the last text block we parsed.
@@ -327,66 +1058,152 @@ impl<'template> TemplateCompiler<'template> {
         self.trim_next = true;
     }
 
+    /// Handle the whitespace around the block tag or comment with the given contents. A `-` at
+    /// either end of the tag trims all of the whitespace on that side, otherwise the trim_blocks
+    /// and lstrip_blocks settings apply.
+    fn trim_around_tag(&mut self, tag: &str) {
+        if tag.starts_with('-') {
+            self.trim_last_whitespace();
+        } else if self.lstrip_blocks {
+            self.strip_last_indentation();
+        }
+        if tag.ends_with('-') {
+            self.trim_next_whitespace();
+        } else if self.trim_blocks {
+            self.trim_next_newline = true;
+        }
+    }
+
+    /// Strip the spaces and tabs at the end of the last text block we parsed, if nothing else
+    /// comes between them and the start of their line.
+    fn strip_last_indentation(&mut self) {
+        let original_text = self.original_text;
+        if let Some(Instruction::Literal(text)) = self.instructions.last_mut() {
+            let stripped = text.trim_right_matches(|c| c == ' ' || c == '\t');
+            let end = stripped.as_ptr() as usize - original_text.as_ptr() as usize + stripped.len();
+            if end == 0 || original_text.as_bytes()[end - 1] == b'\n' {
+                *text = stripped;
+            }
+        }
+    }
+
+    /// Apply the trimming requested by the previous tag to the start of the given text block.
+    fn trim_text_start(&mut self, text: &'template str) -> &'template str {
+        let text = if self.trim_next {
+            text.trim_left()
+        } else if self.trim_next_newline && text.starts_with('\n') {
+            &text[1..]
+        } else if self.trim_next_newline && text.starts_with("\r\n") {
+            &text[2..]
+        } else {
+            text
+        };
+        self.trim_next = false;
+        self.trim_next_newline = false;
+        text
+    }
+
     /// Advance the cursor to the end of the current block tag and return the discriminant substring
     /// and the rest of the text in the tag. Also handles trimming whitespace where needed.
     fn consume_block(&mut self) -> Result<(&'template str, &'template str)> {
-        let tag = self.consume_tag("}}")?;
-        let mut block = tag[2..(tag.len() - 2)].trim();
+        let mut block = self.consume_tag(TagKind::Block)?.trim();
+        self.trim_around_tag(block);
         if block.starts_with('-') {
             block = block[1..].trim();
-            self.trim_last_whitespace();
         }
         if block.ends_with('-') {
             block = block[0..block.len() - 1].trim();
-            self.trim_next_whitespace();
         }
         let discriminant = block.split_whitespace().next().unwrap_or(block);
         let rest = block[discriminant.len()..].trim();
         Ok((discriminant, rest))
     }
 
-    /// Advance the cursor to after the given expected_close string and return the text in between
-    /// (including the expected_close characters), or return an error message if we reach the end
-    /// of a line of text without finding it.
-    /// Assumes that there's a start token with the same length as the close token at the start of
-    /// currently remaining text.
-    fn consume_tag(&mut self, expected_close: &str) -> Result<&'template str> {
-        // We skip over the matching start token for this tag, so that we do not accidentally match
-        // some suffix of it with the close token. We assume that the start token is as long as the
-        // end token.
-        let start_len = expected_close.len();
+    /// Find the kind of tag which starts at the beginning of the remaining text, if any. When more
+    /// than one start delimiter matches, the longest one wins.
+    fn next_tag_kind(&self) -> Option<TagKind> {
+        [TagKind::Block, TagKind::Value, TagKind::Comment]
+            .iter()
+            .cloned()
+            .filter(|kind| {
+                self.remaining_text
+                    .starts_with(tag_delimiters(&self.delimiters, *kind).0)
+            })
+            .max_by_key(|kind| tag_delimiters(&self.delimiters, *kind).0.len())
+    }
+
+    /// Advance the cursor to after the end delimiter of the tag of the given kind and return the
+    /// text in between the delimiters, or return an error message if the tag is not closed. Values
+    /// must be closed on the same line, while block tags and comments may span several lines.
+    /// Assumes that the start delimiter for this kind of tag is at the start of the currently
+    /// remaining text.
+    fn consume_tag(&mut self, kind: TagKind) -> Result<&'template str> {
+        // We skip over the start delimiter for this tag, so that we do not accidentally match some
+        // suffix of it with the end delimiter.
+        let (start, expected_close) = tag_delimiters(&self.delimiters, kind);
+        let start_len = start.len();
         let end_len = expected_close.len();
-        if let Some(line) = self.remaining_text.lines().next() {
-            if let Some(pos) = line[start_len..].find(expected_close) {
-                let (tag, remaining) = self.remaining_text.split_at(pos + start_len + end_len);
-                self.remaining_text = remaining;
-                Ok(tag)
-            } else {
-                Err(self.parse_error(
+        if kind == TagKind::Value {
+            let line = self.remaining_text.lines().next().unwrap_or("");
+            return match find_unquoted(&line[start_len..], expected_close) {
+                Some(pos) => {
+                    let (tag, remaining) = self.remaining_text.split_at(pos + start_len + end_len);
+                    self.remaining_text = remaining;
+                    Ok(&tag[start_len..(start_len + pos)])
+                }
+                None => Err(self.parse_error(
                     line,
                     format!(
                         "Expected a closing '{}' but found end-of-line instead.",
                         expected_close
                     ),
-                ))
+                )),
+            };
+        }
+
+        let opening = &self.remaining_text[..start_len];
+        let search_text = &self.remaining_text[start_len..];
+        // If another block tag starts before this one is closed, the end delimiter we would find
+        // belongs to that tag, so this one was left unclosed. Comments may contain other tags, and
+        // string literals may contain either delimiter. Only the text before the end delimiter is
+        // searched for another tag, so that each tag is scanned once.
+        let (close, next_start) = match kind {
+            TagKind::Block => {
+                let close = find_unquoted(search_text, expected_close);
+                let next_start = close.and_then(|pos| find_unquoted(&search_text[..pos], start));
+                (close, next_start)
             }
-        } else {
-            Err(self.parse_error(
-                self.remaining_text,
+            _ => (search_text.find(expected_close), None),
+        };
+        match (close, next_start) {
+            (Some(pos), Some(next)) if next < pos => Err(self.parse_error(
+                opening,
                 format!(
-                    "Expected a closing '{}' but found end-of-text instead.",
+                    "Expected a closing '{}' for this tag, but found the start of another tag first.",
                     expected_close
                 ),
-            ))
+            )),
+            (Some(pos), _) => {
+                let (tag, remaining) = self.remaining_text.split_at(pos + start_len + end_len);
+                self.remaining_text = remaining;
+                Ok(&tag[start_len..(start_len + pos)])
+            }
+            (None, _) => Err(self.parse_error(
+                opening,
+                format!(
+                    "Expected a closing '{}' for this tag, but reached the end of the template.",
+                    expected_close
+                ),
+            )),
         }
     }
 
     /// Parse a with tag to separate the value path from the (optional) name.
     fn parse_with(&self, with_text: &'template str) -> Result<(Path<'template>, &'template str)> {
-        if let Some(index) = with_text.find(" as ") {
-            let (path_str, name_str) = with_text.split_at(index);
+        if let Some((path_str, name_str)) = split_at_keyword(with_text, "as") {
             let path = self.parse_path(path_str.trim())?;
-            let name = name_str[" as ".len()..].trim();
+            let name = name_str.trim();
+            self.expect_unreserved(name)?;
             Ok((path, name))
         } else {
             Err(self.parse_error(
@@ -399,13 +1216,51 @@ impl<'template> TemplateCompiler<'template> {
         }
     }
 
-    /// Parse a for tag to separate the value path from the name.
-    fn parse_for(&self, for_text: &'template str) -> Result<(Path<'template>, &'template str)> {
-        if let Some(index) = for_text.find(" in ") {
-            let (name_str, path_str) = for_text.split_at(index);
-            let name = name_str.trim();
-            let path = self.parse_path(path_str[" in ".len()..].trim())?;
-            Ok((path, name))
+    /// Parse a for tag to separate the value path or range from the name, and from the key name if
+    /// there is one. Returns the instruction which pushes the iteration context.
+    fn parse_for(&self, for_text: &'template str) -> Result<Instruction<'template>> {
+        if let Some((names_str, path_str)) = split_at_keyword(for_text, "in") {
+            let (key_name, name) = match names_str.find(',') {
+                Some(comma) => {
+                    let key_name = names_str[..comma].trim();
+                    let name = names_str[(comma + 1)..].trim();
+                    if key_name.is_empty() || name.is_empty() {
+                        return Err(self.parse_error(
+                            names_str,
+                            format!(
+                                "Expected 'key, value' in for block, but found '{}'",
+                                names_str
+                            ),
+                        ));
+                    }
+                    (Some(key_name), name)
+                }
+                None => (None, names_str.trim()),
+            };
+            if let Some(key_name) = key_name {
+                self.expect_unreserved(key_name)?;
+            }
+            self.expect_unreserved(name)?;
+            let path_str = path_str.trim();
+            // Whitespace is allowed between the range keyword and its arguments.
+            let range_arguments = if path_str.starts_with("range") {
+                Some(path_str["range".len()..].trim_start())
+            } else {
+                None
+            };
+            let range_arguments = range_arguments
+                .filter(|arguments| arguments.starts_with('(') && arguments.ends_with(')'));
+            if let Some(range_arguments) = range_arguments {
+                if let Some(key_name) = key_name {
+                    return Err(self.parse_error(
+                        key_name,
+                        "Ranges have no keys to iterate over.".to_string(),
+                    ));
+                }
+                return self.parse_range(&range_arguments[1..(range_arguments.len() - 1)], name);
+            }
+            let path = self.parse_path(path_str)?;
+            Ok(Instruction::PushIterationContext(path, key_name, name))
         } else {
             Err(self.parse_error(
                 for_text,
@@ -414,29 +1269,359 @@ impl<'template> TemplateCompiler<'template> {
         }
     }
 
-    /// Parse a call tag to separate the template name and context value.
-    fn parse_call(&self, call_text: &'template str) -> Result<(&'template str, Path<'template>)> {
-        if let Some(index) = call_text.find(" with ") {
-            let (name_str, path_str) = call_text.split_at(index);
-            let name = name_str.trim();
-            let path = self.parse_path(path_str[" with ".len()..].trim())?;
-            Ok((name, path))
-        } else {
-            Err(self.parse_error(
-                call_text,
-                format!("Unable to parse call block text '{}'", call_text),
-            ))
+    /// Parse the arguments of a range in a for tag, which are the start, the end and an optional
+    /// step size.
+    fn parse_range(
+        &self,
+        arguments_text: &'template str,
+        name: &'template str,
+    ) -> Result<Instruction<'template>> {
+        let mut arguments = vec![];
+        for argument in split_unquoted(arguments_text, b',') {
+            arguments.push(self.parse_operand(argument.trim())?);
         }
-    }
-}
-
+        if arguments.len() < 2 || arguments.len() > 3 {
+            return Err(self.parse_error(
+                arguments_text,
+                format!(
+                    "Expected 2 or 3 arguments to range, but found {}",
+                    arguments.len()
+                ),
+            ));
+        }
+        let mut arguments = arguments.into_iter();
+        let start = arguments.next().unwrap();
+        let end = arguments.next().unwrap();
+        Ok(Instruction::PushRangeContext(
+            start,
+            end,
+            arguments.next(),
+            name,
+        ))
+    }
+
+    /// Parse a let tag to separate the name from the value and its formatters.
+    fn parse_let(
+        &self,
+        let_text: &'template str,
+    ) -> Result<(
+        &'template str,
+        Operand<'template>,
+        Vec<FormatterCall<'template>>,
+    )> {
+        if let Some(index) = let_text.find('=') {
+            let name = let_text[..index].trim();
+            if !is_valid_name(name) {
+                return Err(self.parse_error(
+                    let_text,
+                    format!("Invalid variable name '{}' in let block", name),
+                ));
+            }
+            let (operand, formatters) =
+                self.parse_formatter_chain(let_text[(index + 1)..].trim())?;
+            Ok((name, self.parse_operand(operand)?, formatters))
+        } else {
+            Err(self.parse_error(
+                let_text,
+                format!(
+                    "Expected '<name> = <value>' in let block, but found \"{}\" instead",
+                    let_text
+                ),
+            ))
+        }
+    }
+
+    /// Parse the quoted name of another template, as given in an extends or include tag.
+    fn parse_template_name(&self, text: &'template str) -> Result<&'template str> {
+        if text.len() > 2 && text.starts_with('"') && text.ends_with('"') {
+            let name = &text[1..(text.len() - 1)];
+            if !name.contains('"') {
+                return Ok(name);
+            }
+        }
+        Err(self.parse_error(
+            text,
+            format!("Expected a quoted template name, but found '{}'", text),
+        ))
+    }
+
+    /// Parse a call tag to separate the template name and context value.
+    fn parse_call(&self, call_text: &'template str) -> Result<Instruction<'template>> {
+        if let Some((name_str, operand_str)) = split_at_keyword(call_text, "with") {
+            let name = name_str.trim();
+            let operand_str = operand_str.trim();
+            // Paths and literals never contain an unquoted '=', so it marks named arguments.
+            if split_unquoted(operand_str, b'=').len() > 1 {
+                let arguments = self.parse_named_arguments(operand_str)?;
+                Ok(Instruction::CallWithArguments(name, arguments))
+            } else {
+                let operand = self.parse_value_operand(operand_str)?;
+                Ok(Instruction::Call(name, operand))
+            }
+        } else {
+            Err(self.parse_error(
+                call_text,
+                format!("Unable to parse call block text '{}'", call_text),
+            ))
+        }
+    }
+
+    /// Parse a comma-separated list of named arguments to a call tag, such as
+    /// `title=item.name, class="wide"`.
+    fn parse_named_arguments(&self, text: &'template str) -> Result<Vec<NamedArgument<'template>>> {
+        let mut arguments: Vec<NamedArgument> = vec![];
+        for argument in split_unquoted(text, b',') {
+            let parts = split_unquoted(argument, b'=');
+            if parts.len() != 2 {
+                return Err(self.parse_error(
+                    argument,
+                    format!(
+                        "Expected a named argument such as 'name=value' but found '{}'",
+                        argument.trim()
+                    ),
+                ));
+            }
+            let name = parts[0].trim();
+            if !is_valid_name(name) {
+                return Err(self.parse_error(parts[0], format!("Invalid argument name '{}'", name)));
+            }
+            if arguments.iter().any(|(other, _)| *other == name) {
+                return Err(
+                    self.parse_error(name, format!("Found a second argument named '{}'", name))
+                );
+            }
+            arguments.push((name, self.parse_operand(parts[1].trim())?));
+        }
+        Ok(arguments)
+    }
+
+    /// Parse the name and parenthesized parameter list of a macro tag, such as `card(title, rows)`.
+    fn parse_macro(&self, text: &'template str) -> Result<(&'template str, Vec<&'template str>)> {
+        let (name, params) = match text.find('(') {
+            Some(index) if text.ends_with(')') => (
+                text[..index].trim(),
+                self.parse_params(text[(index + 1)..(text.len() - 1)].trim())?,
+            ),
+            _ => {
+                return Err(self.parse_error(
+                    text,
+                    format!(
+                        "Expected a macro name and parameter list but found '{}'",
+                        text
+                    ),
+                ))
+            }
+        };
+        if !is_valid_name(name) {
+            return Err(self.parse_error(text, format!("Invalid macro name '{}'", name)));
+        }
+        if self.macros.contains_key(name) {
+            return Err(self.parse_error(name, format!("Found a second macro named '{}'", name)));
+        }
+        Ok((name, params))
+    }
+
+    /// Parse the comma-separated list of parameter names in a params or macro tag.
+    fn parse_params(&self, text: &'template str) -> Result<Vec<&'template str>> {
+        let mut params: Vec<&str> = vec![];
+        if text.is_empty() {
+            return Ok(params);
+        }
+        for param in text.split(',') {
+            let name = param.trim();
+            if !is_valid_name(name) {
+                return Err(self.parse_error(param, format!("Invalid parameter name '{}'", name)));
+            }
+            if params.contains(&name) {
+                return Err(
+                    self.parse_error(name, format!("Found a second parameter named '{}'", name))
+                );
+            }
+            params.push(name);
+        }
+        Ok(params)
+    }
+}
+
+/// Check whether the text is a valid name for a variable, which must start with a letter or an
+/// underscore followed by letters, digits and underscores, and must not be a reserved word.
+fn is_valid_name(name: &str) -> bool {
+    match name.chars().next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {
+            name.chars().all(|c| c.is_alphanumeric() || c == '_') && !RESERVED_WORDS.contains(&name)
+        }
+        _ => false,
+    }
+}
+
+/// Find the first occurrence of the given keyword with whitespace on both sides, such as the `with`
+/// in a call tag, and return the text before and after it. The whitespace may include line breaks
+/// in tags which span several lines.
+fn split_at_keyword<'a>(text: &'a str, keyword: &str) -> Option<(&'a str, &'a str)> {
+    let mut search_start = 0;
+    while let Some(offset) = text[search_start..].find(keyword) {
+        let index = search_start + offset;
+        let end = index + keyword.len();
+        match (
+            text[..index].chars().next_back(),
+            text[end..].chars().next(),
+        ) {
+            (Some(before), Some(after)) if before.is_whitespace() && after.is_whitespace() => {
+                return Some((&text[..index], &text[end..]));
+            }
+            _ => search_start = end,
+        }
+    }
+    None
+}
+
+/// Check whether the text starts with the given keyword followed by whitespace, a parenthesis or
+/// the end of the text.
+fn starts_with_keyword(text: &str, keyword: &str) -> bool {
+    if !text.starts_with(keyword) {
+        return false;
+    }
+    match text[keyword.len()..].bytes().next() {
+        Some(byte) => is_condition_delimiter(byte),
+        None => true,
+    }
+}
+
+/// Get the start and end delimiters for the given kind of tag.
+fn tag_delimiters(delimiters: &Delimiters, kind: TagKind) -> (&str, &str) {
+    let (start, end) = match kind {
+        TagKind::Block => &delimiters.block,
+        TagKind::Value => &delimiters.value,
+        TagKind::Comment => &delimiters.comment,
+    };
+    (start, end)
+}
+
+/// Check whether the text starts with an endraw tag, including any whitespace trimming markers.
+fn is_endraw_tag(text: &str, start: &str, end: &str) -> bool {
+    match text[start.len()..].find(end) {
+        Some(end) => {
+            let mut tag = text[start.len()..(start.len() + end)].trim();
+            if tag.starts_with('-') {
+                tag = tag[1..].trim();
+            }
+            if tag.ends_with('-') {
+                tag = tag[..(tag.len() - 1)].trim();
+            }
+            tag == "endraw"
+        }
+        None => false,
+    }
+}
+
+/// Keywords in conditions must be separated from their operands by whitespace or parentheses.
+fn is_condition_delimiter(byte: u8) -> bool {
+    byte == b'(' || byte == b')' || (byte as char).is_whitespace()
+}
+
+/// Given text which starts with a '(', find the index of the matching ')'.
+fn find_closing_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, byte) in text.bytes().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' if in_string => escaped = true,
+            b'"' => in_string = !in_string,
+            _ if in_string => {}
+            b'(' => depth += 1,
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Split the text on every occurrence of the given byte which is not inside a quoted string.
+fn split_unquoted(text: &str, separator: u8) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut part_start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, byte) in text.bytes().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' if in_string => escaped = true,
+            b'"' => in_string = !in_string,
+            _ if !in_string && byte == separator => {
+                parts.push(&text[part_start..i]);
+                part_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[part_start..]);
+    parts
+}
+
+/// Find the first occurrence of the pattern in the text which is not inside a quoted string.
+fn find_unquoted(text: &str, pattern: &str) -> Option<usize> {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, byte) in text.bytes().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' if in_string => escaped = true,
+            b'"' => in_string = !in_string,
+            _ if !in_string && text.as_bytes()[i..].starts_with(pattern.as_bytes()) => {
+                return Some(i)
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Check whether the given byte occurs in the text outside of any quoted string.
+fn contains_unquoted(text: &str, byte: u8) -> bool {
+    split_unquoted(text, byte).len() > 1
+}
+
+/// Find the first comparison operator in the text of a condition, skipping over any quoted
+/// strings. Returns the byte offset of the operator as well as the operator itself.
+fn find_comparison(text: &str) -> Option<(usize, Comparison)> {
+    let bytes = text.as_bytes();
+    let mut in_string = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let next = bytes.get(i + 1).cloned();
+        match (bytes[i], next) {
+            (b'\\', _) if in_string => i += 1,
+            (b'"', _) => in_string = !in_string,
+            _ if in_string => {}
+            (b'=', Some(b'=')) => return Some((i, Comparison::Equal)),
+            (b'!', Some(b'=')) => return Some((i, Comparison::NotEqual)),
+            (b'<', Some(b'=')) => return Some((i, Comparison::LessOrEqual)),
+            (b'>', Some(b'=')) => return Some((i, Comparison::GreaterOrEqual)),
+            (b'<', _) => return Some((i, Comparison::Less)),
+            (b'>', _) => return Some((i, Comparison::Greater)),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use instruction::Instruction::*;
 
     fn compile(text: &'static str) -> Result<Vec<Instruction<'static>>> {
-        TemplateCompiler::new(text).compile()
+        TemplateCompiler::new(text, &Delimiters::default())
+            .compile()
+            .map(|compiled| compiled.instructions)
     }
 
     #[test]
@@ -452,120 +1637,820 @@ mod test {
         let text = "{ foobar }";
         let instructions = compile(text).unwrap();
         assert_eq!(1, instructions.len());
-        assert_eq!(&Value(vec![PathStep::Name("foobar")]), &instructions[0]);
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("foobar")])),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_compile_value_with_formatter() {
+        let text = "{ foobar | my_formatter }";
+        let instructions = compile(text).unwrap();
+        assert_eq!(1, instructions.len());
+        assert_eq!(
+            &FormattedValue(
+                Operand::Path(vec![PathStep::Name("foobar")]),
+                vec![("my_formatter", vec![])]
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_compile_literal_values() {
+        let text = r#"{ "n/a" }{ x | join(42) }{{ let a = true }}{{ let b = null }}"#;
+        let instructions = compile(text).unwrap();
+        assert_eq!(4, instructions.len());
+        assert_eq!(
+            &Value(Operand::Literal(
+                r#""n/a""#,
+                ::serde_json::Value::from("n/a")
+            )),
+            &instructions[0]
+        );
+        assert_eq!(
+            &FormattedValue(
+                Operand::Path(vec![PathStep::Name("x")]),
+                vec![(
+                    "join",
+                    vec![Operand::Literal("42", ::serde_json::Value::from(42))]
+                )]
+            ),
+            &instructions[1]
+        );
+        assert_eq!(
+            &PushLetContext(
+                "a",
+                Operand::Literal("true", ::serde_json::Value::Bool(true)),
+                vec![]
+            ),
+            &instructions[2]
+        );
+        assert_eq!(
+            &PushLetContext(
+                "b",
+                Operand::Literal("null", ::serde_json::Value::Null),
+                vec![]
+            ),
+            &instructions[3]
+        );
+    }
+
+    #[test]
+    fn test_compile_literal_containing_pipe() {
+        let text = r#"{ "a | b" | my_formatter }"#;
+        let instructions = compile(text).unwrap();
+        assert_eq!(
+            &FormattedValue(
+                Operand::Literal(r#""a | b""#, ::serde_json::Value::from("a | b")),
+                vec![("my_formatter", vec![])]
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_invalid_literal_value() {
+        compile(r#"{ "unterminated }"#).unwrap_err();
+        compile("{{ let x = 12abc }}").unwrap_err();
+        compile("{ }").unwrap_err();
+    }
+
+    #[test]
+    fn test_compile_formatter_chain() {
+        let text = "{ foobar | trim|upper | truncate }";
+        let instructions = compile(text).unwrap();
+        assert_eq!(1, instructions.len());
+        assert_eq!(
+            &FormattedValue(
+                Operand::Path(vec![PathStep::Name("foobar")]),
+                vec![("trim", vec![]), ("upper", vec![]), ("truncate", vec![])]
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_compile_formatter_arguments() {
+        let text = r#"{ foobar | round(2) | pad_left(width, ", |") | trim() }"#;
+        let instructions = compile(text).unwrap();
+        assert_eq!(1, instructions.len());
+        assert_eq!(
+            &FormattedValue(
+                Operand::Path(vec![PathStep::Name("foobar")]),
+                vec![
+                    (
+                        "round",
+                        vec![Operand::Literal("2", ::serde_json::Value::from(2))]
+                    ),
+                    (
+                        "pad_left",
+                        vec![
+                            Operand::Path(vec![PathStep::Name("width")]),
+                            Operand::Literal(r#"", |""#, ::serde_json::Value::from(", |"))
+                        ]
+                    ),
+                    ("trim", vec![]),
+                ]
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_invalid_formatter_arguments() {
+        compile("{ foobar | round(2 }").unwrap_err();
+        compile("{ foobar | round(2,) }").unwrap_err();
+        compile("{ foobar | (2) }").unwrap_err();
+        compile("{ foobar | my formatter }").unwrap_err();
+    }
+
+    #[test]
+    fn test_empty_formatter_name() {
+        compile("{ foobar | }").unwrap_err();
+        compile("{ foobar | trim || upper }").unwrap_err();
+    }
+
+    #[test]
+    fn test_dotted_path() {
+        let text = "{ foo.bar }";
+        let instructions = compile(text).unwrap();
+        assert_eq!(1, instructions.len());
+        assert_eq!(
+            &Value(Operand::Path(vec![
+                PathStep::Name("foo"),
+                PathStep::Name("bar")
+            ])),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_indexed_path() {
+        let text = "{ foo.0.bar }";
+        let instructions = compile(text).unwrap();
+        assert_eq!(1, instructions.len());
+        assert_eq!(
+            &Value(Operand::Path(vec![
+                PathStep::Name("foo"),
+                PathStep::Index("0", 0),
+                PathStep::Name("bar")
+            ])),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_array_index_paths() {
+        let text = "{ 0 }{ 1.2 | my_formatter }{ 0.name }{{ if 0.ok }}{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(4, instructions.len());
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Index("0", 0)])),
+            &instructions[0]
+        );
+        assert_eq!(
+            &FormattedValue(
+                Operand::Path(vec![PathStep::Index("1", 1), PathStep::Index("2", 2)]),
+                vec![("my_formatter", vec![])]
+            ),
+            &instructions[1]
+        );
+        assert_eq!(
+            &Value(Operand::Path(vec![
+                PathStep::Index("0", 0),
+                PathStep::Name("name")
+            ])),
+            &instructions[2]
+        );
+        assert_eq!(
+            &Branch(
+                Operand::Path(vec![PathStep::Index("0", 0), PathStep::Name("ok")]),
+                true,
+                4
+            ),
+            &instructions[3]
+        );
+
+        // Values tested for truth and the context of a call are paths, as they were before
+        // literals were added.
+        let instructions =
+            compile("{{ if true }}{{ endif }}{{ if not null }}{{ endif }}{{ call t with 0 }}")
+                .unwrap();
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("true")]), true, 1),
+            &instructions[0]
+        );
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("null")]), false, 2),
+            &instructions[1]
+        );
+        assert_eq!(
+            &Call("t", Operand::Path(vec![PathStep::Index("0", 0)])),
+            &instructions[2]
+        );
+
+        // A '-' before a number is a trim marker, as it is before any other path.
+        let instructions = compile("a {-1} b").unwrap();
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Index("1", 1)])),
+            &instructions[1]
+        );
+
+        // In comparisons, numbers are literals.
+        let instructions = compile("{{ if x == 1.5 }}{{ endif }}").unwrap();
+        assert_eq!(
+            &Compare(
+                Operand::Path(vec![PathStep::Name("x")]),
+                Comparison::Equal,
+                Operand::Literal("1.5", ::serde_json::Value::from(1.5)),
+                true,
+                1
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_mixture() {
+        let text = "Hello { name }, how are you?";
+        let instructions = compile(text).unwrap();
+        assert_eq!(3, instructions.len());
+        assert_eq!(&Literal("Hello "), &instructions[0]);
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("name")])),
+            &instructions[1]
+        );
+        assert_eq!(&Literal(", how are you?"), &instructions[2]);
+    }
+
+    #[test]
+    fn test_if_endif() {
+        let text = "{{ if foo }}Hello!{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(2, instructions.len());
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("foo")]), true, 2),
+            &instructions[0]
+        );
+        assert_eq!(&Literal("Hello!"), &instructions[1]);
+    }
+
+    #[test]
+    fn test_if_not_endif() {
+        let text = "{{ if not foo }}Hello!{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(2, instructions.len());
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("foo")]), false, 2),
+            &instructions[0]
+        );
+        assert_eq!(&Literal("Hello!"), &instructions[1]);
+    }
+
+    #[test]
+    fn test_if_else_endif() {
+        let text = "{{ if foo }}Hello!{{ else }}Goodbye!{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(4, instructions.len());
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("foo")]), true, 3),
+            &instructions[0]
+        );
+        assert_eq!(&Literal("Hello!"), &instructions[1]);
+        assert_eq!(&Goto(4), &instructions[2]);
+        assert_eq!(&Literal("Goodbye!"), &instructions[3]);
+    }
+
+    #[test]
+    fn test_if_elif_else_endif() {
+        let text = "{{ if foo }}A{{ elif not bar }}B{{ else }}C{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(7, instructions.len());
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("foo")]), true, 3),
+            &instructions[0]
+        );
+        assert_eq!(&Literal("A"), &instructions[1]);
+        assert_eq!(&Goto(7), &instructions[2]);
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("bar")]), false, 6),
+            &instructions[3]
+        );
+        assert_eq!(&Literal("B"), &instructions[4]);
+        assert_eq!(&Goto(7), &instructions[5]);
+        assert_eq!(&Literal("C"), &instructions[6]);
+    }
+
+    #[test]
+    fn test_else_if_is_elif() {
+        let elif = compile("{{ if foo }}A{{ elif bar }}B{{ endif }}").unwrap();
+        let else_if = compile("{{ if foo }}A{{ else if bar }}B{{ endif }}").unwrap();
+        assert_eq!(elif, else_if);
+        assert_eq!(&Goto(5), &elif[2]);
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("bar")]), true, 5),
+            &elif[3]
+        );
+    }
+
+    #[test]
+    fn test_elif_after_else() {
+        let text = "{{ if foo }}A{{ else }}B{{ elif bar }}C{{ endif }}";
+        compile(text).unwrap_err();
+        let text = "{{ if foo }}A{{ else }}B{{ else }}C{{ endif }}";
+        compile(text).unwrap_err();
+    }
+
+    #[test]
+    fn test_elif_outside_if() {
+        compile("{{ elif foo }}A{{ endif }}").unwrap_err();
+        compile("{{ for a in foo }}{{ elif a }}{{ endif }}{{ endfor }}").unwrap_err();
+    }
+
+    #[test]
+    fn test_if_comparison() {
+        let text = "{{ if foo.bar >= 10 }}Hello!{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(2, instructions.len());
+        assert_eq!(
+            &Compare(
+                Operand::Path(vec![PathStep::Name("foo"), PathStep::Name("bar")]),
+                Comparison::GreaterOrEqual,
+                Operand::Literal("10", ::serde_json::Value::from(10)),
+                true,
+                2
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_if_not_comparison_with_string_literal() {
+        let text = r#"{{ if not "a == b" != foo }}Hello!{{ endif }}"#;
+        let instructions = compile(text).unwrap();
+        assert_eq!(
+            &Compare(
+                Operand::Literal(r#""a == b""#, ::serde_json::Value::from("a == b")),
+                Comparison::NotEqual,
+                Operand::Path(vec![PathStep::Name("foo")]),
+                false,
+                2
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_if_comparison_with_parentheses_in_string() {
+        let text = r#"{{ if status == "(x)" and ("a)" != foo) }}Hello!{{ endif }}"#;
+        let instructions = compile(text).unwrap();
+        assert_eq!(
+            &Compare(
+                Operand::Path(vec![PathStep::Name("status")]),
+                Comparison::Equal,
+                Operand::Literal(r#""(x)""#, ::serde_json::Value::from("(x)")),
+                true,
+                3
+            ),
+            &instructions[0]
+        );
+        assert_eq!(
+            &Compare(
+                Operand::Literal(r#""a)""#, ::serde_json::Value::from("a)")),
+                Comparison::NotEqual,
+                Operand::Path(vec![PathStep::Name("foo")]),
+                true,
+                3
+            ),
+            &instructions[1]
+        );
+    }
+
+    #[test]
+    fn test_if_literal() {
+        let text = r#"{{ if "" }}Hello!{{ endif }}"#;
+        let instructions = compile(text).unwrap();
+        assert_eq!(
+            &Branch(
+                Operand::Literal(r#""""#, ::serde_json::Value::from("")),
+                true,
+                2
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_invalid_comparison_operands() {
+        compile("{{ if foo == }}{{ endif }}").unwrap_err();
+        compile("{{ if < foo }}{{ endif }}").unwrap_err();
+        compile("{{ if foo == \"bar }}{{ endif }}").unwrap_err();
+        compile("{{ if foo == 1.2.3 }}{{ endif }}").unwrap_err();
+    }
+
+    #[test]
+    fn test_if_and_not() {
+        let text = "{{ if foo and not bar }}Hello!{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(3, instructions.len());
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("foo")]), true, 3),
+            &instructions[0]
+        );
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("bar")]), false, 3),
+            &instructions[1]
+        );
+        assert_eq!(&Literal("Hello!"), &instructions[2]);
+    }
+
+    #[test]
+    fn test_if_or() {
+        let text = "{{ if foo or bar }}Hello!{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(3, instructions.len());
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("foo")]), false, 2),
+            &instructions[0]
+        );
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("bar")]), true, 3),
+            &instructions[1]
+        );
+        assert_eq!(&Literal("Hello!"), &instructions[2]);
+    }
+
+    #[test]
+    fn test_if_parenthesized() {
+        let text = "{{ if (foo or bar) and not(baz) }}Hello!{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(4, instructions.len());
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("foo")]), false, 2),
+            &instructions[0]
+        );
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("bar")]), true, 4),
+            &instructions[1]
+        );
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("baz")]), false, 4),
+            &instructions[2]
+        );
+        assert_eq!(&Literal("Hello!"), &instructions[3]);
+    }
+
+    #[test]
+    fn test_condition_keywords_inside_names() {
+        let text = "{{ if order and android.notes }}Hello!{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("order")]), true, 3),
+            &instructions[0]
+        );
+        assert_eq!(
+            &Branch(
+                Operand::Path(vec![PathStep::Name("android"), PathStep::Name("notes")]),
+                true,
+                3
+            ),
+            &instructions[1]
+        );
+    }
+
+    #[test]
+    fn test_invalid_logical_conditions() {
+        compile("{{ if (foo and bar }}{{ endif }}").unwrap_err();
+        compile("{{ if foo) }}{{ endif }}").unwrap_err();
+        compile("{{ if foo and }}{{ endif }}").unwrap_err();
+        compile("{{ if or foo }}{{ endif }}").unwrap_err();
+        compile("{{ if not }}{{ endif }}").unwrap_err();
+        compile("{{ if () }}{{ endif }}").unwrap_err();
+    }
+
+    #[test]
+    fn test_with() {
+        let text = "{{ with foo as bar }}Hello!{{ endwith }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(3, instructions.len());
+        assert_eq!(
+            &PushNamedContext(vec![PathStep::Name("foo")], "bar"),
+            &instructions[0]
+        );
+        assert_eq!(&Literal("Hello!"), &instructions[1]);
+        assert_eq!(&PopContext, &instructions[2]);
     }
 
     #[test]
-    fn test_compile_value_with_formatter() {
-        let text = "{ foobar | my_formatter }";
+    fn test_foreach() {
+        let text = "{{ for foo in bar.baz }}{ foo }{{ endfor }}";
         let instructions = compile(text).unwrap();
-        assert_eq!(1, instructions.len());
+        assert_eq!(5, instructions.len());
         assert_eq!(
-            &FormattedValue(vec![PathStep::Name("foobar")], "my_formatter"),
+            &PushIterationContext(
+                vec![PathStep::Name("bar"), PathStep::Name("baz")],
+                None,
+                "foo"
+            ),
             &instructions[0]
         );
+        assert_eq!(&Iterate(4, 4), &instructions[1]);
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("foo")])),
+            &instructions[2]
+        );
+        assert_eq!(&Goto(1), &instructions[3]);
+        assert_eq!(&PopContext, &instructions[4]);
     }
 
     #[test]
-    fn test_dotted_path() {
-        let text = "{ foo.bar }";
+    fn test_foreach_else() {
+        let text = "{{ for foo in bar }}{ foo }{{ else }}Empty{{ endfor }}";
         let instructions = compile(text).unwrap();
-        assert_eq!(1, instructions.len());
+        assert_eq!(8, instructions.len());
         assert_eq!(
-            &Value(vec![PathStep::Name("foo"), PathStep::Name("bar")]),
+            &PushIterationContext(vec![PathStep::Name("bar")], None, "foo"),
             &instructions[0]
         );
+        assert_eq!(&Iterate(4, 6), &instructions[1]);
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("foo")])),
+            &instructions[2]
+        );
+        assert_eq!(&Goto(1), &instructions[3]);
+        assert_eq!(&PopContext, &instructions[4]);
+        assert_eq!(&Goto(8), &instructions[5]);
+        assert_eq!(&PopContext, &instructions[6]);
+        assert_eq!(&Literal("Empty"), &instructions[7]);
     }
 
     #[test]
-    fn test_indexed_path() {
-        let text = "{ foo.0.bar }";
+    fn test_foreach_key_value() {
+        let text = "{{ for key, value in bar }}{ key }{{ endfor }}";
         let instructions = compile(text).unwrap();
-        assert_eq!(1, instructions.len());
+        assert_eq!(5, instructions.len());
         assert_eq!(
-            &Value(vec![
-                PathStep::Name("foo"),
-                PathStep::Index("0", 0),
-                PathStep::Name("bar")
-            ]),
+            &PushIterationContext(vec![PathStep::Name("bar")], Some("key"), "value"),
             &instructions[0]
         );
     }
 
     #[test]
-    fn test_mixture() {
-        let text = "Hello { name }, how are you?";
-        let instructions = compile(text).unwrap();
-        assert_eq!(3, instructions.len());
-        assert_eq!(&Literal("Hello "), &instructions[0]);
-        assert_eq!(&Value(vec![PathStep::Name("name")]), &instructions[1]);
-        assert_eq!(&Literal(", how are you?"), &instructions[2]);
+    fn test_foreach_key_value_errors() {
+        compile("{{ for , value in bar }}{{ endfor }}").unwrap_err();
+        compile("{{ for key, in bar }}{{ endfor }}").unwrap_err();
     }
 
     #[test]
-    fn test_if_endif() {
-        let text = "{{ if foo }}Hello!{{ endif }}";
+    fn test_foreach_range() {
+        let text = "{{ for i in range(0, count) }}{ i }{{ endfor }}{{ for i in range(5, 0, -1) }}{{ endfor }}";
         let instructions = compile(text).unwrap();
-        assert_eq!(2, instructions.len());
         assert_eq!(
-            &Branch(vec![PathStep::Name("foo")], true, 2),
+            &PushRangeContext(
+                Operand::Literal("0", ::serde_json::Value::from(0)),
+                Operand::Path(vec![PathStep::Name("count")]),
+                None,
+                "i"
+            ),
             &instructions[0]
         );
-        assert_eq!(&Literal("Hello!"), &instructions[1]);
+        assert_eq!(&Iterate(4, 4), &instructions[1]);
+        assert_eq!(
+            &PushRangeContext(
+                Operand::Literal("5", ::serde_json::Value::from(5)),
+                Operand::Literal("0", ::serde_json::Value::from(0)),
+                Some(Operand::Literal("-1", ::serde_json::Value::from(-1))),
+                "i"
+            ),
+            &instructions[5]
+        );
     }
 
     #[test]
-    fn test_if_not_endif() {
-        let text = "{{ if not foo }}Hello!{{ endif }}";
+    fn test_foreach_range_errors() {
+        compile("{{ for i in range(3) }}{{ endfor }}").unwrap_err();
+        compile("{{ for i in range(0, 3, 1, 1) }}{{ endfor }}").unwrap_err();
+        compile("{{ for i in range() }}{{ endfor }}").unwrap_err();
+        compile("{{ for k, v in range(0, 3) }}{{ endfor }}").unwrap_err();
+        // Anything other than the range keyword is a path, which is only checked when rendering.
+        compile("{{ for i in ranges(0, 3) }}{{ endfor }}").unwrap();
+    }
+
+    #[test]
+    fn test_foreach_range_with_space() {
+        let instructions = compile("{{ for i in range (0, 3) }}{{ endfor }}").unwrap();
+        assert_eq!(
+            &PushRangeContext(
+                Operand::Literal("0", ::serde_json::Value::from(0)),
+                Operand::Literal("3", ::serde_json::Value::from(3)),
+                None,
+                "i"
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_loop_keyword_path() {
+        let text = "{{ if row.@first }}{ row.@index }{{ endif }}";
         let instructions = compile(text).unwrap();
-        assert_eq!(2, instructions.len());
         assert_eq!(
-            &Branch(vec![PathStep::Name("foo")], false, 2),
+            &Branch(
+                Operand::Path(vec![PathStep::Name("row"), PathStep::Name("@first")]),
+                true,
+                2
+            ),
             &instructions[0]
         );
-        assert_eq!(&Literal("Hello!"), &instructions[1]);
+        assert_eq!(
+            &Value(Operand::Path(vec![
+                PathStep::Name("row"),
+                PathStep::Name("@index")
+            ])),
+            &instructions[1]
+        );
     }
 
     #[test]
-    fn test_if_else_endif() {
-        let text = "{{ if foo }}Hello!{{ else }}Goodbye!{{ endif }}";
+    fn test_invalid_loop_keyword_path() {
+        compile("{ row.@foo }").unwrap_err();
+        compile("{ row.@root }").unwrap_err();
+        compile("{ row.column.@index }").unwrap_err();
+        compile("{ row.@index.value }").unwrap_err();
+    }
+
+    #[test]
+    fn test_root_path() {
+        let text = "{ @root.nested.0 }";
         let instructions = compile(text).unwrap();
-        assert_eq!(4, instructions.len());
         assert_eq!(
-            &Branch(vec![PathStep::Name("foo")], true, 3),
+            &Value(Operand::Path(vec![
+                PathStep::Name("@root"),
+                PathStep::Name("nested"),
+                PathStep::Index("0", 0)
+            ])),
             &instructions[0]
         );
-        assert_eq!(&Literal("Hello!"), &instructions[1]);
-        assert_eq!(&Goto(4), &instructions[2]);
-        assert_eq!(&Literal("Goodbye!"), &instructions[3]);
     }
 
     #[test]
-    fn test_with() {
-        let text = "{{ with foo as bar }}Hello!{{ endwith }}";
+    fn test_invalid_keyword_paths() {
+        compile("{ @bogus }").unwrap_err();
+        compile("{ @root.@index }").unwrap_err();
+        compile("{ @index.value }").unwrap_err();
+        compile("{ value.@root }").unwrap_err();
+        compile("{{ with @root.@bogus as x }}{{ endwith }}").unwrap_err();
+    }
+
+    #[test]
+    fn test_invalid_path_characters() {
+        let err = compile("{{ if a = b }}{{ endif }}").unwrap_err();
+        if let ParseError { msg, line, column } = err {
+            assert!(
+                msg.starts_with("Unexpected '=' in path 'a = b'."),
+                "{}",
+                msg
+            );
+            assert_eq!(1, line);
+            assert_eq!(8, column);
+        } else {
+            panic!("Should have returned a parse error");
+        }
+        compile("{{ if a ! }}{{ endif }}").unwrap_err();
+        compile("{{ if a == first name }}{{ endif }}").unwrap_err();
+        compile("{{ if a.b-c.d_e == \"a = b\" }}{{ endif }}").unwrap();
+        // Other paths may still name fields containing whitespace or operators.
+        compile("{ first name }").unwrap();
+        compile("{{ with a<b as c }}{{ endwith }}").unwrap();
+    }
+
+    #[test]
+    fn test_let() {
+        let text = "{{ let x = a.b }}{ x }{{ set y = \"c\" | my_formatter(1) }}";
         let instructions = compile(text).unwrap();
         assert_eq!(3, instructions.len());
         assert_eq!(
-            &PushNamedContext(vec![PathStep::Name("foo")], "bar"),
+            &PushLetContext(
+                "x",
+                Operand::Path(vec![PathStep::Name("a"), PathStep::Name("b")]),
+                vec![]
+            ),
             &instructions[0]
         );
-        assert_eq!(&Literal("Hello!"), &instructions[1]);
-        assert_eq!(&PopContext, &instructions[2]);
+        assert_eq!(
+            &PushLetContext(
+                "y",
+                Operand::Literal("\"c\"", ::serde_json::Value::from("c")),
+                vec![(
+                    "my_formatter",
+                    vec![Operand::Literal("1", ::serde_json::Value::from(1))]
+                )]
+            ),
+            &instructions[2]
+        );
     }
 
     #[test]
-    fn test_foreach() {
-        let text = "{{ for foo in bar.baz }}{ foo }{{ endfor }}";
+    fn test_let_is_popped_at_end_of_section() {
+        let text = "{{ if a }}{{ let x = 1 }}{{ else }}{{ let y = 2 }}{ y }{{ endif }}";
         let instructions = compile(text).unwrap();
-        assert_eq!(5, instructions.len());
+        assert_eq!(7, instructions.len());
         assert_eq!(
-            &PushIterationContext(vec![PathStep::Name("bar"), PathStep::Name("baz")], "foo"),
+            &Branch(Operand::Path(vec![PathStep::Name("a")]), true, 4),
             &instructions[0]
         );
-        assert_eq!(&Iterate(4), &instructions[1]);
-        assert_eq!(&Value(vec![PathStep::Name("foo")]), &instructions[2]);
-        assert_eq!(&Goto(1), &instructions[3]);
-        assert_eq!(&PopContext, &instructions[4]);
+        assert_eq!(&PopContext, &instructions[2]);
+        assert_eq!(&Goto(7), &instructions[3]);
+        assert_eq!(&PopContext, &instructions[6]);
+
+        let text = "{{ for a in b }}{{ let x = a }}{{ endfor }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(6, instructions.len());
+        assert_eq!(&Iterate(5, 5), &instructions[1]);
+        assert_eq!(&PopContext, &instructions[3]);
+        assert_eq!(&Goto(1), &instructions[4]);
+    }
+
+    #[test]
+    fn test_invalid_let() {
+        compile("{{ let 1x = a }}").unwrap_err();
+        compile("{{ let = a }}").unwrap_err();
+        compile("{{ let x a }}").unwrap_err();
+        compile("{{ let x.y = a }}").unwrap_err();
+        compile("{{ let @x = a }}").unwrap_err();
+        compile("{{ let x = }}").unwrap_err();
+        compile("{{ let true = a }}").unwrap_err();
+        compile("{{ set null = a }}").unwrap_err();
+        compile("{{ let not = a }}").unwrap_err();
+        compile("{{ with a as false }}{{ endwith }}").unwrap_err();
+        compile("{{ for and in a }}{{ endfor }}").unwrap_err();
+        compile("{{ for or, x in a }}{{ endfor }}").unwrap_err();
+        compile("{{ for i in range(0, 3) }}{{ endfor }}{{ for null in range(0, 3) }}{{ endfor }}")
+            .unwrap_err();
+        compile("{{ params title, true }}").unwrap_err();
+        compile("{{ macro null(x) }}{{ endmacro }}").unwrap_err();
+        compile("{{ macro card(and) }}{{ endmacro }}").unwrap_err();
+        compile("{{ call card with not=1 }}").unwrap_err();
+        // Names which only start with a reserved word are fine.
+        compile("{{ let nothing = a }}{{ with a as order }}{{ endwith }}").unwrap();
+    }
+
+    #[test]
+    fn test_tag_keywords_are_reserved() {
+        compile("{{ let if = a }}").unwrap_err();
+        compile("{{ set endfor = a }}").unwrap_err();
+        compile("{{ with a as with }}{{ endwith }}").unwrap_err();
+        compile("{{ with a as as }}{{ endwith }}").unwrap_err();
+        compile("{{ for in in a }}{{ endfor }}").unwrap_err();
+        compile("{{ for for in a }}{{ endfor }}").unwrap_err();
+        compile("{{ for key, else in a }}{{ endfor }}").unwrap_err();
+        compile("{{ params title, include }}").unwrap_err();
+        compile("{{ macro block(x) }}{{ endmacro }}").unwrap_err();
+        compile("{{ macro card(endif) }}{{ endmacro }}").unwrap_err();
+        compile("{{ call card with call=1 }}").unwrap_err();
+        // Names which only contain a tag keyword are fine.
+        compile("{{ let iffy = a }}{{ for index in a }}{{ endfor }}").unwrap();
+    }
+
+    #[test]
+    fn test_blocks() {
+        let text = "{{ extends \"base\" }}{{ block a }}x{{ block b }}{{ super }}{{ endblock }}{{ endblock a }}";
+        let compiled = TemplateCompiler::new(text, &Delimiters::default())
+            .compile()
+            .unwrap();
+        assert_eq!(Some("base"), compiled.extends);
+        let instructions = compiled.instructions;
+        assert_eq!(4, instructions.len());
+        assert_eq!(&Block("a", 4), &instructions[0]);
+        assert_eq!(&Literal("x"), &instructions[1]);
+        assert_eq!(&Block("b", 4), &instructions[2]);
+        assert_eq!(&Super("b"), &instructions[3]);
+        assert_eq!(Some(&(1, 4)), compiled.blocks.get("a"));
+        assert_eq!(Some(&(3, 4)), compiled.blocks.get("b"));
+    }
+
+    #[test]
+    fn test_invalid_blocks() {
+        compile("x{{ extends \"base\" }}").unwrap_err();
+        compile("{{ extends \"base\" }}{{ extends \"other\" }}").unwrap_err();
+        compile("{{ extends base }}").unwrap_err();
+        compile("{{ block a }}{{ endblock }}{{ block a }}{{ endblock }}").unwrap_err();
+        compile("{{ block a }}{{ block a }}{{ endblock }}{{ endblock }}").unwrap_err();
+        compile("{{ block a }}{{ endblock b }}").unwrap_err();
+        compile("{{ block a.b }}{{ endblock }}").unwrap_err();
+        compile("{{ block a }}{{ if b }}{{ endblock }}{{ endif }}").unwrap_err();
+        compile("{{ block a }}").unwrap_err();
+        compile("{{ super }}").unwrap_err();
+    }
+
+    #[test]
+    fn test_mismatched_foreach_else() {
+        compile("{{ for a in b }}{{ else }}{{ else }}{{ endfor }}").unwrap_err();
+        compile("{{ for a in b }}{{ elif c }}{{ endfor }}").unwrap_err();
+        compile("{{ for a in b }}{{ else }}{{ endif }}").unwrap_err();
+        compile("{{ for a in b }}{{ else }}").unwrap_err();
     }
 
     #[test]
@@ -574,7 +2459,10 @@ mod test {
         let instructions = compile(text).unwrap();
         assert_eq!(3, instructions.len());
         assert_eq!(&Literal("Hello,"), &instructions[0]);
-        assert_eq!(&Value(vec![PathStep::Name("name")]), &instructions[1]);
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("name")])),
+            &instructions[1]
+        );
         assert_eq!(&Literal(", how are you?"), &instructions[2]);
     }
 
@@ -585,11 +2473,14 @@ mod test {
         assert_eq!(6, instructions.len());
         assert_eq!(&Literal("Hello,"), &instructions[0]);
         assert_eq!(
-            &Branch(vec![PathStep::Name("name")], true, 5),
+            &Branch(Operand::Path(vec![PathStep::Name("name")]), true, 5),
             &instructions[1]
         );
         assert_eq!(&Literal(""), &instructions[2]);
-        assert_eq!(&Value(vec![PathStep::Name("name")]), &instructions[3]);
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("name")])),
+            &instructions[3]
+        );
         assert_eq!(&Literal(""), &instructions[4]);
         assert_eq!(&Literal(", how are you?"), &instructions[5]);
     }
@@ -617,8 +2508,14 @@ mod test {
         let text = "{value -}{value} Hello";
         let instructions = compile(text).unwrap();
         assert_eq!(3, instructions.len());
-        assert_eq!(&Value(vec![PathStep::Name("value")]), &instructions[0]);
-        assert_eq!(&Value(vec![PathStep::Name("value")]), &instructions[1]);
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("value")])),
+            &instructions[0]
+        );
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("value")])),
+            &instructions[1]
+        );
         assert_eq!(&Literal(" Hello"), &instructions[2]);
     }
 
@@ -630,12 +2527,132 @@ mod test {
         assert_eq!(
             &Call(
                 "my_macro",
-                vec![PathStep::Name("foo"), PathStep::Name("bar")]
+                Operand::Path(vec![PathStep::Name("foo"), PathStep::Name("bar")])
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_call_with_literal() {
+        let text = r#"{{ call my_macro with "hello" }}"#;
+        let instructions = compile(text).unwrap();
+        assert_eq!(
+            &Call(
+                "my_macro",
+                Operand::Literal(r#""hello""#, ::serde_json::Value::from("hello"))
+            ),
+            &instructions[0]
+        );
+    }
+
+    #[test]
+    fn test_call_with_named_arguments() {
+        let text = r#"{{ call card with title=item.name, class="a, b=c" }}"#;
+        let instructions = compile(text).unwrap();
+        assert_eq!(
+            &CallWithArguments(
+                "card",
+                vec![
+                    (
+                        "title",
+                        Operand::Path(vec![PathStep::Name("item"), PathStep::Name("name")])
+                    ),
+                    (
+                        "class",
+                        Operand::Literal(r#""a, b=c""#, ::serde_json::Value::from("a, b=c"))
+                    ),
+                ]
             ),
             &instructions[0]
         );
     }
 
+    #[test]
+    fn test_invalid_named_arguments() {
+        compile("{{ call card with title=a, b }}").unwrap_err();
+        compile("{{ call card with title=a=b }}").unwrap_err();
+        compile("{{ call card with 1x=a }}").unwrap_err();
+        compile("{{ call card with title=a, title=b }}").unwrap_err();
+        compile("{{ call card with title= }}").unwrap_err();
+    }
+
+    #[test]
+    fn test_params() {
+        let compiled = TemplateCompiler::new(
+            "\n{{ params title, rows }}{ title }",
+            &Delimiters::default(),
+        )
+        .compile()
+        .unwrap();
+        assert_eq!(Some(vec!["title", "rows"]), compiled.params);
+        let compiled = TemplateCompiler::new("{{ params }}", &Delimiters::default())
+            .compile()
+            .unwrap();
+        assert_eq!(Some(vec![]), compiled.params);
+        let compiled = TemplateCompiler::new("{ title }", &Delimiters::default())
+            .compile()
+            .unwrap();
+        assert_eq!(None, compiled.params);
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        compile("x{{ params title }}").unwrap_err();
+        compile("{{ params title }}{{ params rows }}").unwrap_err();
+        compile("{{ params title, title }}").unwrap_err();
+        compile("{{ params title, }}").unwrap_err();
+        compile("{{ params a.b }}").unwrap_err();
+    }
+
+    #[test]
+    fn test_macro() {
+        let text = "{{ import \"forms\" }}{{ macro card(title, class) }}{ title }{{ let x = class }}{{ endmacro }}!";
+        let compiled = TemplateCompiler::new(text, &Delimiters::default())
+            .compile()
+            .unwrap();
+        assert_eq!(vec!["forms"], compiled.imports);
+        let instructions = compiled.instructions;
+        assert_eq!(5, instructions.len());
+        assert_eq!(&Goto(4), &instructions[0]);
+        assert_eq!(&Literal("!"), &instructions[4]);
+        let definition = &compiled.macros["card"];
+        assert_eq!(vec!["title", "class"], definition.params);
+        assert_eq!((1, 4), (definition.start, definition.end));
+    }
+
+    #[test]
+    fn test_invalid_macro() {
+        compile("{{ macro card }}{{ endmacro }}").unwrap_err();
+        compile("{{ macro card( }}{{ endmacro }}").unwrap_err();
+        compile("{{ macro 1card() }}{{ endmacro }}").unwrap_err();
+        compile("{{ macro card(a, a) }}{{ endmacro }}").unwrap_err();
+        compile("{{ macro card() }}{{ endmacro }}{{ macro card() }}{{ endmacro }}").unwrap_err();
+        compile("{{ macro card() }}{{ macro row() }}{{ endmacro }}{{ endmacro }}").unwrap_err();
+        compile("{{ if a }}{{ macro card() }}{{ endmacro }}{{ endif }}").unwrap_err();
+        compile("{{ macro card() }}{{ block a }}{{ endblock }}{{ endmacro }}").unwrap_err();
+        compile("{{ macro card() }}{{ if a }}{{ endmacro }}{{ endif }}").unwrap_err();
+        compile("{{ macro card() }}").unwrap_err();
+        compile("{{ endmacro }}").unwrap_err();
+        compile("x{{ import \"forms\" }}").unwrap_err();
+        compile("{{ import forms }}").unwrap_err();
+    }
+
+    #[test]
+    fn test_include() {
+        let text = "{{ for a in array }}{{ include \"row\" }}{{ endfor }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(5, instructions.len());
+        assert_eq!(&Include("row"), &instructions[2]);
+    }
+
+    #[test]
+    fn test_invalid_include() {
+        compile("{{ include row }}").unwrap_err();
+        compile("{{ include }}").unwrap_err();
+        compile("{{ include \"row\" extra }}").unwrap_err();
+    }
+
     #[test]
     fn test_curly_brace_escaping() {
         let text = "body \\{ \nfont-size: {fontsize} \n}";
@@ -643,7 +2660,10 @@ mod test {
         assert_eq!(4, instructions.len());
         assert_eq!(&Literal("body "), &instructions[0]);
         assert_eq!(&Literal("{ \nfont-size: "), &instructions[1]);
-        assert_eq!(&Value(vec![PathStep::Name("fontsize")]), &instructions[2]);
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("fontsize")])),
+            &instructions[2]
+        );
         assert_eq!(&Literal(" \n}"), &instructions[3]);
     }
 
@@ -655,16 +2675,101 @@ mod test {
             "{ foo.bar\n }",
             "{{",
             "{{ if foo.bar",
-            "{{ if foo.bar \n}}",
+            "{{ if foo.bar \n{{ endif }}",
             "{#",
             "{# if foo.bar",
-            "{# if foo.bar \n#}",
         ];
         for tag in tags {
             compile(tag).unwrap_err();
         }
     }
 
+    #[test]
+    fn test_multi_line_tags() {
+        let text = "{#\n  A comment with {{ tags }} in it\n#}{{ call card with\n    title=a,\n    rows=b\n}}{{ if\r\nfoo\r\n}}x{{ endif }}";
+        let instructions = compile(text).unwrap();
+        assert_eq!(3, instructions.len());
+        assert_eq!(
+            &CallWithArguments(
+                "card",
+                vec![
+                    ("title", Operand::Path(vec![PathStep::Name("a")])),
+                    ("rows", Operand::Path(vec![PathStep::Name("b")])),
+                ]
+            ),
+            &instructions[0]
+        );
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("foo")]), true, 3),
+            &instructions[1]
+        );
+    }
+
+    #[test]
+    fn test_start_delimiter_in_string_literal() {
+        let text = r#"{{ if x == "{{" }}yes{{ endif }}{{ let y = "é \" {{" }}"#;
+        let instructions = compile(text).unwrap();
+        assert_eq!(
+            &Compare(
+                Operand::Path(vec![PathStep::Name("x")]),
+                Comparison::Equal,
+                Operand::Literal(r#""{{""#, ::serde_json::Value::from("{{")),
+                true,
+                2
+            ),
+            &instructions[0]
+        );
+        compile(r#"{{ if x == "a" {{ endif }}"#).unwrap_err();
+        // Quotes in the text after a tag don't hide the tags which follow it.
+        let instructions = compile(r#"{{ if x }}<a href="{{ endif }}"#).unwrap();
+        assert_eq!(&Literal(r#"<a href=""#), &instructions[1]);
+    }
+
+    #[test]
+    fn test_end_delimiter_in_string_literal() {
+        let text = r#"{{ if x == "}}" }}yes{{ endif }}{ "a}b" | my_formatter("}") }"#;
+        let instructions = compile(text).unwrap();
+        assert_eq!(
+            &Compare(
+                Operand::Path(vec![PathStep::Name("x")]),
+                Comparison::Equal,
+                Operand::Literal(r#""}}""#, ::serde_json::Value::from("}}")),
+                true,
+                2
+            ),
+            &instructions[0]
+        );
+        assert_eq!(&Literal("yes"), &instructions[1]);
+        assert_eq!(
+            &FormattedValue(
+                Operand::Literal(r#""a}b""#, ::serde_json::Value::from("a}b")),
+                vec![(
+                    "my_formatter",
+                    vec![Operand::Literal(r#""}""#, ::serde_json::Value::from("}"))]
+                )]
+            ),
+            &instructions[2]
+        );
+    }
+
+    #[test]
+    fn test_unclosed_tag_error_location() {
+        for (text, expected_line, expected_column) in &[
+            ("a\n  {{ if foo\n\nbar {{ endif }}", 2, 2),
+            ("a\nb {{ if foo\n\nbar", 2, 2),
+            ("\n\n {# note\n", 3, 1),
+            ("ab\n{ foo\n}", 2, 0),
+        ] {
+            match compile(text).unwrap_err() {
+                ParseError { line, column, .. } => {
+                    assert_eq!(*expected_line, line);
+                    assert_eq!(*expected_column, column);
+                }
+                err => panic!("Should have returned a parse error, got {:?}", err),
+            }
+        }
+    }
+
     #[test]
     fn test_mismatched_blocks() {
         let text = "{{ if foo }}{{ with bar }}{{ endif }} {{ endwith }}";
@@ -695,6 +2800,135 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_raw() {
+        let text = "a{{ raw }}{{ if x }}{ y } \\{ {#{{ endraw }}b";
+        let instructions = compile(text).unwrap();
+        assert_eq!(3, instructions.len());
+        assert_eq!(&Literal("a"), &instructions[0]);
+        assert_eq!(&Literal("{{ if x }}{ y } \\{ {#"), &instructions[1]);
+        assert_eq!(&Literal("b"), &instructions[2]);
+    }
+
+    #[test]
+    fn test_raw_whitespace_trimming() {
+        let text = "a {{- raw -}}\n  { x }  \n{{- endraw -}} b";
+        let instructions = compile(text).unwrap();
+        assert_eq!(3, instructions.len());
+        assert_eq!(&Literal("a"), &instructions[0]);
+        assert_eq!(&Literal("{ x }"), &instructions[1]);
+        assert_eq!(&Literal("b"), &instructions[2]);
+    }
+
+    #[test]
+    fn test_parse_error_on_unclosed_raw() {
+        let text = "\n  {{ raw }}{{ endraw x }}{{ end raw }}";
+        let err = compile(text).unwrap_err();
+        if let ParseError { line, column, .. } = err {
+            assert_eq!(2, line);
+            assert_eq!(5, column);
+        } else {
+            panic!("Should have returned a parse error");
+        }
+        compile("{{ raw x }}{{ endraw }}").unwrap_err();
+        compile("{{ endraw }}").unwrap_err();
+    }
+
+    fn compile_with(
+        text: &'static str,
+        delimiters: &Delimiters,
+    ) -> Result<Vec<Instruction<'static>>> {
+        TemplateCompiler::new(text, delimiters)
+            .compile()
+            .map(|compiled| compiled.instructions)
+    }
+
+    fn compile_block_whitespace(
+        text: &'static str,
+        trim_blocks: bool,
+        lstrip_blocks: bool,
+    ) -> Vec<Instruction<'static>> {
+        TemplateCompiler::new(text, &Delimiters::default())
+            .block_whitespace(trim_blocks, lstrip_blocks)
+            .compile()
+            .unwrap()
+            .instructions
+    }
+
+    #[test]
+    fn test_trim_blocks() {
+        let text = "{{ if a }}\n  x { b }\n{# c #}\r\n\ny{{ endif }}\n\n";
+        let instructions = compile_block_whitespace(text, true, false);
+        assert_eq!(6, instructions.len());
+        assert_eq!(&Literal("  x "), &instructions[1]);
+        assert_eq!(&Literal("\n"), &instructions[3]);
+        assert_eq!(&Literal("\ny"), &instructions[4]);
+        assert_eq!(&Literal("\n"), &instructions[5]);
+    }
+
+    #[test]
+    fn test_lstrip_blocks() {
+        let text = "  {{ if a }}\n\t {# b #}x  {{ endif }}\n  { c }";
+        let instructions = compile_block_whitespace(text, false, true);
+        assert_eq!(6, instructions.len());
+        assert_eq!(&Literal(""), &instructions[0]);
+        assert_eq!(&Literal("\n"), &instructions[2]);
+        assert_eq!(&Literal("x  "), &instructions[3]);
+        assert_eq!(&Literal("\n  "), &instructions[4]);
+    }
+
+    #[test]
+    fn test_block_whitespace_markers() {
+        let text = "a\n  {{- if a }}\n\n  x\n  {{ endif -}}\n\nb";
+        let instructions = compile_block_whitespace(text, true, true);
+        assert_eq!(4, instructions.len());
+        assert_eq!(&Literal("a"), &instructions[0]);
+        assert_eq!(&Literal("\n  x\n"), &instructions[2]);
+        assert_eq!(&Literal("b"), &instructions[3]);
+    }
+
+    #[test]
+    fn test_custom_delimiters() {
+        let delimiters = Delimiters::new(("<%", "%>"), ("<%=", "%>"), ("<%#", "%>")).unwrap();
+        let text = "{ a } <%# comment %><% if b -%>  <%= c %><% endif %>}";
+        let instructions = compile_with(text, &delimiters).unwrap();
+        assert_eq!(5, instructions.len());
+        assert_eq!(&Literal("{ a } "), &instructions[0]);
+        assert_eq!(
+            &Branch(Operand::Path(vec![PathStep::Name("b")]), true, 4),
+            &instructions[1]
+        );
+        assert_eq!(&Literal(""), &instructions[2]);
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("c")])),
+            &instructions[3]
+        );
+        assert_eq!(&Literal("}"), &instructions[4]);
+    }
+
+    #[test]
+    fn test_custom_delimiters_escaping_and_raw() {
+        let delimiters = Delimiters::new(("[[", "]]"), ("[", "]"), ("[#", "#]")).unwrap();
+        let text = "\\[x] [[ raw ]][a][[ endraw ]][ b ]";
+        let instructions = compile_with(text, &delimiters).unwrap();
+        assert_eq!(4, instructions.len());
+        assert_eq!(&Literal(""), &instructions[0]);
+        assert_eq!(&Literal("[x] "), &instructions[1]);
+        assert_eq!(&Literal("[a]"), &instructions[2]);
+        assert_eq!(
+            &Value(Operand::Path(vec![PathStep::Name("b")])),
+            &instructions[3]
+        );
+    }
+
+    #[test]
+    fn test_custom_delimiters_errors() {
+        let delimiters = Delimiters::new(("<%", "%>"), ("<%=", "%>"), ("<%#", "%>")).unwrap();
+        compile_with("<%= a }", &delimiters).unwrap_err();
+        compile_with("<% if a %>", &delimiters).unwrap_err();
+        compile_with("<% raw %>{{ endraw }}", &delimiters).unwrap_err();
+    }
+
     #[test]
     fn test_parse_error_on_unclosed_if() {
         let text = "{{ if foo }}";
@@ -1,13 +1,15 @@
 //! Module containing the error type returned by TinyTemplate if an error occurs.
 
-use instruction::{path_to_str, PathSlice};
+use instruction::{path_to_str, Operand, PathSlice};
 use serde_json::Error as SerdeJsonError;
 use serde_json::Value;
 use std::error::Error as StdError;
 use std::fmt;
+use std::io;
 
 /// Enum representing the potential errors that TinyTemplate can encounter.
 #[derive(Debug)]
+#[allow(clippy::manual_non_exhaustive)]
 pub enum Error {
     ParseError {
         msg: String,
@@ -40,6 +42,12 @@ pub enum Error {
         line: usize,
         column: usize,
     },
+    WriteError {
+        err: io::Error,
+    },
+    FmtWriteError {
+        err: fmt::Error,
+    },
 
     #[doc(hidden)]
     __NonExhaustive,
@@ -54,6 +62,11 @@ impl From<fmt::Error> for Error {
         Error::StdFormatError { err }
     }
 }
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::WriteError { err }
+    }
+}
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -102,6 +115,12 @@ impl fmt::Display for Error {
                     name, line, column, err
                 )
             }
+            Error::WriteError { err } => {
+                write!(f, "Failed to write the rendered template. Error: {}", err)
+            }
+            Error::FmtWriteError { err } => {
+                write!(f, "Failed to write the rendered template. Error: {}", err)
+            }
             Error::__NonExhaustive => unreachable!(),
         }
     }
@@ -112,10 +131,12 @@ impl StdError for Error {
             Error::ParseError { .. } => "ParseError",
             Error::RenderError { .. } => "RenderError",
             Error::SerdeError { .. } => "SerdeError",
-            Error::GenericError { msg } => &msg,
+            Error::GenericError { msg } => msg,
             Error::StdFormatError { .. } => "StdFormatError",
             Error::CalledTemplateError { .. } => "CalledTemplateError",
             Error::CalledFormatterError { .. } => "CalledFormatterError",
+            Error::WriteError { .. } => "WriteError",
+            Error::FmtWriteError { .. } => "FmtWriteError",
             Error::__NonExhaustive => unreachable!(),
         }
     }
@@ -153,18 +174,56 @@ pub(crate) fn lookup_error(source: &str, step: &str, path: PathSlice, current: &
     }
 }
 
-pub(crate) fn truthiness_error(source: &str, path: PathSlice) -> Error {
-    let (line, column) = get_offset(source, path.last().unwrap());
+pub(crate) fn truthiness_error(source: &str, operand: &Operand) -> Error {
+    let (location, msg) = match operand {
+        Operand::Path(path) => (
+            &**path.last().unwrap(),
+            format!(
+                "Path '{}' produced a value which could not be checked for truthiness.",
+                path_to_str(path)
+            ),
+        ),
+        Operand::Literal(text, _) => (
+            *text,
+            format!("Literal '{}' could not be checked for truthiness.", text),
+        ),
+    };
+    let (line, column) = get_offset(source, location);
+    Error::RenderError { msg, line, column }
+}
+
+pub(crate) fn incomparable_error(
+    source: &str,
+    location: &str,
+    operator: &str,
+    left: &Value,
+    right: &Value,
+) -> Error {
+    let (line, column) = get_offset(source, location);
     Error::RenderError {
         msg: format!(
-            "Path '{}' produced a value which could not be checked for truthiness.",
-            path_to_str(path)
+            "Cannot compare {} with {} using '{}'.",
+            type_name(left),
+            type_name(right),
+            operator
         ),
         line,
         column,
     }
 }
 
+/// Describe the JSON type of a value for use in error messages.
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
 pub(crate) fn unprintable_error() -> Error {
     Error::GenericError {
         msg: "Expected a printable value but found array or object.".to_string(),
@@ -175,7 +234,19 @@ pub(crate) fn not_iterable_error(source: &str, path: PathSlice) -> Error {
     let (line, column) = get_offset(source, path.last().unwrap());
     Error::RenderError {
         msg: format!(
-            "Expected an array for path '{}' but found a non-iterable value.",
+            "Expected an array or object for path '{}' but found a non-iterable value.",
+            path_to_str(path)
+        ),
+        line,
+        column,
+    }
+}
+
+pub(crate) fn not_object_error(source: &str, path: PathSlice) -> Error {
+    let (line, column) = get_offset(source, path.last().unwrap());
+    Error::RenderError {
+        msg: format!(
+            "Expected an object for path '{}' to iterate over keys and values, but found an array.",
             path_to_str(path)
         ),
         line,
@@ -183,6 +254,11 @@ pub(crate) fn not_iterable_error(source: &str, path: PathSlice) -> Error {
     }
 }
 
+pub(crate) fn invalid_range_error(source: &str, location: &str, msg: String) -> Error {
+    let (line, column) = get_offset(source, location);
+    Error::RenderError { msg, line, column }
+}
+
 pub(crate) fn unknown_template(source: &str, name: &str) -> Error {
     let (line, column) = get_offset(source, name);
     Error::RenderError {
@@ -192,6 +268,76 @@ pub(crate) fn unknown_template(source: &str, name: &str) -> Error {
     }
 }
 
+pub(crate) fn unexpected_argument_error(
+    source: &str,
+    template_name: &str,
+    argument: &str,
+) -> Error {
+    let (line, column) = get_offset(source, argument);
+    Error::RenderError {
+        msg: format!(
+            "Passed argument '{}' to template '{}', which does not declare that parameter",
+            argument, template_name
+        ),
+        line,
+        column,
+    }
+}
+
+pub(crate) fn missing_argument_error(source: &str, template_name: &str, param: &str) -> Error {
+    let (line, column) = get_offset(source, template_name);
+    Error::RenderError {
+        msg: format!(
+            "Template '{}' requires the argument '{}', but it was not passed",
+            template_name, param
+        ),
+        line,
+        column,
+    }
+}
+
+pub(crate) fn unknown_import_template(source: &str, name: &str) -> Error {
+    let (line, column) = get_offset(source, name);
+    Error::RenderError {
+        msg: format!("Tried to import macros from an unknown template '{}'", name),
+        line,
+        column,
+    }
+}
+
+pub(crate) fn unknown_parent_template(source: &str, name: &str) -> Error {
+    let (line, column) = get_offset(source, name);
+    Error::RenderError {
+        msg: format!("Tried to extend an unknown template '{}'", name),
+        line,
+        column,
+    }
+}
+
+pub(crate) fn inheritance_cycle_error(source: &str, name: &str) -> Error {
+    let (line, column) = get_offset(source, name);
+    Error::RenderError {
+        msg: format!(
+            "Extending template '{}' would make the template inherit from itself",
+            name
+        ),
+        line,
+        column,
+    }
+}
+
+pub(crate) fn no_parent_block_error(source: &str, name: &str) -> Error {
+    let (line, column) = get_offset(source, name);
+    Error::RenderError {
+        msg: format!(
+            "Used super in block '{}', but no parent template defines that block",
+            name
+        ),
+        line,
+        column,
+    }
+}
+
 pub(crate) fn unknown_formatter(source: &str, name: &str) -> Error {
     let (line, column) = get_offset(source, name);
     Error::RenderError {
@@ -202,6 +348,11 @@ pub(crate) fn unknown_formatter(source: &str, name: &str) -> Error {
 }
 
 pub(crate) fn called_template_error(source: &str, template_name: &str, err: Error) -> Error {
+    // Failing to write the output is not a problem with the called template, so pass it through.
+    match err {
+        Error::WriteError { .. } | Error::FmtWriteError { .. } => return err,
+        _ => {}
+    }
     let (line, column) = get_offset(source, template_name);
     Error::CalledTemplateError {
         name: template_name.to_string(),
@@ -1,3 +1,4 @@
+use serde_json::Value;
 use std::ops::Deref;
 
 /// TinyTemplate implements a simple bytecode interpreter for its template engine. Instructions
@@ -8,7 +9,7 @@ use std::ops::Deref;
 /// longer-lived than the TinyTemplate instance) so paths and instructions simply borrow string
 /// slices from the template text. These string slices can then be appended directly to the output
 /// string.
-
+///
 /// Enum for a step in a path which optionally contains a parsed index.
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub(crate) enum PathStep<'template> {
@@ -32,44 +33,135 @@ pub(crate) type Path<'template> = Vec<PathStep<'template>>;
 /// Path, but as a slice.
 pub(crate) type PathSlice<'a, 'template> = &'a [PathStep<'template>];
 
+/// Enum for a value used by an instruction, which is either a path to look up in the context or a
+/// literal value written in the template. Literals are parsed when the template is compiled, so
+/// rendering them only needs to borrow the value. They also keep the text they were parsed from so
+/// that errors can point at them.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub(crate) enum Operand<'template> {
+    Path(Path<'template>),
+    Literal(&'template str, Value),
+}
+
+/// A step in a chain of formatters, consisting of the formatter's name and the arguments passed to
+/// it.
+pub(crate) type FormatterCall<'template> = (&'template str, Vec<Operand<'template>>);
+
+/// An argument passed by name to a called template, consisting of the argument's name and its
+/// value.
+pub(crate) type NamedArgument<'template> = (&'template str, Operand<'template>);
+
+/// Enum for the comparison operators which can be used in conditions.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub(crate) enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+impl Comparison {
+    /// The operator as it is written in the template.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        }
+    }
+}
+
 /// Enum representing the bytecode instructions.
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub(crate) enum Instruction<'template> {
     /// Emit a literal string into the output buffer
     Literal(&'template str),
 
-    /// Look up the value for the given path and render it into the output buffer using the default
-    /// formatter
-    Value(Path<'template>),
+    /// Look up the value for the given operand and render it into the output buffer using the
+    /// default formatter
+    Value(Operand<'template>),
+
+    /// Look up the value for the given operand and pass it through the given formatters, in order.
+    /// Each formatter receives the output of the one before it, along with its own arguments.
+    FormattedValue(Operand<'template>, Vec<FormatterCall<'template>>),
 
-    /// Look up the value for the given path and pass it to the formatter with the given name
-    FormattedValue(Path<'template>, &'template str),
+    /// Look up the value for the given operand and jump to the given instruction index if that
+    /// value is truthy (if the boolean is true) or falsy (if the boolean is false)
+    Branch(Operand<'template>, bool, usize),
 
-    /// Look up the value at the given path and jump to the given instruction index if that value
-    /// is truthy (if the boolean is true) or falsy (if the boolean is false)
-    Branch(Path<'template>, bool, usize),
+    /// Compare the two operands with the given operator. Like Branch, the boolean negates the
+    /// result and the jump to the given instruction index is taken if the (negated) result is true.
+    Compare(
+        Operand<'template>,
+        Comparison,
+        Operand<'template>,
+        bool,
+        usize,
+    ),
 
     /// Push a named context on the stack, shadowing only that name.
     PushNamedContext(Path<'template>, &'template str),
 
-    /// Push an iteration context on the stack, shadowing the given name with the current value from
-    /// the vec pointed to by the path. The current value will be updated by the Iterate instruction.
+    /// Push a named context on the stack, holding the value of the operand after passing it through
+    /// the formatters (if there are any). Used for let tags, whose contexts are popped at the end of
+    /// the enclosing block.
+    PushLetContext(
+        &'template str,
+        Operand<'template>,
+        Vec<FormatterCall<'template>>,
+    ),
+
+    /// Push an iteration context on the stack, shadowing the last name with the current value from
+    /// the array or object pointed to by the path. If a key name is given, it is shadowed with the
+    /// current key of the object. The current value will be updated by the Iterate instruction.
     /// This is always generated before an Iterate instruction which actually starts the iterator.
-    PushIterationContext(Path<'template>, &'template str),
+    PushIterationContext(Path<'template>, Option<&'template str>, &'template str),
+
+    /// Push an iteration context on the stack, shadowing the given name with the current number
+    /// of a range. The operands are the start (inclusive), the end (exclusive) and the step size,
+    /// which is one if not given. Like PushIterationContext, this is always followed by an Iterate.
+    PushRangeContext(
+        Operand<'template>,
+        Operand<'template>,
+        Option<Operand<'template>>,
+        &'template str,
+    ),
 
     /// Pop a context off the stack
     PopContext,
 
     /// Advance the topmost iterator on the context stack by one and update that context. If the
-    /// iterator is empty, jump to the given instruction.
-    Iterate(usize),
+    /// iterator is exhausted, jump to the first instruction, or to the second if the iterator was
+    /// empty to begin with. The two are the same unless the loop has an else section.
+    Iterate(usize, usize),
+
+    /// Render the block with the given name. If a template which extends this one overrides the
+    /// block, the overriding block is rendered and execution jumps to the given instruction, which
+    /// is the end of this block. Otherwise, execution continues with this block's own contents.
+    Block(&'template str, usize),
+
+    /// Render the block with the given name as it is defined by the closest ancestor of the
+    /// template containing this instruction.
+    Super(&'template str),
 
     /// Unconditionally jump to the given instruction. Used to skip else blocks and repeat loops.
     Goto(usize),
 
-    /// Look up the named template and render it into the output buffer with the value pointed to
-    /// by the path as its context.
-    Call(&'template str, Path<'template>),
+    /// Look up the named template and render it into the output buffer with the value of the
+    /// operand as its context.
+    Call(&'template str, Operand<'template>),
+
+    /// Look up the named template and render it into the output buffer with an object built from
+    /// the named arguments as its context.
+    CallWithArguments(&'template str, Vec<NamedArgument<'template>>),
+
+    /// Render the template with the given name using the current context stack, so that it can see
+    /// everything that is visible at the point where it is included.
+    Include(&'template str),
 }
 
 /// Convert a path back into a dotted string.
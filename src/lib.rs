@@ -13,8 +13,9 @@
 //! * Rendering values - `{ myvalue }`
 //! * Conditionals - `{{ if foo }}Foo is true{{ else }}Foo is false{{ endif }}`
 //! * Loops - `{{ for value in row }}{value}{{ endfor }}`
-//! * Customizable value formatters `{ value | my_formatter }`
+//! * Customizable value formatters `{ value | my_formatter }`, which can be chained
 //! * Macros `{{ call my_template with foo }}`
+//! * Template inheritance `{{ extends "base" }}{{ block body }}...{{ endblock }}`
 //!
 //! ## Restrictions
 //!
@@ -23,8 +24,11 @@
 //! template text itself and uses them during the rendering process. Although it is possible to use
 //! TinyTemplate with template strings loaded at runtime, this is not recommended.
 //!
-//! Additionally, TinyTemplate can only render templates into Strings. If you need to render a
-//! template directly to a socket or file, TinyTemplate may not be right for you.
+//! Templates can be rendered into a String with `render`, or directly into a file, socket or other
+//! `std::io::Write` with `render_to_writer`, and into a `std::fmt::Write` with `render_to_fmt`.
+//! With the optional `async` feature, `render_async` streams a template into a tokio `AsyncWrite`
+//! as it is rendered. If the context is already a `serde_json::Value`, the `render_value` methods
+//! borrow from it directly.
 //!
 //! ## Example
 //!
@@ -64,6 +68,8 @@
 
 extern crate serde;
 extern crate serde_json;
+#[cfg(feature = "async")]
+extern crate tokio;
 
 #[cfg(test)]
 #[cfg_attr(test, macro_use)]
@@ -72,19 +78,114 @@ extern crate serde_derive;
 mod compiler;
 pub mod error;
 mod instruction;
+mod lookup;
+#[cfg(feature = "async")]
+mod render_async;
 pub mod syntax;
 mod template;
 
 use error::*;
+use lookup::{Lookup, RootNames, Serialized};
 use serde::Serialize;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::fmt;
 use std::fmt::Write;
-use template::Template;
+use std::io;
+use std::ops::RangeInclusive;
+use template::{FmtOutput, IoOutput, Output, Template};
+#[cfg(feature = "async")]
+use tokio::io::AsyncWrite;
+
+#[cfg(feature = "async")]
+pub use render_async::RenderAsync;
 
 /// Type alias for closures which can be used as value formatters.
 pub type ValueFormatter = dyn Fn(&Value, &mut String) -> Result<()>;
 
+/// Type alias for closures which can be used as value transformers. Transformers are used like
+/// formatters, but they produce a new value instead of appending text to the output. This makes
+/// them useful in the middle of a chain of formatters, like `{ value | trim | upper }`.
+pub type ValueTransformer = dyn Fn(&Value) -> Result<Value>;
+
+/// Type alias for closures which can be used as formatters that take arguments, like
+/// `{ price | round(2) }`. The second parameter holds the values of the arguments given in the
+/// template.
+pub type ArgumentFormatter = dyn Fn(&Value, &[&Value], &mut String) -> Result<()>;
+
+/// The delimiters which mark block tags, values and comments in a template. By default, block
+/// tags are written as `{{ if x }}`, values as `{ x }` and comments as `{# note #}`. Other
+/// delimiters can be useful for templates of text which uses many curly braces itself:
+///
+/// ```
+/// # use tinytemplate::{Delimiters, TinyTemplate};
+/// let mut tt = TinyTemplate::new();
+/// tt.set_delimiters(Delimiters::new(("<%", "%>"), ("<%=", "%>"), ("<%#", "%>")).unwrap());
+/// tt.add_template("erb", "<%# A comment %><% if name %>{<%= name %>}<% endif %>").unwrap();
+/// # let mut context = ::std::collections::HashMap::new();
+/// # context.insert("name", "value");
+/// # assert_eq!("{value}", tt.render("erb", &context).unwrap());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delimiters {
+    pub(crate) block: (String, String),
+    pub(crate) value: (String, String),
+    pub(crate) comment: (String, String),
+}
+impl Delimiters {
+    /// Create a set of delimiters from the start and end delimiters of block tags, values and
+    /// comments. Returns an error if any delimiter is empty or contains whitespace, or if two kinds
+    /// of tag start with the same delimiter. When one start delimiter begins with another, as `{{`
+    /// begins with `{` in the default delimiters, the longer one takes precedence.
+    pub fn new(
+        block: (&str, &str),
+        value: (&str, &str),
+        comment: (&str, &str),
+    ) -> Result<Delimiters> {
+        let delimiters = [block.0, block.1, value.0, value.1, comment.0, comment.1];
+        if delimiters.iter().any(|delimiter| delimiter.is_empty()) {
+            return Err(Error::GenericError {
+                msg: "Template delimiters must not be empty".to_string(),
+            });
+        }
+        if delimiters
+            .iter()
+            .any(|delimiter| delimiter.chars().any(char::is_whitespace))
+        {
+            return Err(Error::GenericError {
+                msg: "Template delimiters must not contain whitespace".to_string(),
+            });
+        }
+        if block.0 == value.0 || block.0 == comment.0 || value.0 == comment.0 {
+            return Err(Error::GenericError {
+                msg: "Block tags, values and comments must have different start delimiters"
+                    .to_string(),
+            });
+        }
+        Ok(Delimiters {
+            block: (block.0.to_string(), block.1.to_string()),
+            value: (value.0.to_string(), value.1.to_string()),
+            comment: (comment.0.to_string(), comment.1.to_string()),
+        })
+    }
+}
+impl Default for Delimiters {
+    fn default() -> Delimiters {
+        Delimiters::new(("{{", "}}"), ("{", "}"), ("{#", "#}")).unwrap()
+    }
+}
+
+/// The kinds of functions which can be registered under a formatter name.
+pub(crate) enum Formatter {
+    /// Appends the formatted value to the output.
+    Value(Box<ValueFormatter>),
+    /// Converts the value into another value, to be passed on to the next formatter in the chain.
+    Transformer(Box<ValueTransformer>),
+    /// Appends the formatted value to the output, using the given arguments. The range is the
+    /// number of arguments that the formatter accepts.
+    Arguments(RangeInclusive<usize>, Box<ArgumentFormatter>),
+}
+
 /// Appends `value` to `output`, performing HTML-escaping in the process.
 pub fn escape(value: &str, output: &mut String) {
     // Algorithm taken from the rustdoc source code.
@@ -168,8 +269,14 @@ pub fn format_unescaped(value: &Value, output: &mut String) -> Result<()> {
 /// register templates and formatters.
 pub struct TinyTemplate<'template> {
     templates: HashMap<&'template str, Template<'template>>,
-    formatters: HashMap<&'template str, Box<ValueFormatter>>,
+    /// The top-level names which the templates look up, so that only those values of a context
+    /// are converted when rendering with it.
+    root_names: RootNames<'template>,
+    formatters: HashMap<&'template str, Formatter>,
     default_formatter: &'template ValueFormatter,
+    delimiters: Delimiters,
+    trim_blocks: bool,
+    lstrip_blocks: bool,
 }
 impl<'template> TinyTemplate<'template> {
     /// Create a new TinyTemplate registry. The returned registry contains no templates, and has
@@ -177,8 +284,12 @@ impl<'template> TinyTemplate<'template> {
     pub fn new() -> TinyTemplate<'template> {
         let mut tt = TinyTemplate {
             templates: HashMap::default(),
+            root_names: RootNames::default(),
             formatters: HashMap::default(),
             default_formatter: &format,
+            delimiters: Delimiters::default(),
+            trim_blocks: false,
+            lstrip_blocks: false,
         };
         tt.add_formatter("unescaped", format_unescaped);
         tt
@@ -186,11 +297,58 @@ impl<'template> TinyTemplate<'template> {
 
     /// Parse and compile the given template, then register it under the given name.
     pub fn add_template(&mut self, name: &'template str, text: &'template str) -> Result<()> {
-        let template = Template::compile(text)?;
+        let template =
+            Template::compile(text, &self.delimiters, self.trim_blocks, self.lstrip_blocks)?;
+        template.root_names(&mut self.root_names);
+        self.templates.insert(name, template);
+        Ok(())
+    }
+
+    /// Parse and compile the given template using the given delimiters instead of the delimiters
+    /// of this registry, then register it under the given name.
+    pub fn add_template_with_delimiters(
+        &mut self,
+        name: &'template str,
+        text: &'template str,
+        delimiters: &Delimiters,
+    ) -> Result<()> {
+        let template = Template::compile(text, delimiters, self.trim_blocks, self.lstrip_blocks)?;
+        template.root_names(&mut self.root_names);
         self.templates.insert(name, template);
         Ok(())
     }
 
+    /// Changes the delimiters used by templates which are added to this registry afterwards.
+    /// Templates which have already been added are not affected.
+    pub fn set_delimiters(&mut self, delimiters: Delimiters) {
+        self.delimiters = delimiters;
+    }
+
+    /// Changes whether the first newline after each block tag or comment is removed, in templates
+    /// which are added to this registry afterwards. This is off by default. A `-` at the end of a
+    /// tag still removes all of the whitespace after it.
+    ///
+    /// ```
+    /// # use tinytemplate::TinyTemplate;
+    /// let mut tt = TinyTemplate::new();
+    /// tt.set_trim_blocks(true);
+    /// tt.set_lstrip_blocks(true);
+    /// tt.add_template("list", "<ul>\n  {{ for item in items }}\n  <li>{ item }</li>\n  {{ endfor }}\n</ul>").unwrap();
+    /// # let mut context = ::std::collections::HashMap::new();
+    /// # context.insert("items", vec![1, 2]);
+    /// assert_eq!("<ul>\n  <li>1</li>\n  <li>2</li>\n</ul>", tt.render("list", &context).unwrap());
+    /// ```
+    pub fn set_trim_blocks(&mut self, trim_blocks: bool) {
+        self.trim_blocks = trim_blocks;
+    }
+
+    /// Changes whether the spaces and tabs between the start of a line and a block tag or comment
+    /// are stripped, in templates which are added to this registry afterwards. This is off by
+    /// default. A `-` at the start of a tag still removes all of the whitespace before it.
+    pub fn set_lstrip_blocks(&mut self, lstrip_blocks: bool) {
+        self.lstrip_blocks = lstrip_blocks;
+    }
+
     /// Changes the default formatter from [`format`](fn.format.html) to `formatter`. Usefull in combination with [`format_unescaped`](fn.format_unescaped.html) to deactivate HTML-escaping
     pub fn set_default_formatter<F>(&mut self, formatter: &'template F)
     where
@@ -204,23 +362,233 @@ impl<'template> TinyTemplate<'template> {
     where
         F: 'static + Fn(&Value, &mut String) -> Result<()>,
     {
-        self.formatters.insert(name, Box::new(formatter));
+        self.formatters
+            .insert(name, Formatter::Value(Box::new(formatter)));
+    }
+
+    /// Register the given formatter function, which takes arguments, under the given name.
+    /// `arguments` is the range of argument counts which the formatter accepts; rendering a
+    /// template which passes any other number of arguments to it will produce an error. For
+    /// example, this formatter may be used as `{ value | pad_left(5) }` or
+    /// `{ value | pad_left(5, ".") }`:
+    ///
+    /// ```
+    /// # use tinytemplate::{TinyTemplate, format_unescaped};
+    /// let mut tt = TinyTemplate::new();
+    /// tt.add_argument_formatter("pad_left", 1..=2, |value, args, output| {
+    ///     let width = args[0].as_u64().unwrap_or(0) as usize;
+    ///     let fill = args.get(1).and_then(|fill| fill.as_str()).unwrap_or(" ");
+    ///     let mut formatted = String::new();
+    ///     format_unescaped(value, &mut formatted)?;
+    ///     for _ in formatted.chars().count()..width {
+    ///         output.push_str(fill);
+    ///     }
+    ///     output.push_str(&formatted);
+    ///     Ok(())
+    /// });
+    /// tt.add_template("padded", r#"{ number | pad_left(5, ".") }"#).unwrap();
+    /// # let mut context = ::std::collections::HashMap::new();
+    /// # context.insert("number", 42);
+    /// # assert_eq!("...42", tt.render("padded", &context).unwrap());
+    /// ```
+    pub fn add_argument_formatter<F>(
+        &mut self,
+        name: &'template str,
+        arguments: RangeInclusive<usize>,
+        formatter: F,
+    ) where
+        F: 'static + Fn(&Value, &[&Value], &mut String) -> Result<()>,
+    {
+        self.formatters
+            .insert(name, Formatter::Arguments(arguments, Box::new(formatter)));
+    }
+
+    /// Register the given transformer function under the given name. Transformers share a
+    /// namespace with formatters, and can be used anywhere a formatter can. If a transformer is the
+    /// last in a chain of formatters, the value it produces is rendered using the default
+    /// formatter.
+    pub fn add_transformer<F>(&mut self, name: &'template str, transformer: F)
+    where
+        F: 'static + Fn(&Value) -> Result<Value>,
+    {
+        self.formatters
+            .insert(name, Formatter::Transformer(Box::new(transformer)));
     }
 
     /// Render the template with the given name using the given context object. The context
-    /// object must implement `serde::Serialize` as it will be converted to `serde_json::Value`.
+    /// object must implement `serde::Serialize`. Only the top-level values that the templates in
+    /// this registry name are converted to `serde_json::Value`, once per render, so the rest of the
+    /// context is never copied. Each of those values is converted in full, so a large array which a
+    /// template loops over is still copied.
     pub fn render<C>(&self, template: &str, context: &C) -> Result<String>
     where
         C: Serialize,
     {
-        let value = serde_json::to_value(context)?;
-        match self.templates.get(template) {
-            Some(tmpl) => tmpl.render(
-                &value,
+        self.get_template(template)?.render(
+            &Serialized::new(context, &self.root_names)?,
+            &self.templates,
+            &self.formatters,
+            self.default_formatter,
+        )
+    }
+
+    /// Render the template with the given name using the given `serde_json::Value` as the context.
+    /// Values are borrowed from the context as they are looked up, so nothing is copied.
+    pub fn render_value(&self, template: &str, context: &Value) -> Result<String> {
+        self.get_template(template)?.render(
+            context,
+            &self.templates,
+            &self.formatters,
+            self.default_formatter,
+        )
+    }
+
+    /// Render the template with the given name using the given context object, writing the output
+    /// into the given `io::Write` as it is rendered instead of building a string. The output is
+    /// written in chunks of several kilobytes, so there is usually no need for a `BufWriter`. An
+    /// error writing the output is returned as `Error::WriteError`.
+    pub fn render_to_writer<C, W>(&self, template: &str, context: &C, writer: W) -> Result<()>
+    where
+        C: Serialize,
+        W: io::Write,
+    {
+        self.write_to(
+            template,
+            &Serialized::new(context, &self.root_names)?,
+            writer,
+        )
+    }
+
+    /// Render the template with the given name using the given `serde_json::Value` as the context,
+    /// writing the output into the given `io::Write` like `render_to_writer`.
+    pub fn render_value_to_writer<W>(
+        &self,
+        template: &str,
+        context: &Value,
+        writer: W,
+    ) -> Result<()>
+    where
+        W: io::Write,
+    {
+        self.write_to(template, context, writer)
+    }
+
+    /// Render the template with the given name using the given context object, writing the output
+    /// into the given `fmt::Write`, such as a `fmt::Formatter`. An error writing the output is
+    /// returned as `Error::FmtWriteError`.
+    pub fn render_to_fmt<C, W>(&self, template: &str, context: &C, writer: W) -> Result<()>
+    where
+        C: Serialize,
+        W: fmt::Write,
+    {
+        self.format_to(
+            template,
+            &Serialized::new(context, &self.root_names)?,
+            writer,
+        )
+    }
+
+    /// Render the template with the given name using the given `serde_json::Value` as the context,
+    /// writing the output into the given `fmt::Write` like `render_to_fmt`.
+    pub fn render_value_to_fmt<W>(&self, template: &str, context: &Value, writer: W) -> Result<()>
+    where
+        W: fmt::Write,
+    {
+        self.format_to(template, context, writer)
+    }
+
+    /// Render the template with the given name using the given context object, and return a future
+    /// which writes the output into the given tokio `AsyncWrite` as it is rendered. Rendering
+    /// pauses at the end of each literal in the template, where the text rendered since the last
+    /// one is written into the writer, which is then flushed. Rendering carries on once the writer
+    /// has accepted all of it, so a slow writer holds up rendering instead of the output being
+    /// buffered in memory. Any error is returned by the future. Requires the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn render_async<'a, C, W>(
+        &'a self,
+        template: &'a str,
+        context: &'a C,
+        writer: &'a mut W,
+    ) -> RenderAsync<'a, W>
+    where
+        C: Serialize,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let render = self.get_template(template).and_then(|template| {
+            template.start_owned_render(
+                Box::new(Serialized::new(context, &self.root_names)?),
+                &self.templates,
+                &self.formatters,
+                self.default_formatter,
+            )
+        });
+        RenderAsync::new(writer, render)
+    }
+
+    /// Render the template with the given name using the given `serde_json::Value` as the context,
+    /// and return a future which writes the output into the given tokio `AsyncWrite` like
+    /// `render_async`. Requires the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn render_value_async<'a, W>(
+        &'a self,
+        template: &'a str,
+        context: &'a Value,
+        writer: &'a mut W,
+    ) -> RenderAsync<'a, W>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let render = self.get_template(template).and_then(|template| {
+            template.start_render(
+                context,
                 &self.templates,
                 &self.formatters,
                 self.default_formatter,
-            ),
+            )
+        });
+        RenderAsync::new(writer, render)
+    }
+
+    fn write_to<W: io::Write>(
+        &self,
+        template: &str,
+        context: &dyn Lookup,
+        writer: W,
+    ) -> Result<()> {
+        let mut output = IoOutput::new(writer);
+        self.render_to_output(template, context, &mut output)?;
+        output.finish()
+    }
+
+    fn format_to<W: fmt::Write>(
+        &self,
+        template: &str,
+        context: &dyn Lookup,
+        writer: W,
+    ) -> Result<()> {
+        let mut output = FmtOutput::new(writer);
+        self.render_to_output(template, context, &mut output)?;
+        output.finish()
+    }
+
+    fn render_to_output(
+        &self,
+        template: &str,
+        context: &dyn Lookup,
+        output: &mut dyn Output,
+    ) -> Result<()> {
+        self.get_template(template)?.render_into(
+            context,
+            &self.templates,
+            &self.formatters,
+            self.default_formatter,
+            output,
+        )
+    }
+
+    fn get_template(&self, template: &str) -> Result<&Template<'template>> {
+        match self.templates.get(template) {
+            Some(tmpl) => Ok(tmpl),
             None => Err(Error::GenericError {
                 msg: format!("Unknown template '{}'", template),
             }),
@@ -236,13 +604,27 @@ impl<'template> Default for TinyTemplate<'template> {
 #[cfg(test)]
 mod test {
     use super::*;
+    #[cfg(feature = "async")]
+    use std::cell::Cell;
+    #[cfg(feature = "async")]
+    use std::future::Future;
+    #[cfg(feature = "async")]
+    use std::pin::Pin;
+    #[cfg(feature = "async")]
+    use std::rc::Rc;
+    #[cfg(feature = "async")]
+    use std::sync::Arc;
+    #[cfg(feature = "async")]
+    use std::task::{self, Poll, Wake, Waker};
+    #[cfg(feature = "async")]
+    use tokio::io::{duplex, AsyncRead, DuplexStream, ReadBuf};
 
     #[derive(Serialize)]
     struct Context {
         name: String,
     }
 
-    static TEMPLATE: &'static str = "Hello {name}!";
+    static TEMPLATE: &str = "Hello {name}!";
 
     #[test]
     pub fn test_set_default_formatter() {
@@ -257,4 +639,390 @@ mod test {
         let rendered = tt.render("hello", &context).unwrap();
         assert_eq!(rendered, "Hello <World>!")
     }
+
+    #[test]
+    pub fn test_delimiters() {
+        let mut tt = TinyTemplate::new();
+        let delimiters = Delimiters::new(("[[", "]]"), ("[", "]"), ("[#", "#]")).unwrap();
+        tt.add_template_with_delimiters(
+            "brackets",
+            "[[ if name ]]{[name]}[[ endif ]]",
+            &delimiters,
+        )
+        .unwrap();
+        tt.add_template("hello", TEMPLATE).unwrap();
+        tt.set_delimiters(delimiters);
+        tt.add_template("later", "{ Hello [name] }").unwrap();
+
+        let context = Context {
+            name: "World".to_string(),
+        };
+
+        assert_eq!(tt.render("brackets", &context).unwrap(), "{World}");
+        assert_eq!(tt.render("hello", &context).unwrap(), "Hello World!");
+        assert_eq!(tt.render("later", &context).unwrap(), "{ Hello World }");
+    }
+
+    #[test]
+    pub fn test_invalid_delimiters() {
+        Delimiters::new(("", "}}"), ("{", "}"), ("{#", "#}")).unwrap_err();
+        Delimiters::new(("{{", "}}"), ("{", ""), ("{#", "#}")).unwrap_err();
+        Delimiters::new(("{{", "}}"), ("{{", "}"), ("{#", "#}")).unwrap_err();
+        Delimiters::new(("{{", "}}"), ("{", "}"), ("{", "#}")).unwrap_err();
+        Delimiters::new(("{{", "}}"), ("<\n", "\n>"), ("{#", "#}")).unwrap_err();
+        Delimiters::new(("{{ ", "}}"), ("{", "}"), ("{#", "#}")).unwrap_err();
+        Delimiters::new(("{{", "}}"), ("{", "}"), ("{#", "\t#}")).unwrap_err();
+    }
+
+    /// Writer which records each write separately, and fails once it has been given too much.
+    struct ChunkWriter {
+        chunks: Vec<Vec<u8>>,
+        limit: usize,
+    }
+    impl io::Write for ChunkWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let written: usize = self.chunks.iter().map(|chunk| chunk.len()).sum();
+            if written + buf.len() > self.limit {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.chunks.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize)]
+    struct RowsContext {
+        rows: Vec<String>,
+    }
+
+    fn rows_template() -> TinyTemplate<'static> {
+        let mut tt = TinyTemplate::new();
+        tt.add_template("row", "<td>{ @root }</td>").unwrap();
+        tt.add_template(
+            "rows",
+            "<table>{{ for row in rows }}<tr>{{ call row with row }}</tr>\n{{ endfor }}</table>",
+        )
+        .unwrap();
+        tt
+    }
+
+    #[test]
+    pub fn test_render_to_writer() {
+        let tt = rows_template();
+        let context = RowsContext {
+            rows: (0..1000).map(|i| format!("<{}>", i)).collect(),
+        };
+        let expected = tt.render("rows", &context).unwrap();
+
+        let mut writer = ChunkWriter {
+            chunks: vec![],
+            limit: 1 << 20,
+        };
+        tt.render_to_writer("rows", &context, &mut writer).unwrap();
+        assert!(writer.chunks.len() > 1);
+        assert_eq!(expected.as_bytes(), &writer.chunks.concat()[..]);
+
+        let mut bytes = vec![];
+        tt.render_to_writer("hello", &context, &mut bytes)
+            .unwrap_err();
+    }
+
+    #[test]
+    pub fn test_render_to_writer_error() {
+        let tt = rows_template();
+        let context = RowsContext {
+            rows: (0..1000).map(|i| i.to_string()).collect(),
+        };
+        let mut writer = ChunkWriter {
+            chunks: vec![],
+            limit: 10000,
+        };
+        match tt.render_to_writer("rows", &context, &mut writer) {
+            Err(Error::WriteError { .. }) => {}
+            other => panic!("Expected a write error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_render_to_writer_without_literals() {
+        let mut tt = TinyTemplate::new();
+        tt.add_template("rows", "{{ for row in rows }}{ row }{{ endfor }}")
+            .unwrap();
+        let context = RowsContext {
+            rows: (0..10000).map(|i| i.to_string()).collect(),
+        };
+        let mut writer = ChunkWriter {
+            chunks: vec![],
+            limit: 1 << 20,
+        };
+        tt.render_to_writer("rows", &context, &mut writer).unwrap();
+        assert!(writer.chunks.len() > 1);
+        assert_eq!(
+            tt.render("rows", &context).unwrap().as_bytes(),
+            &writer.chunks.concat()[..]
+        );
+    }
+
+    #[test]
+    pub fn test_render_to_fmt_error() {
+        struct FailingWriter;
+        impl fmt::Write for FailingWriter {
+            fn write_str(&mut self, _s: &str) -> fmt::Result {
+                Err(fmt::Error)
+            }
+        }
+
+        let tt = rows_template();
+        let context = RowsContext {
+            rows: vec!["a".to_string()],
+        };
+        match tt.render_to_fmt("rows", &context, FailingWriter) {
+            Err(Error::FmtWriteError { .. }) => {}
+            other => panic!("Expected a write error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_render_looks_up_lazily() {
+        #[derive(Serialize)]
+        struct Context {
+            name: &'static str,
+            // Maps with keys like these cannot be converted to JSON.
+            invalid: HashMap<(u32, u32), u32>,
+        }
+
+        let mut tt = TinyTemplate::new();
+        tt.add_template("name", "Hello { name }!").unwrap();
+        tt.add_template("invalid", "{ invalid }").unwrap();
+        let context = Context {
+            name: "World",
+            invalid: vec![((1, 2), 3)].into_iter().collect(),
+        };
+        assert_eq!("Hello World!", tt.render("name", &context).unwrap());
+        match tt.render("invalid", &context) {
+            Err(Error::SerdeError { .. }) => {}
+            other => panic!("Expected a serde error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_render_value() {
+        let tt = rows_template();
+        let context = RowsContext {
+            rows: (0..1000).map(|i| format!("<{}>", i)).collect(),
+        };
+        let value = serde_json::to_value(&context).unwrap();
+        let expected = tt.render("rows", &context).unwrap();
+        assert_eq!(expected, tt.render_value("rows", &value).unwrap());
+
+        let mut bytes = vec![];
+        tt.render_value_to_writer("rows", &value, &mut bytes)
+            .unwrap();
+        assert_eq!(expected.as_bytes(), &bytes[..]);
+
+        let mut string = String::new();
+        tt.render_value_to_fmt("rows", &value, &mut string).unwrap();
+        assert_eq!(expected, string);
+
+        tt.render_value("hello", &value).unwrap_err();
+    }
+
+    #[test]
+    pub fn test_render_to_fmt() {
+        let tt = rows_template();
+        let context = RowsContext {
+            rows: vec!["a".to_string(), "b".to_string()],
+        };
+        let mut output = String::from("Rows: ");
+        tt.render_to_fmt("rows", &context, &mut output).unwrap();
+        assert_eq!(
+            "Rows: <table><tr><td>a</td></tr>\n<tr><td>b</td></tr>\n</table>",
+            output
+        );
+    }
+
+    /// Waker for futures which are polled in a loop rather than when they are woken.
+    #[cfg(feature = "async")]
+    struct NoopWaker;
+    #[cfg(feature = "async")]
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Poll the given future until it is ready, calling the given function after each poll.
+    #[cfg(feature = "async")]
+    fn poll_until_ready<F, A>(future: F, mut after_poll: A) -> F::Output
+    where
+        F: Future,
+        A: FnMut(&mut task::Context),
+    {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = task::Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            let polled = future.as_mut().poll(&mut cx);
+            after_poll(&mut cx);
+            if let Poll::Ready(output) = polled {
+                return output;
+            }
+        }
+    }
+
+    /// Poll the given future, which writes into one end of a pipe, and read whatever it has written
+    /// from the other end each time it stops. The pipes in these tests only hold a few bytes, so
+    /// the future keeps waiting for them to be emptied. The number of bytes read so far is kept in
+    /// `received`. Returns the output of the future and all of the bytes read.
+    #[cfg(feature = "async")]
+    fn read_while_polling<F: Future>(
+        future: F,
+        reader: &mut DuplexStream,
+        received: &Cell<usize>,
+    ) -> (F::Output, Vec<u8>) {
+        let mut bytes = vec![];
+        let output = poll_until_ready(future, |cx| {
+            loop {
+                let mut chunk = [0; 100];
+                let mut buf = ReadBuf::new(&mut chunk);
+                match Pin::new(&mut *reader).poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(())) if !buf.filled().is_empty() => {
+                        bytes.extend_from_slice(buf.filled())
+                    }
+                    _ => break,
+                }
+            }
+            received.set(bytes.len());
+        });
+        (output, bytes)
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    pub fn test_render_async() {
+        let tt = rows_template();
+        let context = RowsContext {
+            rows: (0..1000).map(|i| format!("<{}>", i)).collect(),
+        };
+        let expected = tt.render("rows", &context).unwrap();
+
+        let (mut writer, mut reader) = duplex(64);
+        let (result, bytes) = read_while_polling(
+            tt.render_async("rows", &context, &mut writer),
+            &mut reader,
+            &Cell::new(0),
+        );
+        result.unwrap();
+        assert_eq!(expected.as_bytes(), &bytes[..]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    pub fn test_render_async_writes_while_rendering() {
+        let received = Rc::new(Cell::new(0));
+        let received_before_end = received.clone();
+        let mut tt = rows_template();
+        tt.add_template("report", "{{ call rows with @root }}|{ @root | received }")
+            .unwrap();
+        tt.add_formatter("received", move |_, output| {
+            write!(output, "{}", received_before_end.get())?;
+            Ok(())
+        });
+        let context = RowsContext {
+            rows: (0..1000).map(|i| format!("<{}>", i)).collect(),
+        };
+        let table = tt.render("rows", &context).unwrap();
+
+        // By the time the formatter at the end of the template runs, most of the table has already
+        // gone through the pipe, which can only hold a few bytes of it at a time.
+        let (mut writer, mut reader) = duplex(64);
+        let (result, bytes) = read_while_polling(
+            tt.render_async("report", &context, &mut writer),
+            &mut reader,
+            &received,
+        );
+        result.unwrap();
+        let rendered = ::std::str::from_utf8(&bytes).unwrap();
+        assert!(rendered.starts_with(&format!("{}|", table)));
+        let received_before_end: usize = rendered[(table.len() + 1)..].parse().unwrap();
+        assert!(received_before_end > table.len() - 64);
+        assert!(received_before_end < table.len());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    pub fn test_render_value_async() {
+        let tt = rows_template();
+        let context = RowsContext {
+            rows: vec!["a".to_string(), "b".to_string()],
+        };
+        let value = serde_json::to_value(&context).unwrap();
+        let (mut writer, mut reader) = duplex(4);
+        let (result, bytes) = read_while_polling(
+            tt.render_value_async("rows", &value, &mut writer),
+            &mut reader,
+            &Cell::new(0),
+        );
+        result.unwrap();
+        assert_eq!(tt.render("rows", &context).unwrap().as_bytes(), &bytes[..]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    pub fn test_render_async_errors() {
+        let mut tt = rows_template();
+        tt.add_template("missing", "{{ call rows with @root }}{ missing }")
+            .unwrap();
+        let context = RowsContext {
+            rows: (0..1000).map(|i| i.to_string()).collect(),
+        };
+        let (mut writer, mut reader) = duplex(64);
+        let (result, bytes) = read_while_polling(
+            tt.render_async("hello", &context, &mut writer),
+            &mut reader,
+            &Cell::new(0),
+        );
+        result.unwrap_err();
+        assert!(bytes.is_empty());
+
+        // Everything up to the last literal before the error has already been written.
+        let (result, bytes) = read_while_polling(
+            tt.render_async("missing", &context, &mut writer),
+            &mut reader,
+            &Cell::new(0),
+        );
+        result.unwrap_err();
+        assert_eq!(tt.render("rows", &context).unwrap().as_bytes(), &bytes[..]);
+
+        // Nobody is reading from the other end of the pipe any more.
+        drop(reader);
+        match poll_until_ready(tt.render_async("rows", &context, &mut writer), |_| {}) {
+            Err(Error::WriteError { .. }) => {}
+            other => panic!("Expected a write error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_add_transformer() {
+        let mut tt = TinyTemplate::new();
+        tt.add_template("hello", "Hello { name | trim | upper }!")
+            .unwrap();
+        tt.add_transformer("trim", |value| {
+            Ok(Value::String(
+                value.as_str().unwrap_or("").trim().to_string(),
+            ))
+        });
+        tt.add_transformer("upper", |value| {
+            Ok(Value::String(value.as_str().unwrap_or("").to_uppercase()))
+        });
+
+        let context = Context {
+            name: "  <World>  ".to_string(),
+        };
+
+        let rendered = tt.render("hello", &context).unwrap();
+        assert_eq!(rendered, "Hello &lt;WORLD&gt;!")
+    }
 }
@@ -0,0 +1,1255 @@
+//! This module implements the lookup of paths in the data that templates are rendered with. The
+//! interpreter walks paths through the `Lookup` trait, so that only the parts of the context data
+//! which templates use have to be converted into a `serde_json::Value`.
+
+use error::Result;
+use instruction::{Operand, PathSlice, PathStep};
+use serde::ser::{self, Impossible, Serialize};
+use serde_json::{Error as SerdeJsonError, Map, Value};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+/// Result type of the serializers which search through serialized data.
+type SerResult<T> = ::std::result::Result<T, SerdeJsonError>;
+
+/// The result of looking up a path.
+pub(crate) enum Found<'a> {
+    /// The value at the end of the path, which is borrowed if the data already holds it as a
+    /// `Value`.
+    Value(Cow<'a, Value>),
+    /// The index of the first step of the path that could not be found, along with the value that
+    /// it was looked up in.
+    Missing { step: usize, level: Cow<'a, Value> },
+}
+
+/// Data which paths can be looked up in.
+pub(crate) trait Lookup {
+    /// Look up the given path in this data. An empty path produces all of it.
+    fn lookup<'a>(&'a self, path: PathSlice) -> Result<Found<'a>>;
+}
+
+impl Lookup for Value {
+    fn lookup<'a>(&'a self, path: PathSlice) -> Result<Found<'a>> {
+        let mut current = self;
+        for (i, step) in path.iter().enumerate() {
+            if let PathStep::Index(_, n) = step {
+                if let Some(next) = current.get(n) {
+                    current = next;
+                    continue;
+                }
+            }
+
+            let step: &str = step;
+
+            match current.get(step) {
+                Some(next) => current = next,
+                None => {
+                    return Ok(Found::Missing {
+                        step: i,
+                        level: Cow::Borrowed(current),
+                    })
+                }
+            }
+        }
+        Ok(Found::Value(Cow::Borrowed(current)))
+    }
+}
+
+impl<T: Lookup + ?Sized> Lookup for &T {
+    fn lookup<'a>(&'a self, path: PathSlice) -> Result<Found<'a>> {
+        (**self).lookup(path)
+    }
+}
+
+/// The top-level names which templates look up in the context they are rendered with, collected
+/// from the paths in their instructions.
+#[derive(Default)]
+pub(crate) struct RootNames<'template> {
+    /// Set if a template looks at the whole context, such as with `@root`.
+    all: bool,
+    names: HashSet<&'template str>,
+}
+impl<'template> RootNames<'template> {
+    /// Add the top-level name which the given path starts with. Paths starting with a loop keyword
+    /// don't look into the context at all.
+    pub fn add_path(&mut self, path: PathSlice<'_, 'template>) {
+        let step = match path.first() {
+            Some(PathStep::Name("@root")) => path.get(1),
+            first => first,
+        };
+        match step {
+            Some(PathStep::Name(name)) | Some(PathStep::Index(name, _)) => {
+                if !name.starts_with('@') {
+                    self.names.insert(name);
+                }
+            }
+            None => self.all = true,
+        }
+    }
+
+    /// Add the top-level name which the given operand looks up, if it is a path.
+    pub fn add_operand(&mut self, operand: &Operand<'template>) {
+        if let Operand::Path(path) = operand {
+            self.add_path(path);
+        }
+    }
+}
+
+/// Lookup for any serializable data. When rendering starts, the top-level values which templates
+/// name are converted into `Value`s, and lookups borrow from them. Other values are skipped without
+/// being copied. A value which a template names is converted in full, even if the template only
+/// uses a small part of it, so a table which a template loops over is still copied once per render.
+/// If a template looks at the whole data, or the data is not an object, all of it is converted.
+pub(crate) struct Serialized<'c, C: ?Sized + 'c> {
+    data: &'c C,
+    converted: Converted,
+}
+impl<'c, C: Serialize + ?Sized> Serialized<'c, C> {
+    pub fn new(data: &'c C, names: &RootNames) -> Result<Serialized<'c, C>> {
+        let fields = if names.all {
+            None
+        } else {
+            data.serialize(FieldCollector { names })?
+        };
+        let converted = match fields {
+            Some(fields) => Converted::Fields(fields),
+            None => Converted::Whole(serde_json::to_value(data)?),
+        };
+        Ok(Serialized { data, converted })
+    }
+
+    /// Look up a path whose first step names a value which was not converted up front, because
+    /// no template names it or it could not be converted. The value is converted again for each
+    /// lookup, which also reports the error if it cannot be converted.
+    fn lookup_unconverted(&self, path: PathSlice) -> Result<Found<'static>> {
+        let finder = PathFinder {
+            path: &path[..1],
+            depth: 0,
+        };
+        Ok(match finder.find_in(self.data)? {
+            Finding::Found(value) => match value.lookup(&path[1..])? {
+                Found::Value(found) => Found::Value(Cow::Owned(found.into_owned())),
+                Found::Missing { step, level } => Found::Missing {
+                    step: step + 1,
+                    level: Cow::Owned(level.into_owned()),
+                },
+            },
+            Finding::Missing => Found::Missing {
+                step: 0,
+                level: Cow::Owned(Value::Null),
+            },
+        })
+    }
+}
+
+impl<'c, C: Serialize + ?Sized> Lookup for Serialized<'c, C> {
+    fn lookup<'a>(&'a self, path: PathSlice) -> Result<Found<'a>> {
+        let fields = match &self.converted {
+            Converted::Whole(value) => return value.lookup(path),
+            Converted::Fields(fields) => fields,
+        };
+        let first: &str = match path.first() {
+            Some(first) => first,
+            // Templates which look at the whole data have it converted up front.
+            None => return Ok(Found::Value(Cow::Owned(serde_json::to_value(self.data)?))),
+        };
+        if let Some(value) = fields.values.get(first) {
+            return Ok(match value.lookup(&path[1..])? {
+                Found::Missing { step, level } => Found::Missing {
+                    step: step + 1,
+                    level,
+                },
+                found => found,
+            });
+        }
+        if fields.keys.iter().any(|key| key == first) {
+            return self.lookup_unconverted(path);
+        }
+        // Errors only list the keys of the level where the lookup failed, so the values which
+        // were never converted are left out.
+        let level = fields
+            .keys
+            .iter()
+            .map(|key| (key.to_string(), Value::Null))
+            .collect();
+        Ok(Found::Missing {
+            step: 0,
+            level: Cow::Owned(Value::Object(level)),
+        })
+    }
+}
+
+/// The data converted when rendering starts.
+enum Converted {
+    Whole(Value),
+    Fields(Fields),
+}
+
+/// The converted values of an object which templates name, along with the keys of all of its
+/// values.
+struct Fields {
+    values: HashMap<String, Value>,
+    keys: Vec<Cow<'static, str>>,
+}
+
+/// The result of searching through serialized data for a path.
+enum Finding {
+    Found(Value),
+    Missing,
+}
+
+/// Serializer which searches for the value at the end of a path, starting at the given depth
+/// into the path.
+#[derive(Clone, Copy)]
+struct PathFinder<'p, 't: 'p> {
+    path: PathSlice<'p, 't>,
+    depth: usize,
+}
+impl<'p, 't> PathFinder<'p, 't> {
+    /// Search the given value for the rest of the path, or convert it if the path ends here.
+    fn find_in<T: Serialize + ?Sized>(self, value: &T) -> SerResult<Finding> {
+        if self.depth == self.path.len() {
+            serde_json::to_value(value).map(Finding::Found)
+        } else {
+            value.serialize(self)
+        }
+    }
+
+    fn next(self) -> PathFinder<'p, 't> {
+        PathFinder {
+            path: self.path,
+            depth: self.depth + 1,
+        }
+    }
+
+    fn missing(self) -> SerResult<Finding> {
+        Ok(Finding::Missing)
+    }
+
+    /// Check whether the current step names the given object key.
+    fn is_key(self, key: &str) -> bool {
+        &*self.path[self.depth] == key
+    }
+
+    /// Check whether the current step is the given array index.
+    fn is_index(self, index: usize) -> bool {
+        match self.path[self.depth] {
+            PathStep::Index(_, n) => n == index,
+            PathStep::Name(_) => false,
+        }
+    }
+
+    /// Search the elements or fields of an enum variant, which serde_json represents as an object
+    /// with the variant name as its only key.
+    fn variant(self, variant: &str, collect: Collect) -> Compound<'p, 't> {
+        if !self.is_key(variant) {
+            Compound::Missing
+        } else if self.depth + 1 == self.path.len() {
+            match collect {
+                Collect::Array => Compound::Array(vec![]),
+                Collect::Object => Compound::Object(Map::new()),
+            }
+        } else {
+            Compound::search(self.next())
+        }
+    }
+}
+
+/// What to collect from an enum variant, if the path ends at it.
+enum Collect {
+    Array,
+    Object,
+}
+
+macro_rules! missing_scalars {
+    ($($method:ident($ty:ty)),*) => {
+        $(
+            fn $method(self, _value: $ty) -> SerResult<Finding> {
+                self.missing()
+            }
+        )*
+    };
+}
+
+impl<'p, 't> ser::Serializer for PathFinder<'p, 't> {
+    type Ok = Finding;
+    type Error = SerdeJsonError;
+    type SerializeSeq = Compound<'p, 't>;
+    type SerializeTuple = Compound<'p, 't>;
+    type SerializeTupleStruct = Compound<'p, 't>;
+    type SerializeTupleVariant = Compound<'p, 't>;
+    type SerializeMap = Compound<'p, 't>;
+    type SerializeStruct = Compound<'p, 't>;
+    type SerializeStructVariant = Compound<'p, 't>;
+
+    missing_scalars!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str)
+    );
+
+    fn serialize_bytes(self, value: &[u8]) -> SerResult<Finding> {
+        // serde_json represents bytes as an array of numbers.
+        for (index, byte) in value.iter().enumerate() {
+            if self.is_index(index) {
+                return self.next().find_in(byte);
+            }
+        }
+        self.missing()
+    }
+
+    fn serialize_none(self) -> SerResult<Finding> {
+        self.missing()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> SerResult<Finding> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> SerResult<Finding> {
+        self.missing()
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> SerResult<Finding> {
+        self.missing()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+    ) -> SerResult<Finding> {
+        self.missing()
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> SerResult<Finding> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> SerResult<Finding> {
+        if self.is_key(variant) {
+            self.next().find_in(value)
+        } else {
+            self.missing()
+        }
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> SerResult<Compound<'p, 't>> {
+        Ok(Compound::search(self))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> SerResult<Compound<'p, 't>> {
+        Ok(Compound::search(self))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> SerResult<Compound<'p, 't>> {
+        Ok(Compound::search(self))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> SerResult<Compound<'p, 't>> {
+        Ok(self.variant(variant, Collect::Array))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> SerResult<Compound<'p, 't>> {
+        Ok(Compound::search(self))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> SerResult<Compound<'p, 't>> {
+        Ok(Compound::search(self))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> SerResult<Compound<'p, 't>> {
+        Ok(self.variant(variant, Collect::Object))
+    }
+}
+
+/// State of the search through the elements of an array or the entries of an object.
+enum Compound<'p, 't: 'p> {
+    /// Search for the current step of the path. For arrays, this counts the elements, and for maps
+    /// it remembers whether the last key matched.
+    Search {
+        finder: PathFinder<'p, 't>,
+        index: usize,
+        key_matched: bool,
+        found: Option<Finding>,
+    },
+    /// The path ends at this enum variant, so all of its elements are collected.
+    Array(Vec<Value>),
+    /// The path ends at this enum variant, so all of its fields are collected.
+    Object(Map<String, Value>),
+    /// The path names a different enum variant, so the step at this depth is missing.
+    Missing,
+}
+impl<'p, 't> Compound<'p, 't> {
+    fn search(finder: PathFinder<'p, 't>) -> Compound<'p, 't> {
+        Compound::Search {
+            finder,
+            index: 0,
+            key_matched: false,
+            found: None,
+        }
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult<()> {
+        match self {
+            Compound::Search {
+                finder,
+                index,
+                found,
+                ..
+            } => {
+                if finder.is_index(*index) {
+                    *found = Some(finder.next().find_in(value)?);
+                }
+                *index += 1;
+            }
+            Compound::Array(values) => values.push(serde_json::to_value(value)?),
+            Compound::Object(_) | Compound::Missing => {}
+        }
+        Ok(())
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> SerResult<()> {
+        match self {
+            Compound::Search { finder, found, .. } => {
+                if finder.is_key(key) {
+                    *found = Some(finder.next().find_in(value)?);
+                }
+            }
+            Compound::Object(map) => {
+                map.insert(key.to_string(), serde_json::to_value(value)?);
+            }
+            Compound::Array(_) | Compound::Missing => {}
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> SerResult<Finding> {
+        Ok(match self {
+            Compound::Search { found, .. } => found.unwrap_or(Finding::Missing),
+            Compound::Array(values) => Finding::Found(Value::Array(values)),
+            Compound::Object(map) => Finding::Found(Value::Object(map)),
+            Compound::Missing => Finding::Missing,
+        })
+    }
+}
+
+impl<'p, 't> ser::SerializeSeq for Compound<'p, 't> {
+    type Ok = Finding;
+    type Error = SerdeJsonError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> SerResult<Finding> {
+        self.finish()
+    }
+}
+
+impl<'p, 't> ser::SerializeTuple for Compound<'p, 't> {
+    type Ok = Finding;
+    type Error = SerdeJsonError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> SerResult<Finding> {
+        self.finish()
+    }
+}
+
+impl<'p, 't> ser::SerializeTupleStruct for Compound<'p, 't> {
+    type Ok = Finding;
+    type Error = SerdeJsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> SerResult<Finding> {
+        self.finish()
+    }
+}
+
+impl<'p, 't> ser::SerializeTupleVariant for Compound<'p, 't> {
+    type Ok = Finding;
+    type Error = SerdeJsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> SerResult<Finding> {
+        self.finish()
+    }
+}
+
+impl<'p, 't> ser::SerializeMap for Compound<'p, 't> {
+    type Ok = Finding;
+    type Error = SerdeJsonError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> SerResult<()> {
+        if let Compound::Search {
+            finder,
+            key_matched,
+            ..
+        } = self
+        {
+            *key_matched = key.serialize(KeyMatcher(&finder.path[finder.depth]))?;
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult<()> {
+        if let Compound::Search {
+            finder,
+            key_matched: true,
+            found,
+            ..
+        } = self
+        {
+            *found = Some(finder.next().find_in(value)?);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> SerResult<Finding> {
+        self.finish()
+    }
+}
+
+impl<'p, 't> ser::SerializeStruct for Compound<'p, 't> {
+    type Ok = Finding;
+    type Error = SerdeJsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> SerResult<()> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> SerResult<Finding> {
+        self.finish()
+    }
+}
+
+impl<'p, 't> ser::SerializeStructVariant for Compound<'p, 't> {
+    type Ok = Finding;
+    type Error = SerdeJsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> SerResult<()> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> SerResult<Finding> {
+        self.finish()
+    }
+}
+
+/// Serializer which checks whether a map key, converted to a string the way serde_json would,
+/// is equal to the given step.
+struct KeyMatcher<'s>(&'s str);
+
+fn key_must_be_a_string() -> SerdeJsonError {
+    ser::Error::custom("key must be a string")
+}
+
+macro_rules! match_displayed_keys {
+    ($($method:ident($ty:ty)),*) => {
+        $(
+            fn $method(self, value: $ty) -> SerResult<bool> {
+                Ok(value.to_string() == self.0)
+            }
+        )*
+    };
+}
+
+macro_rules! reject_keys {
+    ($($method:ident($($ty:ty),*) -> $ret:ty),*) => {
+        $(
+            fn $method(self, $(_: $ty),*) -> SerResult<$ret> {
+                Err(key_must_be_a_string())
+            }
+        )*
+    };
+}
+
+impl<'s> ser::Serializer for KeyMatcher<'s> {
+    type Ok = bool;
+    type Error = SerdeJsonError;
+    type SerializeSeq = Impossible<bool, SerdeJsonError>;
+    type SerializeTuple = Impossible<bool, SerdeJsonError>;
+    type SerializeTupleStruct = Impossible<bool, SerdeJsonError>;
+    type SerializeTupleVariant = Impossible<bool, SerdeJsonError>;
+    type SerializeMap = Impossible<bool, SerdeJsonError>;
+    type SerializeStruct = Impossible<bool, SerdeJsonError>;
+    type SerializeStructVariant = Impossible<bool, SerdeJsonError>;
+
+    match_displayed_keys!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128)
+    );
+
+    reject_keys!(
+        serialize_f32(f32) -> bool,
+        serialize_f64(f64) -> bool,
+        serialize_bytes(&[u8]) -> bool,
+        serialize_none() -> bool,
+        serialize_unit() -> bool,
+        serialize_unit_struct(&'static str) -> bool,
+        serialize_seq(Option<usize>) -> Self::SerializeSeq,
+        serialize_tuple(usize) -> Self::SerializeTuple,
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct,
+        serialize_tuple_variant(&'static str, u32, &'static str, usize)
+            -> Self::SerializeTupleVariant,
+        serialize_map(Option<usize>) -> Self::SerializeMap,
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct,
+        serialize_struct_variant(&'static str, u32, &'static str, usize)
+            -> Self::SerializeStructVariant
+    );
+
+    fn serialize_char(self, value: char) -> SerResult<bool> {
+        let mut buffer = [0; 4];
+        Ok(value.encode_utf8(&mut buffer) == self.0)
+    }
+
+    fn serialize_str(self, value: &str) -> SerResult<bool> {
+        Ok(value == self.0)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> SerResult<bool> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> SerResult<bool> {
+        Ok(variant == self.0)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> SerResult<bool> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> SerResult<bool> {
+        Err(key_must_be_a_string())
+    }
+}
+
+/// Serializer which converts the values of an object that templates name, and collects the keys of
+/// all of its values. Produces None if the data is not an object.
+#[derive(Clone, Copy)]
+struct FieldCollector<'n, 't: 'n> {
+    names: &'n RootNames<'t>,
+}
+
+macro_rules! not_an_object {
+    ($($method:ident($($ty:ty),*)),*) => {
+        $(
+            fn $method(self, $(_: $ty),*) -> SerResult<Option<Fields>> {
+                Ok(None)
+            }
+        )*
+    };
+}
+
+impl<'n, 't> ser::Serializer for FieldCollector<'n, 't> {
+    type Ok = Option<Fields>;
+    type Error = SerdeJsonError;
+    type SerializeSeq = FieldsCompound<'n, 't>;
+    type SerializeTuple = FieldsCompound<'n, 't>;
+    type SerializeTupleStruct = FieldsCompound<'n, 't>;
+    type SerializeTupleVariant = FieldsCompound<'n, 't>;
+    type SerializeMap = FieldsCompound<'n, 't>;
+    type SerializeStruct = FieldsCompound<'n, 't>;
+    type SerializeStructVariant = FieldsCompound<'n, 't>;
+
+    not_an_object!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+        serialize_none(),
+        serialize_unit(),
+        serialize_unit_struct(&'static str),
+        serialize_unit_variant(&'static str, u32, &'static str)
+    );
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> SerResult<Option<Fields>> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> SerResult<Option<Fields>> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> SerResult<Option<Fields>> {
+        Ok(None)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> SerResult<FieldsCompound<'n, 't>> {
+        Ok(FieldsCompound::Other)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> SerResult<FieldsCompound<'n, 't>> {
+        Ok(FieldsCompound::Other)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> SerResult<FieldsCompound<'n, 't>> {
+        Ok(FieldsCompound::Other)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> SerResult<FieldsCompound<'n, 't>> {
+        Ok(FieldsCompound::Other)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> SerResult<FieldsCompound<'n, 't>> {
+        Ok(FieldsCompound::object(self.names))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> SerResult<FieldsCompound<'n, 't>> {
+        Ok(FieldsCompound::object(self.names))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> SerResult<FieldsCompound<'n, 't>> {
+        Ok(FieldsCompound::Other)
+    }
+}
+
+/// State of the FieldCollector while it goes through the entries of an object, or skips over the
+/// elements of something else.
+enum FieldsCompound<'n, 't: 'n> {
+    Object {
+        names: &'n RootNames<'t>,
+        fields: Fields,
+        /// The key of a map entry whose value comes next.
+        key: Option<String>,
+    },
+    Other,
+}
+impl<'n, 't> FieldsCompound<'n, 't> {
+    fn object(names: &'n RootNames<'t>) -> FieldsCompound<'n, 't> {
+        FieldsCompound::Object {
+            names,
+            fields: Fields {
+                values: HashMap::new(),
+                keys: vec![],
+            },
+            key: None,
+        }
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: Cow<'static, str>, value: &T) {
+        if let FieldsCompound::Object { names, fields, .. } = self {
+            if names.names.contains(&*key) {
+                // A value which cannot be converted is left out, so that the error is only
+                // reported if it is looked up.
+                if let Ok(value) = serde_json::to_value(value) {
+                    fields.values.insert(key.to_string(), value);
+                }
+            }
+            fields.keys.push(key);
+        }
+    }
+
+    fn finish(self) -> SerResult<Option<Fields>> {
+        Ok(match self {
+            FieldsCompound::Object { mut fields, .. } => {
+                // Keep the order in which a converted object would list the keys.
+                fields.keys.sort();
+                Some(fields)
+            }
+            FieldsCompound::Other => None,
+        })
+    }
+}
+
+impl<'n, 't> ser::SerializeSeq for FieldsCompound<'n, 't> {
+    type Ok = Option<Fields>;
+    type Error = SerdeJsonError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, _value: &T) -> SerResult<()> {
+        Ok(())
+    }
+
+    fn end(self) -> SerResult<Option<Fields>> {
+        self.finish()
+    }
+}
+
+impl<'n, 't> ser::SerializeTuple for FieldsCompound<'n, 't> {
+    type Ok = Option<Fields>;
+    type Error = SerdeJsonError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, _value: &T) -> SerResult<()> {
+        Ok(())
+    }
+
+    fn end(self) -> SerResult<Option<Fields>> {
+        self.finish()
+    }
+}
+
+impl<'n, 't> ser::SerializeTupleStruct for FieldsCompound<'n, 't> {
+    type Ok = Option<Fields>;
+    type Error = SerdeJsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _value: &T) -> SerResult<()> {
+        Ok(())
+    }
+
+    fn end(self) -> SerResult<Option<Fields>> {
+        self.finish()
+    }
+}
+
+impl<'n, 't> ser::SerializeTupleVariant for FieldsCompound<'n, 't> {
+    type Ok = Option<Fields>;
+    type Error = SerdeJsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _value: &T) -> SerResult<()> {
+        Ok(())
+    }
+
+    fn end(self) -> SerResult<Option<Fields>> {
+        self.finish()
+    }
+}
+
+impl<'n, 't> ser::SerializeMap for FieldsCompound<'n, 't> {
+    type Ok = Option<Fields>;
+    type Error = SerdeJsonError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> SerResult<()> {
+        if let FieldsCompound::Object { key: next_key, .. } = self {
+            // Convert the key to a string the way serde_json does for the keys of objects.
+            *next_key = Some(match serde_json::to_value(key)? {
+                Value::String(key) => key,
+                Value::Number(key) => key.to_string(),
+                Value::Bool(key) => key.to_string(),
+                _ => return Err(key_must_be_a_string()),
+            });
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult<()> {
+        let key = match self {
+            FieldsCompound::Object { key, .. } => key.take(),
+            FieldsCompound::Other => None,
+        };
+        if let Some(key) = key {
+            self.field(Cow::Owned(key), value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> SerResult<Option<Fields>> {
+        self.finish()
+    }
+}
+
+impl<'n, 't> ser::SerializeStruct for FieldsCompound<'n, 't> {
+    type Ok = Option<Fields>;
+    type Error = SerdeJsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> SerResult<()> {
+        self.field(Cow::Borrowed(key), value);
+        Ok(())
+    }
+
+    fn end(self) -> SerResult<Option<Fields>> {
+        self.finish()
+    }
+}
+
+impl<'n, 't> ser::SerializeStructVariant for FieldsCompound<'n, 't> {
+    type Ok = Option<Fields>;
+    type Error = SerdeJsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        _value: &T,
+    ) -> SerResult<()> {
+        Ok(())
+    }
+
+    fn end(self) -> SerResult<Option<Fields>> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize)]
+    struct Wrapper(Vec<u32>);
+
+    #[derive(Serialize)]
+    struct Pair(&'static str, u32);
+
+    #[derive(Serialize)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Line(u32, u32),
+        Rect { width: u32, height: u32 },
+    }
+
+    #[derive(Serialize)]
+    struct Inner {
+        name: &'static str,
+        tags: Vec<&'static str>,
+        missing: Option<u32>,
+        present: Option<u32>,
+    }
+
+    #[derive(Serialize)]
+    struct Context {
+        inner: Inner,
+        wrapper: Wrapper,
+        pair: Pair,
+        shapes: Vec<Shape>,
+        numbers: BTreeMap<u32, &'static str>,
+        names: BTreeMap<&'static str, Inner>,
+        unit: (),
+    }
+
+    fn inner(name: &'static str) -> Inner {
+        Inner {
+            name,
+            tags: vec!["a", "b"],
+            missing: None,
+            present: Some(3),
+        }
+    }
+
+    fn context() -> Context {
+        Context {
+            inner: inner("inner"),
+            wrapper: Wrapper(vec![4, 5, 6]),
+            pair: Pair("left", 7),
+            shapes: vec![
+                Shape::Point,
+                Shape::Circle(1.5),
+                Shape::Line(1, 2),
+                Shape::Rect {
+                    width: 3,
+                    height: 4,
+                },
+            ],
+            numbers: vec![(1, "one"), (20, "twenty")].into_iter().collect(),
+            names: vec![("x", inner("x")), ("y", inner("y"))]
+                .into_iter()
+                .collect(),
+            unit: (),
+        }
+    }
+
+    fn path(text: &'static str) -> Vec<PathStep<'static>> {
+        if text.is_empty() {
+            return vec![];
+        }
+        text.split('.')
+            .map(|step| match step.parse() {
+                Ok(n) => PathStep::Index(step, n),
+                Err(_) => PathStep::Name(step),
+            })
+            .collect()
+    }
+
+    fn names(paths: &[&'static str]) -> RootNames<'static> {
+        let mut names = RootNames::default();
+        for text in paths {
+            names.add_path(&path(text));
+        }
+        names
+    }
+
+    /// The keys of the level where a lookup failed, which are all that errors show of it.
+    fn keys(level: &Value) -> Vec<&String> {
+        match level {
+            Value::Object(map) => map.keys().collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Check that looking the path up in the serialized context finds the same thing as looking it
+    /// up in the converted context, both when a template names the path and when none does.
+    fn check(text: &'static str) {
+        let context = context();
+        let value = serde_json::to_value(&context).unwrap();
+        let path = path(text);
+        for names in &[names(&[text]), names(&[])] {
+            let serialized = Serialized::new(&context, names).unwrap();
+            match (
+                serialized.lookup(&path).unwrap(),
+                value.lookup(&path).unwrap(),
+            ) {
+                (Found::Value(lazy), Found::Value(converted)) => {
+                    assert_eq!(converted, lazy, "{}", text)
+                }
+                (
+                    Found::Missing { step, level },
+                    Found::Missing {
+                        step: converted_step,
+                        level: converted_level,
+                    },
+                ) => {
+                    assert_eq!(converted_step, step, "{}", text);
+                    assert_eq!(keys(&converted_level), keys(&level), "{}", text);
+                }
+                _ => panic!("Lookups of {} disagree", text),
+            }
+        }
+    }
+
+    #[test]
+    fn test_lookup_found() {
+        for text in &[
+            "",
+            "inner",
+            "inner.name",
+            "inner.tags.1",
+            "inner.present",
+            "inner.missing",
+            "wrapper.2",
+            "pair.0",
+            "pair.1",
+            "shapes",
+            "shapes.0",
+            "shapes.1.Circle",
+            "shapes.2.Line",
+            "shapes.2.Line.1",
+            "shapes.3.Rect",
+            "shapes.3.Rect.height",
+            "numbers.20",
+            "names.y.tags.0",
+            "unit",
+        ] {
+            check(text);
+        }
+    }
+
+    #[test]
+    fn test_lookup_missing() {
+        for text in &[
+            "nothing",
+            "inner.nothing",
+            "inner.name.length",
+            "inner.tags.2",
+            "inner.tags.first",
+            "inner.missing.value",
+            "inner.present.value",
+            "wrapper.3",
+            "pair.2",
+            "shapes.0.Point",
+            "shapes.1.Line",
+            "shapes.2.Line.2",
+            "shapes.3.Circle",
+            "shapes.3.Rect.depth",
+            "numbers.2",
+            "names.z",
+            "unit.value",
+        ] {
+            check(text);
+        }
+    }
+
+    #[test]
+    fn test_lookup_borrows_named_values() {
+        let context = context();
+        let serialized = Serialized::new(&context, &names(&["inner.tags", "names"])).unwrap();
+        // Values below a top-level value which a template names are borrowed from it.
+        match serialized.lookup(&path("inner.tags.1")).unwrap() {
+            Found::Value(Cow::Borrowed(tag)) => assert_eq!(&Value::from("b"), tag),
+            _ => panic!("Expected a borrowed value"),
+        }
+        match serialized.lookup(&path("names.y.name")).unwrap() {
+            Found::Value(Cow::Borrowed(name)) => assert_eq!(&Value::from("y"), name),
+            _ => panic!("Expected a borrowed value"),
+        }
+        // Other values are converted for each lookup.
+        match serialized.lookup(&path("pair.0")).unwrap() {
+            Found::Value(Cow::Owned(left)) => assert_eq!(Value::from("left"), left),
+            _ => panic!("Expected an owned value"),
+        }
+    }
+
+    #[test]
+    fn test_lookup_root_converts_once() {
+        let context = context();
+        let value = serde_json::to_value(&context).unwrap();
+        let serialized = Serialized::new(&context, &names(&["@root"])).unwrap();
+        let first = match serialized.lookup(&[]).unwrap() {
+            Found::Value(Cow::Borrowed(root)) => root as *const Value,
+            _ => panic!("Expected a borrowed value"),
+        };
+        match serialized.lookup(&[]).unwrap() {
+            Found::Value(Cow::Borrowed(root)) => {
+                assert_eq!(&value, root);
+                assert_eq!(first, root as *const Value);
+            }
+            _ => panic!("Expected a borrowed value"),
+        }
+        // Failed top-level lookups borrow the same converted data.
+        match serialized.lookup(&path("nothing")).unwrap() {
+            Found::Missing {
+                level: Cow::Borrowed(level),
+                ..
+            } => assert_eq!(first, level as *const Value),
+            _ => panic!("Expected a borrowed level"),
+        }
+    }
+
+    #[test]
+    fn test_lookup_non_object_context() {
+        let context = vec![inner("a"), inner("b")];
+        let serialized = Serialized::new(&context, &names(&["1.name"])).unwrap();
+        match serialized.lookup(&path("1.name")).unwrap() {
+            Found::Value(name) => assert_eq!(Value::from("b"), *name),
+            Found::Missing { .. } => panic!("Expected to find the name"),
+        }
+    }
+
+    #[test]
+    fn test_lookup_skips_other_values() {
+        // Converting the whole map would fail, but lookups elsewhere never serialize it.
+        #[derive(Serialize)]
+        struct Context {
+            name: &'static str,
+            invalid: BTreeMap<Vec<u32>, u32>,
+        }
+        let context = Context {
+            name: "name",
+            invalid: vec![(vec![1], 1)].into_iter().collect(),
+        };
+        assert!(serde_json::to_value(&context).is_err());
+        // Values which cannot be converted are only reported when they are looked up.
+        let serialized = Serialized::new(&context, &names(&["name", "invalid"])).unwrap();
+        match serialized.lookup(&path("name")).unwrap() {
+            Found::Value(value) => assert_eq!(Value::from("name"), *value),
+            Found::Missing { .. } => panic!("Expected to find the name"),
+        }
+        assert!(serialized.lookup(&path("invalid.x")).is_err());
+        assert!(Serialized::new(&context, &names(&["@root"])).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! This module implements the future which renders templates into a tokio `AsyncWrite`.
+
+use error::*;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use template::{Output, Render};
+use tokio::io::AsyncWrite;
+
+/// Output which pauses rendering at the end of every literal, so that the text rendered since the
+/// last one can be written out as a chunk.
+struct ChunkOutput {
+    chunk: String,
+}
+impl Output for ChunkOutput {
+    fn buffer(&mut self) -> &mut String {
+        &mut self.chunk
+    }
+
+    fn after_literal(&mut self) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Future returned by `TinyTemplate::render_async`. Rendering pauses at the end of each literal,
+/// and everything rendered since the last one is written into the writer, which is then flushed.
+/// Rendering only carries on once the writer has accepted the whole chunk, so a slow writer holds
+/// up rendering rather than letting the output pile up in memory.
+#[must_use = "futures do nothing unless polled"]
+pub struct RenderAsync<'a, W: ?Sized + 'a> {
+    /// The render, until it is done. If it could not be started, the error is returned the first
+    /// time the future is polled.
+    render: Option<Result<Render<'a>>>,
+    writer: &'a mut W,
+    output: ChunkOutput,
+    /// The number of bytes of the current chunk which have already been written.
+    written: usize,
+}
+impl<'a, W: ?Sized + 'a> RenderAsync<'a, W> {
+    pub(crate) fn new(writer: &'a mut W, render: Result<Render<'a>>) -> RenderAsync<'a, W> {
+        RenderAsync {
+            render: Some(render),
+            writer,
+            output: ChunkOutput {
+                chunk: String::new(),
+            },
+            written: 0,
+        }
+    }
+}
+impl<'a, W> Future for RenderAsync<'a, W>
+where
+    W: AsyncWrite + Unpin + ?Sized + 'a,
+{
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.output.chunk.is_empty() {
+                let bytes = this.output.chunk.as_bytes();
+                while this.written < bytes.len() {
+                    match Pin::new(&mut *this.writer).poll_write(cx, &bytes[this.written..]) {
+                        Poll::Ready(Ok(0)) => {
+                            let err = io::Error::from(io::ErrorKind::WriteZero);
+                            return Poll::Ready(Err(err.into()));
+                        }
+                        Poll::Ready(Ok(count)) => this.written += count,
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err.into())),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                match Pin::new(&mut *this.writer).poll_flush(cx) {
+                    Poll::Ready(Ok(())) => {}
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err.into())),
+                    Poll::Pending => return Poll::Pending,
+                }
+                this.output.chunk.clear();
+                this.written = 0;
+            }
+
+            match this.render.take() {
+                Some(Ok(mut render)) => match render.resume(&mut this.output) {
+                    // The last chunk is written out the next time round.
+                    Ok(true) => {}
+                    Ok(false) => this.render = Some(Ok(render)),
+                    Err(err) => return Poll::Ready(Err(err)),
+                },
+                Some(Err(err)) => return Poll::Ready(Err(err)),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
@@ -25,6 +25,29 @@
 //! Give it {percentage | percent_formatter}!
 //! ```
 //!
+//! Formatters registered with
+//! [`TinyTemplate.add_argument_formatter`](../struct.TinyTemplate.html#method.add_argument_formatter)
+//! can be given arguments in parentheses after the formatter name. Arguments are separated by
+//! commas, and each one may be a path or a literal. Passing a number of arguments which the
+//! formatter does not accept will produce a rendering error.
+//!
+//! ```text
+//! Total: {price | round(2)}
+//! {name | pad_left(20, ".")}{ price | pad_left(column_width) }
+//! ```
+//!
+//! Formatters can be chained, in which case the value is passed through each of them in turn:
+//!
+//! ```text
+//! Hello, {name | trim | upper | truncate}!
+//! ```
+//!
+//! Every formatter except the last renders into a temporary string, which is then passed to the
+//! next formatter as a string value. Value transformers, registered with
+//! [`TinyTemplate.add_transformer`](../struct.TinyTemplate.html#method.add_transformer), can
+//! instead produce any value to pass on. If the last step of a chain is a transformer, the value it
+//! produces is rendered with the default formatter.
+//!
 //! The value may be a dotted path through a hierarchy of context objects. This will look up the
 //! "friend" field in the context structure, then substitute the "name" field from the "friend"
 //! object.
@@ -34,18 +57,55 @@
 //! ```
 //!
 //! Additionally, you may use the `@root` keyword to refer to the root object of your context.
-//! Since TinyTemplate can't normally print complex context objects, this is only useful if the
-//! context is a simple object like an integer or string.
+//! It can be followed by a path into the root object, which is useful to reach values that are
+//! shadowed by a loop or a with block:
+//!
+//! ```text
+//! {{ for name in guests }}Hello {name}, from {@root.name}!{{ endfor }}
+//! ```
+//!
+//! `@root` and the loop keywords described below can be used anywhere a path can, including in
+//! formatted values, formatter arguments, conditions and the path of a with, for or call tag.
+//!
+//! ### Literals
+//!
+//! Instead of a path, a literal value may be written in value tags, comparisons, formatter
+//! arguments, let tags and call tags. Literals use JSON syntax, so they may be double-quoted
+//! strings, numbers, `true`, `false` or `null`. Literal values are rendered and formatted exactly
+//! as if they had come from the context.
+//!
+//! ```text
+//! Status: {{ if status }}{ status }{{ else }}{ "n/a" }{{ endif }}
+//! Limit: { limit | percent_formatter(2) }{{ if limit > 90 }} (almost full){{ endif }}
+//! ```
+//!
+//! Value tags, the values tested in if conditions and the single value passed by a call tag
+//! accepted any path before literals were added, so only string literals are accepted there. A
+//! number, `true`, `false` or `null` in those places is still a path: `{ 0 }` looks up index 0 of
+//! an array and `{{ if null }}` looks up a field named `null`. To use such a literal there, name it
+//! with a let tag first.
+//!
+//! Literal strings may contain the delimiters of the tag they are in.
 //!
 //! ### Conditionals
 //!
 //! TinyTemplate blocks are marked with `{{...}}` - double-braces where values are single-braces.
+//! Unlike values, block tags may span several lines, which helps to keep long tags readable:
+//!
+//! ```text
+//! {{ call card with
+//!     title=report.name,
+//!     rows=report.rows
+//! }}
+//! ```
+//!
+//! A block tag which is not closed before the next block tag starts is an error.
 //!
 //! Conditionals are denoted by "{{ if path }}...{{ else }}...{{ endif }}". The Else block is
-//! optional. Else-if is not currently supported. If "path" evaluates to a truthy expression
-//! (true if boolean, non-zero if numeric, non-empty for strings and arrays, and non-null for
-//! objects) then the section of the template between "if" and "else" is evaluated, otherwise the
-//! section between "else" and "endif" (if present) is evaluated.
+//! optional. If "path" evaluates to a truthy expression (true if boolean, non-zero if numeric,
+//! non-empty for strings and arrays, and non-null for objects) then the section of the template
+//! between "if" and "else" is evaluated, otherwise the section between "else" and "endif" (if
+//! present) is evaluated.
 //!
 //! ```text
 //! {{ if user.is_birthday }}
@@ -65,13 +125,61 @@
 //! {{ endif }}
 //! ```
 //!
+//! Instead of a single path, the condition may compare two operands with one of the operators
+//! `==`, `!=`, `<`, `<=`, `>` or `>=`. Each operand is either a path or a literal value of any
+//! kind (see Literals above). Paths in conditions cannot contain whitespace or any of the characters
+//! `= ! < > ( ) "`, so a mistyped comparison such as `{{ if a = b }}` is reported when the template
+//! is compiled.
+//!
+//! ```text
+//! {{ if status == "failed" }}
+//! Failed!
+//! {{ endif }}
+//! {{ if count > 10 }}
+//! Too many results
+//! {{ endif }}
+//! ```
+//!
+//! Any two values can be compared with `==` and `!=`. Values of different types are never equal,
+//! except that numbers compare by value (so `1 == 1.0`). The ordering operators can only compare
+//! two numbers, two strings (compared lexicographically) or two booleans (`false < true`), and
+//! attempting to order any other combination of values will produce a rendering error.
+//!
+//! Conditions can be combined with "and" and "or", and grouped with parentheses. "not" binds
+//! most tightly, followed by "and" and then "or". Conditions are evaluated from left to right and
+//! evaluation stops as soon as the result is known, so a path on the right-hand side of an "and"
+//! is not looked up (and cannot cause an error) if the left-hand side is falsy.
+//!
+//! ```text
+//! {{ if user.is_birthday and not (user.is_admin or user.is_robot) }}
+//! Happy Birthday!
+//! {{ endif }}
+//! ```
+//!
+//! Any number of "{{ elif path }}" tags may be placed between the "if" and the "else" to chain
+//! further conditions. Each condition is only checked if all of the conditions before it were
+//! falsy, and the first truthy one selects the section to evaluate. "{{ else if path }}" is
+//! accepted as another spelling of "elif", and "elif" accepts all of the same conditions as "if".
+//!
+//! ```text
+//! {{ if build.failed }}
+//! Failed
+//! {{ elif not build.finished }}
+//! Running
+//! {{ elif build.warnings }}
+//! Passed with warnings
+//! {{ else }}
+//! Passed
+//! {{ endif }}
+//! ```
+//!
 //! If desired, the `@root` keyword can be used to branch on the root context object.
 //!
 //! ### Loops
 //!
-//! TinyTemplate supports iterating over the values of arrays. Only arrays are supported. Loops
-//! are denoted by "{{ for value_name in value.path }}...{{ endfor }}". The section of the template between
-//! the two tags will be executed once for each value in the array denoted by "value.path".
+//! TinyTemplate supports iterating over the values of arrays and objects. Loops are denoted by
+//! "{{ for value_name in value.path }}...{{ endfor }}". The section of the template between the
+//! two tags will be executed once for each value in the array or object denoted by "value.path".
 //!
 //! ```text
 //! Hello to {{ for name in guests }}
@@ -89,11 +197,41 @@
 //! {{ endfor }}
 //! ```
 //!
-//! There are three special values which are available within a loop:
+//! When iterating over an object, the key of each entry can be given a name as well, using
+//! "{{ for key_name, value_name in value.path }}". Naming the key is an error if "value.path" is
+//! an array.
+//!
+//! ```text
+//! {{ for name, score in scores }}
+//! {name}: {score}
+//! {{ endfor }}
+//! ```
+//!
+//! The entries of an object are visited in the order of `serde_json`'s map type. By default this
+//! is sorted by key. If `serde_json`'s `preserve_order` feature is enabled, they are visited in
+//! insertion order instead, which is the order of the fields of a serialized struct.
 //!
-//! * `@index` - zero-based index of the current value within the array.
+//! Loops can also count through a range of integers with "{{ for i in range(start, end) }}". The
+//! range includes "start" but not "end", and an optional third argument gives the step between
+//! numbers, which may be negative to count down. Each argument may be a path or a literal, and
+//! must be an integer.
+//!
+//! ```text
+//! {{ for i in range(0, columns) }}<td></td>{{ endfor }}
+//! {{ for i in range(10, 0, -2) }}{i} {{ endfor }}
+//! ```
+//!
+//! There are several special values which are available within a loop:
+//!
+//! * `@index` - zero-based index of the current value within the array or object.
+//! * `@index1` - one-based index of the current value, for "1 of 3" style labels.
+//! * `@revindex` - the number of values remaining after the current one, so zero for the last.
+//! * `@length` - the number of values in the array, object or range.
 //! * `@first` - true if this is the first iteration of the loop, otherwise false.
 //! * `@last` - true if this is the last iteration of the loop, otherwise false.
+//! * `@odd` - true if `@index` is odd, otherwise false.
+//! * `@even` - true if `@index` is even (as it is for the first value), otherwise false.
+//! * `@key` - the key of the current entry, when iterating over an object.
 //!
 //! ```text
 //! Hello to {{ for name in guests -}}
@@ -102,11 +240,30 @@
 //! ```
 //!
 //!
-//! In case of nested loops, these values refer to the innermost loop which contains them.
+//! In case of nested loops, these values refer to the innermost loop which contains them. To refer
+//! to an enclosing loop instead, put the name of that loop's value (or key) in front of the
+//! keyword:
+//!
+//! ```text
+//! {{ for row in rows }}{{ for cell in row.cells }}
+//! Row { row.@index }, column { cell.@index }: {cell}
+//! {{ endfor }}{{ endfor }}
+//! ```
 //!
 //! If the root context object is an array, the `@root` keyword can be used to iterate over the
 //! root object.
 //!
+//! A loop may have an "{{ else }}" section, which is evaluated instead of the loop body if the
+//! array is empty. The loop's value name is not defined within the else section.
+//!
+//! ```text
+//! {{ for name in guests }}
+//! Hello, {name}!
+//! {{ else }}
+//! Nobody is here.
+//! {{ endfor }}
+//! ```
+//!
 //! ### With Blocks
 //!
 //! Templates can use with blocks to partially shadows the outer context, the same way that
@@ -125,6 +282,27 @@
 //! This looks up "person.spouse" and adds that to the context as "s" within the block. Only the
 //! name "s" is shadowed within the with block and otherwise the outer context is still accessible.
 //!
+//! ### Variables
+//!
+//! A let tag gives a name to a value for the rest of the enclosing block, without needing a
+//! closing tag. The value may be a path or a literal, optionally followed by formatters, and
+//! "set" can be used instead of "let":
+//!
+//! ```text
+//! {{ let primary = report.summary.benchmarks.primary }}
+//! {{ set price = item.price | round(2) }}
+//! { primary.name }: { price }
+//! ```
+//!
+//! The name is visible until the end of the if, elif, else, for or with section containing the
+//! let tag, or until the end of the template if it is not inside a block. If formatters are given,
+//! the variable holds the text they produce, unless the last of them is a value transformer, in
+//! which case it holds the transformed value. Variable names must start with a letter or an
+//! underscore and contain only letters, digits and underscores. The words `true`, `false`, `null`,
+//! `and`, `or` and `not` and the names of tags and their keywords, such as `if`, `for`, `in`,
+//! `with`, `as` and `endfor`, are reserved, so they cannot name variables here or in with, for,
+//! params and macro tags.
+//!
 //! ### Trimming Whitespace
 //!
 //! If a block tag, comment or value tag includes a "-" character at the start, the trailing
@@ -140,6 +318,26 @@
 //!
 //! This will print "Hello friend, how are you? I am fine." without the newlines or extra spaces.
 //!
+//! Templates which generate code often need the whitespace around every block tag trimmed. Instead
+//! of adding "-" to each tag, two settings can be turned on for a whole registry. With
+//! [`TinyTemplate.set_trim_blocks`](../struct.TinyTemplate.html#method.set_trim_blocks), the first
+//! newline after each block tag or comment is removed. With
+//! [`TinyTemplate.set_lstrip_blocks`](../struct.TinyTemplate.html#method.set_lstrip_blocks), the
+//! spaces and tabs before a block tag or comment are removed if nothing else comes before it on
+//! its line. Together, they let block tags sit on lines of their own without leaving blank lines
+//! in the output:
+//!
+//! ```text
+//! <ul>
+//!     {{ for item in items }}
+//!     <li>{ item }</li>
+//!     {{ endfor }}
+//! </ul>
+//! ```
+//!
+//! Value tags are not affected by these settings. A "-" still trims all of the whitespace on its
+//! side of a tag, including any newlines.
+//!
 //! ### Calling other Templates
 //!
 //! Templates may call other templates by name. The other template must have been registered using
@@ -150,8 +348,79 @@
 //!
 //! The call tag has no closing tag. This will look up the "path.to.context" path in the current
 //! context, then render the "template_name" template using the value at that path as the context
-//! for the other template. The string produced by the called template is then inserted into the
-//! output from the calling template. This can be used for a limited form of template code reuse.
+//! for the other template. A string literal may be passed instead of a path. The string produced by
+//! the called template is then inserted into the output from the calling template. This can be used
+//! for a limited form of template code reuse.
+//!
+//! Instead of a single value, a call tag may pass named arguments, which can be paths or literals:
+//!
+//! "{{ call card with title=item.name, rows=item.data, class=\"wide\" }}"
+//!
+//! The called template is then rendered with an object containing each argument as its context,
+//! so it can use "{ title }", "{ rows }" and "{ class }". A template may declare the arguments it
+//! expects with a "params" tag, which must come before any other text in the template:
+//!
+//! "{{ params title, rows, class }}"
+//!
+//! When a template declares its parameters, calling it with named arguments is an error if any of
+//! them are missing or if an argument is passed which it doesn't declare.
+//!
+//! Small snippets which are only needed by one template can be defined inside it as macros, which
+//! are called in the same way as templates:
+//!
+//! ```text
+//! {{ macro card(title, rows) }}<div>{ title }: {{ for row in rows }}{ row }{{ endfor }}</div>{{ endmacro }}
+//! {{ call card with title=report.name, rows=report.rows }}
+//! ```
+//!
+//! The body of a macro is not rendered where it is defined, only when it is called. Like any
+//! called template, a macro can only see the context it is called with, and named arguments are
+//! checked against its parameters. Macros must be defined outside of any other block. A template
+//! can also use the macros defined by another registered template by importing it with a tag such
+//! as "{{ import \"forms\" }}", which must come before any other text in the template. When a
+//! macro has the same name as a registered template, calls use the macro.
+//!
+//! A template can also be included with the "include" tag, which takes the quoted name of the
+//! template:
+//!
+//! "{{ include \"template_name\" }}"
+//!
+//! Unlike a called template, an included template is rendered with the full context of the place
+//! where it is included, so it can use the values of enclosing loops, with blocks and variables
+//! as well as the root context. Variables set by the included template are not visible after the
+//! include tag.
+//!
+//! ### Template Inheritance
+//!
+//! Templates can share a common layout through inheritance. A base template marks the sections
+//! which other templates may replace with "{{ block name }}...{{ endblock }}" tags. The name may
+//! optionally be repeated in the closing tag, as in "{{ endblock name }}".
+//!
+//! ```text
+//! <html>
+//! <head><title>{{ block title }}My Site{{ endblock }}</title></head>
+//! <body>{{ block body }}{{ endblock }}</body>
+//! </html>
+//! ```
+//!
+//! A template which starts with "{{ extends \"base\" }}" is rendered using the layout of the
+//! template registered as "base", replacing each block of the base template with the block of the
+//! same name in the extending template. Blocks which the extending template doesn't define are
+//! rendered as they are in the base template, and anything outside of the blocks of the extending
+//! template is ignored. Within a block, "{{ super }}" renders the block as the parent template
+//! defines it.
+//!
+//! ```text
+//! {{ extends "base" }}
+//! {{ block title }}Reports - {{ super }}{{ endblock }}
+//! {{ block body }}{{ for report in reports }}{ report.name }{{ endfor }}{{ endblock }}
+//! ```
+//!
+//! The extends tag must come before any other tags or text, other than whitespace and comments.
+//! Base templates may themselves extend other templates. Blocks are rendered with the context of
+//! the place in the base template where they appear, so a block inside a for loop in the base
+//! template can use the loop's value. It is an error to extend a template which is not registered,
+//! or for a template to inherit from itself.
 //!
 //! ### Comments
 //!
@@ -165,7 +434,8 @@
 //! {#- This is a comment #} world!
 //! ```
 //!
-//! This will print "Hello world!".
+//! This will print "Hello world!". Comments may span several lines, and may contain other tags,
+//! which makes them useful to disable a part of a template.
 //!
 //! ### Escaping Curly Braces
 //!
@@ -180,5 +450,40 @@
 //!
 //! If using a string literal in rust source code, the `\` itself must be escaped, producing `\\{`.
 //!
+//! Longer sections which contain many curly braces, such as scripts, styles or templates for
+//! another template engine, can instead be wrapped in a raw block. Everything between
+//! "{{ raw }}" and "{{ endraw }}" is copied to the output as it is, without looking for any tags,
+//! values, comments or escapes:
+//!
+//! ```text
+//! {{ raw }}
+//! <script id="row-template" type="text/x-handlebars-template">
+//!     {{#each rows}}<li>{{name}}</li>{{/each}}
+//! </script>
+//! {{ endraw }}
+//! ```
+//!
+//! It is an error for a raw block not to be closed by an endraw tag.
+//!
+//! ### Changing the Delimiters
+//!
+//! The delimiters described above are the defaults. For text which uses many curly braces itself,
+//! such as LaTeX, JSON or C code, a [`Delimiters`](../struct.Delimiters.html) value can set other
+//! start and end delimiters for block tags, values and comments. They can be set for all templates
+//! added to a registry afterwards with
+//! [`TinyTemplate.set_delimiters`](../struct.TinyTemplate.html#method.set_delimiters), or for a
+//! single template with
+//! [`TinyTemplate.add_template_with_delimiters`](../struct.TinyTemplate.html#method.add_template_with_delimiters).
+//! For example, with `<% %>` for block tags, `<%= %>` for values and `<%# %>` for comments:
+//!
+//! ```text
+//! <%# Render the rows %>
+//! <% for row in rows %>\begin{row}<%= row.name -%>\end{row}<% endfor %>
+//! ```
+//!
+//! Everything else works as it does with the default delimiters, including the `-` markers to
+//! trim whitespace. A backslash escapes the first character of a start delimiter. Delimiters must
+//! not be empty or contain whitespace, and the three kinds of tag need different start delimiters.
+//!
 
 // There's nothing here, this module is solely for documentation.
@@ -1,33 +1,174 @@
 //! This module implements the bytecode interpreter that actually renders the templates.
 
-use compiler::TemplateCompiler;
+// The associated numeric constants are newer than our minimum supported Rust version.
+#![allow(clippy::legacy_numeric_constants)]
+
+use compiler::{MacroDefinition, TemplateCompiler};
 use error::Error::*;
 use error::*;
-use instruction::{Instruction, PathSlice, PathStep};
-use serde_json::Value;
+use instruction::{
+    Comparison, FormatterCall, Instruction, NamedArgument, Operand, PathSlice, PathStep,
+};
+use lookup::{Found, Lookup, RootNames};
+use serde_json::{map, Map, Number, Value};
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::fmt::Write;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::ptr;
+use std::rc::Rc;
 use std::slice;
-use ValueFormatter;
+use std::vec;
+use {Delimiters, Formatter, ValueFormatter};
 
 /// Enum defining the different kinds of records on the context stack.
 enum ContextElement<'render, 'template> {
     /// Object contexts shadow everything below them on the stack, because every name is looked up
     /// in this object.
-    Object(&'render Value),
+    Object(&'render dyn Lookup),
+    /// Owned object contexts are object contexts which the stack owns, such as the value which a
+    /// template is called with when it was produced during rendering. Values found in them are
+    /// treated like values owned by the stack.
+    OwnedObject(Box<dyn Lookup + 'render>),
     /// Named contexts shadow only one name. Any path that starts with that name is looked up in
-    /// this object, and all others are passed on down the stack.
-    Named(&'template str, &'render Value),
-    /// Iteration contexts shadow one name with the current value of the iteration. They also
-    /// store the iteration state. The two usizes are the index of the current value and the length
-    /// of the array that we're iterating over.
-    Iteration(
-        &'template str,
-        &'render Value,
-        usize,
-        usize,
-        slice::Iter<'render, Value>,
-    ),
+    /// this object, and all others are passed on down the stack. The value is owned if it was
+    /// produced during rendering rather than found in the context, such as a loop key.
+    Named(&'template str, Cow<'render, Value>),
+    /// Iteration contexts shadow one name with the current value of the iteration, and possibly a
+    /// second name with the current key. They also store the iteration state.
+    Iteration(IterationContext<'render, 'template>),
+}
+
+impl<'render, 'template> ContextElement<'render, 'template> {
+    /// The object at the bottom of the context stack of a called template, which is borrowed if it
+    /// was found in the context and owned if it was produced during rendering.
+    fn object(value: Cow<'render, Value>) -> ContextElement<'render, 'template> {
+        match value {
+            Cow::Borrowed(value) => ContextElement::Object(value),
+            Cow::Owned(value) => ContextElement::OwnedObject(Box::new(value)),
+        }
+    }
+}
+
+/// The state of a for loop on the context stack.
+struct IterationContext<'render, 'template> {
+    /// The name which is shadowed by the current value.
+    name: &'template str,
+    /// The name which is shadowed by the current key, for loops over objects which name it.
+    key_name: Option<&'template str>,
+    /// The current value, for loops over values borrowed from the context and ranges. Values owned
+    /// by the context stack are looked up by index instead.
+    value: Cow<'render, Value>,
+    /// The current key, when iterating over an object.
+    key: Option<Cow<'render, str>>,
+    /// The index of the current value. This is usize::MAX until the first value is produced.
+    index: usize,
+    /// The number of values we're iterating over.
+    length: usize,
+    items: Items<'render, 'template>,
+}
+
+/// Iterator over the values of an array, the entries of an object or the numbers of a range.
+/// Object entries are produced in the order used by serde_json's Map, which is sorted by key unless
+/// serde_json's `preserve_order` feature is enabled, in which case it is insertion order.
+enum Items<'render, 'template> {
+    Array(slice::Iter<'render, Value>),
+    Object(map::Iter<'render>),
+    Range(Range),
+    /// An array produced during rendering rather than found in the context, which this loop owns.
+    OwnedArray(Vec<Value>),
+    /// The keys and values of an object produced during rendering.
+    OwnedObject(vec::IntoIter<String>, Vec<Value>),
+    /// An array at the given path within the value of a lower layer of the context stack, which
+    /// cannot change while this loop is running.
+    StackArray(usize, PathSlice<'template, 'template>),
+}
+
+/// Iterator over a range of integers, which produces them one at a time rather than collecting
+/// them into an array.
+struct Range {
+    next: i64,
+    step: i64,
+    remaining: usize,
+}
+impl Range {
+    /// Create a range, unless it holds too many numbers for its length to fit in a usize.
+    fn new(start: i64, end: i64, step: i64) -> Option<Range> {
+        // Compute in i128 so that ranges spanning most of the i64 range cannot overflow.
+        let (span, step_size) = if step > 0 {
+            (i128::from(end) - i128::from(start), i128::from(step))
+        } else {
+            (i128::from(start) - i128::from(end), -i128::from(step))
+        };
+        let remaining = if span <= 0 {
+            0
+        } else {
+            (span - 1) / step_size + 1
+        };
+        usize::try_from(remaining).ok().map(|remaining| Range {
+            next: start,
+            step,
+            remaining,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.remaining
+    }
+}
+impl Iterator for Range {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let current = self.next;
+        // The step after the last number may overflow, but that value is never produced.
+        self.next = self.next.wrapping_add(self.step);
+        self.remaining -= 1;
+        Some(current)
+    }
+}
+
+/// Iterator over an object produced during rendering.
+fn owned_object(map: Map<String, Value>) -> Items<'static, 'static> {
+    let (keys, values): (Vec<String>, Vec<Value>) = map.into_iter().unzip();
+    Items::OwnedObject(keys.into_iter(), values)
+}
+
+/// Iterator over an array or object found within a value owned by the given layer of the context
+/// stack. Arrays which the layer holds are not cloned, but looked up by index as the loop runs.
+fn stack_items<'render, 'template>(
+    layer: usize,
+    path: PathSlice<'template, 'template>,
+    value: Cow<Value>,
+) -> Option<(usize, Items<'render, 'template>)> {
+    match value {
+        Cow::Borrowed(Value::Array(values)) => Some((values.len(), Items::StackArray(layer, path))),
+        Cow::Borrowed(Value::Object(map)) => Some((map.len(), owned_object(map.clone()))),
+        Cow::Owned(Value::Array(values)) => Some((values.len(), Items::OwnedArray(values))),
+        Cow::Owned(Value::Object(map)) => Some((map.len(), owned_object(map))),
+        _ => None,
+    }
+}
+
+/// The layer of the context stack which a path is looked up in.
+enum Scope<'render, 'stack> {
+    /// The whole path is looked up in a context object.
+    Object(&'render dyn Lookup),
+    /// The whole path is looked up in a context object owned by the given layer of the stack.
+    OwnedObject(usize, &'stack dyn Lookup),
+    /// The rest of the path is looked up in a value borrowed from the context, which shadows the
+    /// first step.
+    Borrowed(&'render Value),
+    /// The rest of the path is looked up in a value owned by the given layer of the stack, which
+    /// shadows the first step.
+    Owned(usize, &'stack Value),
+    /// The rest of the path is looked up in the current key of a loop.
+    Key(Value),
 }
 
 /// Helper struct which mostly exists so that I have somewhere to put functions that access the
@@ -37,20 +178,28 @@ struct RenderContext<'render, 'template> {
     context_stack: Vec<ContextElement<'render, 'template>>,
 }
 impl<'render, 'template> RenderContext<'render, 'template> {
-    /// Look up the given path in the context stack and return the value (if found) or an error (if
-    /// not)
-    fn lookup(&self, path: PathSlice) -> Result<&'render Value> {
-        for stack_layer in self.context_stack.iter().rev() {
+    /// Find the layer of the context stack which a path starting with the given name is looked up
+    /// in.
+    fn scope(&self, name: &str) -> Scope<'render, '_> {
+        for (layer, stack_layer) in self.context_stack.iter().enumerate().rev() {
             match stack_layer {
-                ContextElement::Object(obj) => return self.lookup_in(path, obj),
-                ContextElement::Named(name, obj) => {
-                    if *name == &*path[0] {
-                        return self.lookup_in(&path[1..], obj);
+                ContextElement::Object(obj) => return Scope::Object(*obj),
+                ContextElement::OwnedObject(obj) => return Scope::OwnedObject(layer, &**obj),
+                ContextElement::Named(other, obj) => {
+                    if *other == name {
+                        return match obj {
+                            Cow::Borrowed(value) => Scope::Borrowed(value),
+                            Cow::Owned(value) => Scope::Owned(layer, value),
+                        };
                     }
                 }
-                ContextElement::Iteration(name, obj, _, _, _) => {
-                    if *name == &*path[0] {
-                        return self.lookup_in(&path[1..], obj);
+                ContextElement::Iteration(iteration) => {
+                    if iteration.name == name {
+                        return self.iteration_scope(layer, iteration);
+                    }
+                    if iteration.key_name == Some(name) {
+                        let key = iteration.key.as_ref().map(|key| key.to_string());
+                        return Scope::Key(Value::String(key.unwrap_or_default()));
                     }
                 }
             }
@@ -58,45 +207,272 @@ impl<'render, 'template> RenderContext<'render, 'template> {
         panic!("Attempted to do a lookup with an empty context stack. That shouldn't be possible.")
     }
 
-    /// Look up a path within a given value object and return the resulting value (if found) or
-    /// an error (if not)
-    fn lookup_in(&self, path: PathSlice, object: &'render Value) -> Result<&'render Value> {
-        let mut current = object;
-        for step in path.iter() {
-            if let PathStep::Index(_, n) = step {
-                if let Some(next) = current.get(n) {
-                    current = next;
-                    continue;
+    /// Find the layer of the context stack which the given path is looked up in, along with the
+    /// part of the path which is looked up there. Paths starting with `@root` are looked up in the
+    /// object at the bottom of the stack.
+    fn resolve<'p, 't>(&self, path: PathSlice<'p, 't>) -> (Scope<'render, '_>, PathSlice<'p, 't>) {
+        if &*path[0] == "@root" {
+            return (self.root_scope(), &path[1..]);
+        }
+        match self.scope(&path[0]) {
+            scope @ Scope::Object(_) | scope @ Scope::OwnedObject(..) => (scope, path),
+            scope => (scope, &path[1..]),
+        }
+    }
+
+    /// Find the current value of the given loop, which is at the given layer of the stack.
+    fn iteration_scope<'a>(
+        &'a self,
+        layer: usize,
+        iteration: &'a IterationContext<'render, 'template>,
+    ) -> Scope<'render, 'a> {
+        let values = match iteration.items {
+            Items::OwnedArray(ref values) | Items::OwnedObject(_, ref values) => values,
+            Items::StackArray(source, path) => match self.stack_value(source, path) {
+                Value::Array(values) => values,
+                _ => panic!("Malformed context stack."),
+            },
+            _ => {
+                return match iteration.value {
+                    Cow::Borrowed(value) => Scope::Borrowed(value),
+                    Cow::Owned(ref value) => Scope::Owned(layer, value),
+                }
+            }
+        };
+        // Before the first iteration, the value is null, as it is for other loops.
+        Scope::Owned(layer, values.get(iteration.index).unwrap_or(&Value::Null))
+    }
+
+    /// Find the value at the given path within the current value of the given layer of the stack.
+    /// The path is known to exist, because it was looked up when the layer above was pushed.
+    fn stack_value(&self, layer: usize, path: PathSlice) -> &Value {
+        let value: &dyn Lookup = match &self.context_stack[layer] {
+            ContextElement::Named(_, value) => &**value,
+            ContextElement::Iteration(iteration) => match self.iteration_scope(layer, iteration) {
+                Scope::Borrowed(value) => value,
+                Scope::Owned(_, value) => value,
+                _ => panic!("Malformed context stack."),
+            },
+            ContextElement::OwnedObject(object) => &**object,
+            ContextElement::Object(_) => panic!("Malformed context stack."),
+        };
+        match value.lookup(path) {
+            Ok(Found::Value(Cow::Borrowed(value))) => value,
+            _ => panic!("Malformed context stack."),
+        }
+    }
+
+    /// Look up the given path in the context stack and return the value (if found) or an error (if
+    /// not). Values owned by the context stack are borrowed rather than cloned, so the result can
+    /// only be used until the stack changes.
+    fn lookup(&self, path: PathSlice) -> Result<Cow<'_, Value>> {
+        match self.resolve(path) {
+            (Scope::Object(object), rest) => self.lookup_in(rest, object),
+            (Scope::OwnedObject(_, object), rest) => self.lookup_in(rest, object),
+            (Scope::Borrowed(value), rest) => self.lookup_in(rest, value),
+            (Scope::Owned(_, value), rest) => self.lookup_in(rest, value),
+            (Scope::Key(key), rest) => self.lookup_in_owned(rest, key),
+        }
+    }
+
+    /// Look up the given path in the context stack, to push the value onto the stack. Values found
+    /// in the context data stay borrowed, but values owned by the context stack have to be cloned.
+    fn lookup_for_stack(&self, path: PathSlice) -> Result<Cow<'render, Value>> {
+        match self.resolve(path) {
+            (Scope::Object(object), rest) => self.lookup_in(rest, object),
+            (Scope::OwnedObject(_, object), rest) => self
+                .lookup_in(rest, object)
+                .map(|value| Cow::Owned(value.into_owned())),
+            (Scope::Borrowed(value), rest) => self.lookup_in(rest, value),
+            (Scope::Owned(_, value), rest) => self
+                .lookup_in(rest, value)
+                .map(|value| Cow::Owned(value.into_owned())),
+            (Scope::Key(key), rest) => self.lookup_in_owned(rest, key),
+        }
+    }
+
+    /// Look up the array or object which a for loop iterates over, and return its length and an
+    /// iterator over it, or None if it is neither. Arrays owned by the context stack are not
+    /// cloned, but looked up by index as the loop runs.
+    fn lookup_items(
+        &self,
+        path: PathSlice<'template, 'template>,
+    ) -> Result<Option<(usize, Items<'render, 'template>)>> {
+        let value = match self.lookup_keyword(path) {
+            Some(value) => value?,
+            None => match self.resolve(path) {
+                (Scope::OwnedObject(layer, object), rest) => {
+                    return Ok(stack_items(layer, rest, self.lookup_in(rest, object)?));
+                }
+                (Scope::Owned(layer, value), rest) => {
+                    return Ok(stack_items(layer, rest, self.lookup_in(rest, value)?));
                 }
+                _ => self.lookup_for_stack(path)?,
+            },
+        };
+        Ok(match value {
+            Cow::Borrowed(Value::Array(values)) => {
+                Some((values.len(), Items::Array(values.iter())))
+            }
+            Cow::Borrowed(Value::Object(map)) => Some((map.len(), Items::Object(map.iter()))),
+            Cow::Owned(Value::Array(values)) => Some((values.len(), Items::OwnedArray(values))),
+            Cow::Owned(Value::Object(map)) => Some((map.len(), owned_object(map))),
+            _ => None,
+        })
+    }
+
+    /// Look up a path within the given data and return the resulting value (if found) or an error
+    /// (if not)
+    fn lookup_in<'a, L: Lookup + ?Sized>(
+        &self,
+        path: PathSlice,
+        object: &'a L,
+    ) -> Result<Cow<'a, Value>> {
+        match object.lookup(path)? {
+            Found::Value(value) => Ok(value),
+            Found::Missing { step, level } => {
+                Err(lookup_error(self.original_text, &path[step], path, &level))
             }
+        }
+    }
 
-            let step: &str = &*step;
+    /// Look up a path within an owned value. Any value found within it has to be cloned.
+    fn lookup_in_owned<'a>(&self, path: PathSlice, object: Value) -> Result<Cow<'a, Value>> {
+        if path.is_empty() {
+            return Ok(Cow::Owned(object));
+        }
+        self.lookup_in(path, &object)
+            .map(|value| Cow::Owned(value.into_owned()))
+    }
 
-            match current.get(step) {
-                Some(next) => current = next,
-                None => return Err(lookup_error(self.original_text, step, path, current)),
+    /// Look up the top iteration context on the stack, for the given loop keyword.
+    fn lookup_iteration(&self, keyword: &str) -> Result<&IterationContext<'render, 'template>> {
+        for stack_layer in self.context_stack.iter().rev() {
+            match stack_layer {
+                ContextElement::Iteration(iteration) => return Ok(iteration),
+                _ => continue,
             }
         }
-        Ok(current)
+        Err(GenericError {
+            msg: format!("Used {} outside of a foreach block.", keyword),
+        })
     }
 
-    /// Look up the index and length values for the top iteration context on the stack.
-    fn lookup_index(&self) -> Result<(usize, usize)> {
+    /// Look up the iteration context of the loop whose value or key has the given name. This is
+    /// the innermost such loop, unless the name is shadowed by something other than a loop.
+    fn lookup_loop(&self, name: &str) -> Result<&IterationContext<'render, 'template>> {
         for stack_layer in self.context_stack.iter().rev() {
             match stack_layer {
-                ContextElement::Iteration(_, _, index, length, _) => return Ok((*index, *length)),
+                ContextElement::Iteration(iteration)
+                    if iteration.name == name || iteration.key_name == Some(name) =>
+                {
+                    return Ok(iteration)
+                }
+                ContextElement::Named(other, _) if *other == name => break,
+                ContextElement::Object(_) | ContextElement::OwnedObject(_) => break,
                 _ => continue,
             }
         }
         Err(GenericError {
-            msg: "Used @index outside of a foreach block.".to_string(),
+            msg: format!(
+                "Used a loop keyword on '{}', which is not a loop value.",
+                name
+            ),
         })
     }
 
-    /// Look up the root context object
-    fn lookup_root(&self) -> Result<&'render Value> {
-        match self.context_stack.get(0) {
-            Some(ContextElement::Object(obj)) => Ok(obj),
+    /// Produce the value of one of the loop keywords for the given iteration context.
+    fn loop_keyword(
+        &self,
+        iteration: &IterationContext<'render, 'template>,
+        keyword: &str,
+    ) -> Result<Cow<'render, Value>> {
+        let value = match keyword {
+            "@index" => Value::from(iteration.index),
+            "@index1" => Value::from(iteration.index + 1),
+            "@revindex" => Value::from(iteration.length - iteration.index - 1),
+            "@length" => Value::from(iteration.length),
+            "@first" => Value::Bool(iteration.index == 0),
+            "@last" => Value::Bool(iteration.index == iteration.length - 1),
+            "@odd" => Value::Bool(iteration.index & 1 == 1),
+            "@even" => Value::Bool(iteration.index & 1 == 0),
+            "@key" => match iteration.key {
+                Some(ref key) => Value::from(&**key),
+                None => {
+                    return Err(GenericError {
+                        msg: "Used @key in a loop over an array.".to_string(),
+                    })
+                }
+            },
+            other => panic!("Unknown keyword {}", other), // This should have been caught by the parser.
+        };
+        Ok(Cow::Owned(value))
+    }
+
+    /// Look up a path which is one of the loop keywords or a loop value followed by a loop keyword,
+    /// like `row.@index`. The loop keywords produce owned values. Returns None for other paths,
+    /// including those starting with `@root`.
+    fn lookup_keyword(&self, path: PathSlice) -> Option<Result<Cow<'render, Value>>> {
+        let first: &str = path.first().unwrap();
+        if first == "@root" {
+            None
+        } else if first.starts_with('@') {
+            Some(
+                self.lookup_iteration(first)
+                    .and_then(|iteration| self.loop_keyword(iteration, first)),
+            )
+        } else if path.len() == 2 && path[1].starts_with('@') {
+            Some(
+                self.lookup_loop(first)
+                    .and_then(|iteration| self.loop_keyword(iteration, &path[1])),
+            )
+        } else {
+            None
+        }
+    }
+
+    /// Look up a path which may use the @-keywords. The result can only be used until the stack
+    /// changes.
+    fn lookup_path(&self, path: PathSlice) -> Result<Cow<'_, Value>> {
+        match self.lookup_keyword(path) {
+            Some(value) => value,
+            None => self.lookup(path),
+        }
+    }
+
+    /// Look up a path which may use the @-keywords, to push the value onto the stack.
+    fn lookup_path_for_stack(&self, path: PathSlice) -> Result<Cow<'render, Value>> {
+        match self.lookup_keyword(path) {
+            Some(value) => value,
+            None => self.lookup_for_stack(path),
+        }
+    }
+
+    /// Look up the value of an operand, which is either a literal or a path in the context stack.
+    /// The path may use the @-keywords. The result can only be used until the stack changes.
+    fn lookup_value<'a>(&'a self, operand: &'a Operand) -> Result<Cow<'a, Value>> {
+        match operand {
+            Operand::Literal(_, value) => Ok(Cow::Borrowed(value)),
+            Operand::Path(path) => self.lookup_path(path),
+        }
+    }
+
+    /// Look up the value of an operand, to push the value onto the stack.
+    fn lookup_value_for_stack<'a>(&self, operand: &'a Operand) -> Result<Cow<'a, Value>>
+    where
+        'render: 'a,
+    {
+        match operand {
+            Operand::Literal(_, value) => Ok(Cow::Borrowed(value)),
+            Operand::Path(path) => self.lookup_path_for_stack(path),
+        }
+    }
+
+    /// Find the scope of the object at the bottom of the context stack, which `@root` refers to.
+    fn root_scope(&self) -> Scope<'render, '_> {
+        match self.context_stack.first() {
+            Some(ContextElement::Object(obj)) => Scope::Object(*obj),
+            Some(ContextElement::OwnedObject(obj)) => Scope::OwnedObject(0, &**obj),
             Some(_) => {
                 panic!("Expected Object value at root of context stack, but was something else.")
             }
@@ -107,30 +483,197 @@ impl<'render, 'template> RenderContext<'render, 'template> {
     }
 }
 
+/// Once this many bytes of rendered text are buffered, outputs which render into a writer write
+/// them out.
+pub(crate) const WRITE_BUFFER_SIZE: usize = 8 * 1024;
+
+/// The destination which templates are rendered into. Rendered text is appended to a string
+/// buffer, so that formatters can write into it. Outputs which render into a writer empty the
+/// buffer into it from time to time.
+pub(crate) trait Output {
+    /// The buffer to append rendered text to.
+    fn buffer(&mut self) -> &mut String;
+
+    /// Called after each instruction which appends text to the buffer.
+    fn after_write(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called instead of `after_write` after each literal. Returns true to pause rendering, so
+    /// that what has been rendered so far can be written out before it carries on.
+    fn after_literal(&mut self) -> Result<bool> {
+        self.after_write().map(|()| false)
+    }
+}
+impl Output for String {
+    fn buffer(&mut self) -> &mut String {
+        self
+    }
+}
+
+/// Output which renders into an `io::Write`.
+pub(crate) struct IoOutput<W: io::Write> {
+    writer: W,
+    buffer: String,
+}
+impl<W: io::Write> IoOutput<W> {
+    pub fn new(writer: W) -> IoOutput<W> {
+        IoOutput {
+            writer,
+            buffer: String::with_capacity(WRITE_BUFFER_SIZE),
+        }
+    }
+
+    /// Write out whatever is left in the buffer.
+    pub fn finish(&mut self) -> Result<()> {
+        self.writer.write_all(self.buffer.as_bytes())?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+impl<W: io::Write> Output for IoOutput<W> {
+    fn buffer(&mut self) -> &mut String {
+        &mut self.buffer
+    }
+
+    fn after_write(&mut self) -> Result<()> {
+        if self.buffer.len() >= WRITE_BUFFER_SIZE {
+            self.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Output which renders into a `fmt::Write`.
+pub(crate) struct FmtOutput<W: fmt::Write> {
+    writer: W,
+    buffer: String,
+}
+impl<W: fmt::Write> FmtOutput<W> {
+    pub fn new(writer: W) -> FmtOutput<W> {
+        FmtOutput {
+            writer,
+            buffer: String::with_capacity(WRITE_BUFFER_SIZE),
+        }
+    }
+
+    /// Write out whatever is left in the buffer.
+    pub fn finish(&mut self) -> Result<()> {
+        self.writer
+            .write_str(&self.buffer)
+            .map_err(|err| Error::FmtWriteError { err })?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+impl<W: fmt::Write> Output for FmtOutput<W> {
+    fn buffer(&mut self) -> &mut String {
+        &mut self.buffer
+    }
+
+    fn after_write(&mut self) -> Result<()> {
+        if self.buffer.len() >= WRITE_BUFFER_SIZE {
+            self.finish()?;
+        }
+        Ok(())
+    }
+}
+
 /// Structure representing a parsed template. It holds the bytecode program for rendering the
 /// template as well as the length of the original template string, which is used as a guess to
 /// pre-size the output string buffer.
+///
+/// Templates which extend another template also hold the name of that template, and every template
+/// holds the range of instructions making up each of its overridable blocks. Templates which declare
+/// their parameters hold their names, so that named arguments can be checked against them. The
+/// macros which a template defines are part of its bytecode, and it also holds the names of the
+/// templates it imports macros from.
 pub(crate) struct Template<'template> {
     original_text: &'template str,
     instructions: Vec<Instruction<'template>>,
     template_len: usize,
+    extends: Option<&'template str>,
+    blocks: HashMap<&'template str, (usize, usize)>,
+    params: Option<Vec<&'template str>>,
+    macros: HashMap<&'template str, MacroDefinition<'template>>,
+    imports: Vec<&'template str>,
 }
 impl<'template> Template<'template> {
-    /// Create a Template from the given template string.
-    pub fn compile(text: &'template str) -> Result<Template> {
+    /// Create a Template from the given template string, which uses the given delimiters and
+    /// whitespace settings.
+    pub fn compile(
+        text: &'template str,
+        delimiters: &Delimiters,
+        trim_blocks: bool,
+        lstrip_blocks: bool,
+    ) -> Result<Template<'template>> {
+        let compiled = TemplateCompiler::new(text, delimiters)
+            .block_whitespace(trim_blocks, lstrip_blocks)
+            .compile()?;
         Ok(Template {
             original_text: text,
             template_len: text.len(),
-            instructions: TemplateCompiler::new(text).compile()?,
+            instructions: compiled.instructions,
+            extends: compiled.extends,
+            blocks: compiled.blocks,
+            params: compiled.params,
+            macros: compiled.macros,
+            imports: compiled.imports,
         })
     }
 
+    /// Add the top-level names which this template looks up in the context it is rendered with to
+    /// the given set.
+    pub fn root_names(&self, names: &mut RootNames<'template>) {
+        for instruction in &self.instructions {
+            match instruction {
+                Instruction::Value(operand)
+                | Instruction::Branch(operand, _, _)
+                | Instruction::Call(_, operand) => names.add_operand(operand),
+                Instruction::FormattedValue(operand, formatters)
+                | Instruction::PushLetContext(_, operand, formatters) => {
+                    names.add_operand(operand);
+                    for (_, arguments) in formatters {
+                        for argument in arguments {
+                            names.add_operand(argument);
+                        }
+                    }
+                }
+                Instruction::Compare(left, _, right, _, _) => {
+                    names.add_operand(left);
+                    names.add_operand(right);
+                }
+                Instruction::PushNamedContext(path, _)
+                | Instruction::PushIterationContext(path, _, _) => names.add_path(path),
+                Instruction::PushRangeContext(start, end, step, _) => {
+                    names.add_operand(start);
+                    names.add_operand(end);
+                    if let Some(step) = step {
+                        names.add_operand(step);
+                    }
+                }
+                Instruction::CallWithArguments(_, arguments) => {
+                    for (_, argument) in arguments {
+                        names.add_operand(argument);
+                    }
+                }
+                Instruction::Literal(_)
+                | Instruction::PopContext
+                | Instruction::Iterate(_, _)
+                | Instruction::Block(_, _)
+                | Instruction::Super(_)
+                | Instruction::Goto(_)
+                | Instruction::Include(_) => {}
+            }
+        }
+    }
+
     /// Render this template into a string and return it (or any error if one is encountered).
     pub fn render(
         &self,
-        context: &Value,
+        context: &dyn Lookup,
         template_registry: &HashMap<&str, Template>,
-        formatter_registry: &HashMap<&str, Box<ValueFormatter>>,
+        formatter_registry: &HashMap<&str, Formatter>,
         default_formatter: &ValueFormatter,
     ) -> Result<String> {
         // The length of the original template seems like a reasonable guess at the length of the
@@ -146,271 +689,2117 @@ impl<'template> Template<'template> {
         Ok(output)
     }
 
-    /// Render this template into a given string. Used for calling other templates.
+    /// Render this template into the given output.
     pub fn render_into(
         &self,
-        context: &Value,
+        context: &dyn Lookup,
         template_registry: &HashMap<&str, Template>,
-        formatter_registry: &HashMap<&str, Box<ValueFormatter>>,
+        formatter_registry: &HashMap<&str, Formatter>,
         default_formatter: &ValueFormatter,
-        output: &mut String,
+        output: &mut dyn Output,
     ) -> Result<()> {
-        let mut program_counter = 0;
-        let mut render_context = RenderContext {
-            original_text: self.original_text,
-            context_stack: vec![ContextElement::Object(context)],
+        let mut render = Render::new(
+            self,
+            ContextElement::Object(context),
+            template_registry,
+            formatter_registry,
+            default_formatter,
+        )?;
+        while !render.resume(output)? {}
+        Ok(())
+    }
+
+    /// Start rendering this template with the given context object. The render runs whenever it is
+    /// resumed, until the output asks it to pause.
+    #[cfg(feature = "async")]
+    pub fn start_render(
+        &'template self,
+        context: &'template dyn Lookup,
+        template_registry: &'template HashMap<&'template str, Template<'template>>,
+        formatter_registry: &'template HashMap<&'template str, Formatter>,
+        default_formatter: &'template ValueFormatter,
+    ) -> Result<Render<'template>> {
+        Render::new(
+            self,
+            ContextElement::Object(context),
+            template_registry,
+            formatter_registry,
+            default_formatter,
+        )
+    }
+
+    /// Start rendering this template like `start_render`, with a context object which the render
+    /// takes ownership of.
+    #[cfg(feature = "async")]
+    pub fn start_owned_render(
+        &'template self,
+        context: Box<dyn Lookup + 'template>,
+        template_registry: &'template HashMap<&'template str, Template<'template>>,
+        formatter_registry: &'template HashMap<&'template str, Formatter>,
+        default_formatter: &'template ValueFormatter,
+    ) -> Result<Render<'template>> {
+        Render::new(
+            self,
+            ContextElement::OwnedObject(context),
+            template_registry,
+            formatter_registry,
+            default_formatter,
+        )
+    }
+
+    /// Make the frame which renders this whole template. A template which extends another is
+    /// rendered by running the root of its chain of parent templates, which renders the blocks that
+    /// the others override.
+    fn template_frame(
+        &'template self,
+        template_registry: &'template HashMap<&str, Template<'template>>,
+        kind: FrameKind<'template>,
+    ) -> Result<Frame<'template>> {
+        let chain: Rc<[&Template]> = if self.extends.is_none() {
+            Rc::new([self])
+        } else {
+            Rc::from(self.inheritance_chain(template_registry)?)
         };
+        let root = chain.len() - 1;
+        let template = chain[root];
+        Ok(Frame {
+            template,
+            program_counter: 0,
+            end: template.instructions.len(),
+            level: root,
+            chain,
+            kind,
+        })
+    }
 
-        while program_counter < self.instructions.len() {
-            match &self.instructions[program_counter] {
+    /// Find the chain of templates which this template inherits from, starting with this template
+    /// and ending with the root template, which doesn't extend any other.
+    fn inheritance_chain<'a>(
+        &'a self,
+        template_registry: &'a HashMap<&str, Template<'a>>,
+    ) -> Result<Vec<&'a Template<'a>>> {
+        let mut chain: Vec<&Template> = vec![self];
+        let mut current: &Template = self;
+        while let Some(parent_name) = current.extends {
+            let parent = match template_registry.get(parent_name) {
+                Some(parent) => parent,
+                None => return Err(unknown_parent_template(current.original_text, parent_name)),
+            };
+            if chain.iter().any(|template| ptr::eq(*template, parent)) {
+                return Err(inheritance_cycle_error(current.original_text, parent_name));
+            }
+            chain.push(parent);
+            current = parent;
+        }
+        Ok(chain)
+    }
+
+    /// Run the instructions of the given frame of this template, until the frame is done, another
+    /// frame has to be run first or the output asks to pause.
+    fn run(
+        &'template self,
+        frame: &mut Frame<'template>,
+        environment: &RenderEnvironment<'template>,
+        render_context: &mut RenderContext<'template, 'template>,
+        output: &mut dyn Output,
+    ) -> Result<Step<'template>> {
+        let template_registry = environment.template_registry;
+        let formatter_registry = environment.formatter_registry;
+        let default_formatter = environment.default_formatter;
+        let Frame {
+            ref mut program_counter,
+            end,
+            level,
+            ref chain,
+            ..
+        } = *frame;
+        while *program_counter < end {
+            match &self.instructions[*program_counter] {
                 Instruction::Literal(text) => {
-                    output.push_str(text);
-                    program_counter += 1;
-                }
-                Instruction::Value(path) => {
-                    let first = path.first().unwrap();
-                    if first.starts_with('@') {
-                        // Currently we just hard-code the special @-keywords and have special
-                        // lookup functions to use them because there are lifetime complexities with
-                        // looking up values that don't live for as long as the given context object.
-                        let first: &str = &*first;
-                        match first {
-                            "@index" => {
-                                write!(output, "{}", render_context.lookup_index()?.0).unwrap()
-                            }
-                            "@first" => {
-                                write!(output, "{}", render_context.lookup_index()?.0 == 0).unwrap()
-                            }
-                            "@last" => {
-                                let (index, length) = render_context.lookup_index()?;
-                                write!(output, "{}", index == length - 1).unwrap()
-                            }
-                            "@root" => {
-                                let value_to_render = render_context.lookup_root()?;
-                                default_formatter(value_to_render, output)?;
-                            }
-                            _ => panic!(), // This should have been caught by the parser.
-                        }
-                    } else {
-                        let value_to_render = render_context.lookup(path)?;
-                        default_formatter(value_to_render, output)?;
+                    output.buffer().push_str(text);
+                    *program_counter += 1;
+                    if output.after_literal()? {
+                        return Ok(Step::Pause);
                     }
-                    program_counter += 1;
                 }
-                Instruction::FormattedValue(path, name) => {
-                    // The @ keywords aren't supported for formatted values. Should they be?
-                    let value_to_render = render_context.lookup(path)?;
-                    match formatter_registry.get(name) {
-                        Some(formatter) => {
-                            let formatter_result = formatter(value_to_render, output);
-                            if let Err(err) = formatter_result {
-                                return Err(called_formatter_error(self.original_text, name, err));
-                            }
-                        }
-                        None => return Err(unknown_formatter(self.original_text, name)),
+                Instruction::Value(operand) => {
+                    let value_to_render = render_context.lookup_value(operand)?;
+                    default_formatter(&value_to_render, output.buffer())?;
+                    output.after_write()?;
+                    *program_counter += 1;
+                }
+                Instruction::FormattedValue(operand, formatters) => {
+                    let value_to_render = render_context.lookup_value(operand)?;
+                    let transformed = self.render_formatted(
+                        &value_to_render,
+                        formatters,
+                        render_context,
+                        formatter_registry,
+                        output.buffer(),
+                    )?;
+                    if let Some(value) = transformed {
+                        default_formatter(&value, output.buffer())?;
                     }
-                    program_counter += 1;
+                    output.after_write()?;
+                    *program_counter += 1;
                 }
-                Instruction::Branch(path, negate, target) => {
-                    let first = path.first().unwrap();
-                    let mut truthy = if first.starts_with('@') {
-                        let first: &str = &*first;
-                        match &*first {
-                            "@index" => render_context.lookup_index()?.0 != 0,
-                            "@first" => render_context.lookup_index()?.0 == 0,
-                            "@last" => {
-                                let (index, length) = render_context.lookup_index()?;
-                                index == (length - 1)
-                            }
-                            "@root" => self.value_is_truthy(render_context.lookup_root()?, path)?,
-                            other => panic!("Unknown keyword {}", other), // This should have been caught by the parser.
-                        }
-                    } else {
-                        let value_to_render = render_context.lookup(path)?;
-                        self.value_is_truthy(value_to_render, path)?
-                    };
+                Instruction::Branch(operand, negate, target) => {
+                    let value_to_check = render_context.lookup_value(operand)?;
+                    let mut truthy = self.value_is_truthy(&value_to_check, operand)?;
                     if *negate {
                         truthy = !truthy;
                     }
 
                     if truthy {
-                        program_counter = *target;
+                        *program_counter = *target;
+                    } else {
+                        *program_counter += 1;
+                    }
+                }
+                Instruction::Compare(left, comparison, right, negate, target) => {
+                    let left_value = render_context.lookup_value(left)?;
+                    let right_value = render_context.lookup_value(right)?;
+                    let mut holds = self.compare(&left_value, *comparison, &right_value, left)?;
+                    if *negate {
+                        holds = !holds;
+                    }
+
+                    if holds {
+                        *program_counter = *target;
                     } else {
-                        program_counter += 1;
+                        *program_counter += 1;
                     }
                 }
                 Instruction::PushNamedContext(path, name) => {
-                    let context_value = render_context.lookup(path)?;
+                    let context_value = render_context.lookup_path_for_stack(path)?;
                     render_context
                         .context_stack
                         .push(ContextElement::Named(name, context_value));
-                    program_counter += 1;
+                    *program_counter += 1;
                 }
-                Instruction::PushIterationContext(path, name) => {
+                Instruction::PushLetContext(name, operand, formatters) => {
+                    let mut context_value = render_context.lookup_value_for_stack(operand)?;
+                    if !formatters.is_empty() {
+                        let mut formatted = String::new();
+                        let transformed = self.render_formatted(
+                            &context_value,
+                            formatters,
+                            render_context,
+                            formatter_registry,
+                            &mut formatted,
+                        )?;
+                        context_value = Cow::Owned(match transformed {
+                            Some(value) => value,
+                            None => Value::String(formatted),
+                        });
+                    }
+                    render_context
+                        .context_stack
+                        .push(ContextElement::Named(name, context_value));
+                    *program_counter += 1;
+                }
+                Instruction::PushIterationContext(path, key_name, name) => {
                     // We push a context with an invalid index and no value and then wait for the
                     // following Iterate instruction to set the index and value properly.
-                    let first = path.first().unwrap();
-                    let context_value = match first {
-                        PathStep::Name("@root") => render_context.lookup_root()?,
-                        PathStep::Name(other) if other.starts_with('@') => {
-                            return Err(not_iterable_error(self.original_text, path))
+                    let (length, items) = match render_context.lookup_items(path)? {
+                        Some((_, Items::Array(_)))
+                        | Some((_, Items::OwnedArray(_)))
+                        | Some((_, Items::StackArray(..)))
+                            if key_name.is_some() =>
+                        {
+                            return Err(not_object_error(self.original_text, path))
                         }
-                        _ => render_context.lookup(path)?,
+                        Some(items) => items,
+                        None => return Err(not_iterable_error(self.original_text, path)),
+                    };
+                    render_context.context_stack.push(ContextElement::Iteration(
+                        IterationContext {
+                            name,
+                            key_name: *key_name,
+                            value: Cow::Borrowed(&Value::Null),
+                            key: None,
+                            index: ::std::usize::MAX,
+                            length,
+                            items,
+                        },
+                    ));
+                    *program_counter += 1;
+                }
+                Instruction::PushRangeContext(start, end, step, name) => {
+                    let start_value = self.lookup_range_bound(render_context, start)?;
+                    let end_value = self.lookup_range_bound(render_context, end)?;
+                    let step = match step {
+                        Some(step) => match self.lookup_range_bound(render_context, step)? {
+                            0 => {
+                                return Err(invalid_range_error(
+                                    self.original_text,
+                                    operand_location(step),
+                                    "The step of a range cannot be zero.".to_string(),
+                                ))
+                            }
+                            step => step,
+                        },
+                        None => 1,
                     };
-                    match context_value {
-                        Value::Array(ref arr) => {
-                            render_context.context_stack.push(ContextElement::Iteration(
-                                name,
-                                &Value::Null,
-                                ::std::usize::MAX,
-                                arr.len(),
-                                arr.iter(),
+                    let range = match Range::new(start_value, end_value, step) {
+                        Some(range) => range,
+                        None => {
+                            return Err(invalid_range_error(
+                                self.original_text,
+                                operand_location(end),
+                                "The range is too long to iterate over.".to_string(),
                             ))
                         }
-                        _ => return Err(not_iterable_error(self.original_text, path)),
                     };
-                    program_counter += 1;
+                    render_context.context_stack.push(ContextElement::Iteration(
+                        IterationContext {
+                            name,
+                            key_name: None,
+                            value: Cow::Borrowed(&Value::Null),
+                            key: None,
+                            index: ::std::usize::MAX,
+                            length: range.len(),
+                            items: Items::Range(range),
+                        },
+                    ));
+                    *program_counter += 1;
                 }
                 Instruction::PopContext => {
                     render_context.context_stack.pop();
-                    program_counter += 1;
+                    *program_counter += 1;
                 }
+                Instruction::Block(name, block_end) => match find_block(chain, name, 0) {
+                    Some(definer) if definer < level => {
+                        *program_counter = *block_end;
+                        return Ok(Step::Enter(block_frame(chain, definer, name), None));
+                    }
+                    _ => *program_counter += 1,
+                },
+                Instruction::Super(name) => match find_block(chain, name, level + 1) {
+                    Some(definer) => {
+                        *program_counter += 1;
+                        return Ok(Step::Enter(block_frame(chain, definer, name), None));
+                    }
+                    None => return Err(no_parent_block_error(self.original_text, name)),
+                },
                 Instruction::Goto(target) => {
-                    program_counter = *target;
+                    *program_counter = *target;
                 }
-                Instruction::Iterate(target) => {
+                Instruction::Iterate(target, empty_target) => {
                     match render_context.context_stack.last_mut() {
-                        Some(ContextElement::Iteration(_, val, index, _, iter)) => {
-                            match iter.next() {
-                                Some(new_val) => {
-                                    *val = new_val;
+                        Some(ContextElement::Iteration(iteration)) => {
+                            let next = match iteration.items {
+                                Items::Array(ref mut iter) => {
+                                    iter.next().map(|value| (None, Cow::Borrowed(value)))
+                                }
+                                Items::Object(ref mut iter) => iter.next().map(|(key, value)| {
+                                    (Some(Cow::Borrowed(key.as_str())), Cow::Borrowed(value))
+                                }),
+                                Items::Range(ref mut iter) => iter
+                                    .next()
+                                    .map(|number| (None, Cow::Owned(Value::from(number)))),
+                                // Values owned by the stack are looked up by index, so only the key
+                                // has to be produced.
+                                Items::OwnedArray(_) | Items::StackArray(..) => {
+                                    if iteration.index.wrapping_add(1) < iteration.length {
+                                        Some((None, Cow::Borrowed(&Value::Null)))
+                                    } else {
+                                        None
+                                    }
+                                }
+                                Items::OwnedObject(ref mut keys, _) => keys.next().map(|key| {
+                                    (Some(Cow::Owned(key)), Cow::Borrowed(&Value::Null))
+                                }),
+                            };
+                            match next {
+                                Some((key, value)) => {
+                                    iteration.key = key;
+                                    iteration.value = value;
                                     // On the first iteration, this will be usize::MAX so it will
                                     // wrap around to zero.
-                                    *index = index.wrapping_add(1);
-                                    program_counter += 1;
+                                    iteration.index = iteration.index.wrapping_add(1);
+                                    *program_counter += 1;
+                                }
+                                None if iteration.index == ::std::usize::MAX => {
+                                    *program_counter = *empty_target;
                                 }
                                 None => {
-                                    program_counter = *target;
+                                    *program_counter = *target;
                                 }
                             }
                         }
                         _ => panic!("Malformed program."),
                     };
                 }
-                Instruction::Call(template_name, path) => {
-                    let context_value = render_context.lookup(path)?;
-                    match template_registry.get(template_name) {
-                        Some(templ) => {
-                            let called_templ_result = templ.render_into(
-                                context_value,
-                                template_registry,
-                                formatter_registry,
-                                default_formatter,
-                                output,
-                            );
-                            if let Err(err) = called_templ_result {
-                                return Err(called_template_error(
-                                    self.original_text,
-                                    template_name,
-                                    err,
-                                ));
-                            }
-                        }
-                        None => return Err(unknown_template(self.original_text, template_name)),
+                Instruction::Call(template_name, operand) => {
+                    let context_value = render_context.lookup_value_for_stack(operand)?;
+                    let callee = self.call_frame(template_name, None, template_registry)?;
+                    *program_counter += 1;
+                    return Ok(Step::Enter(callee, Some(context_value)));
+                }
+                Instruction::CallWithArguments(template_name, arguments) => {
+                    let mut context_map = Map::new();
+                    for (name, operand) in arguments {
+                        let value = render_context.lookup_value(operand)?;
+                        context_map.insert(name.to_string(), value.into_owned());
                     }
-                    program_counter += 1;
+                    let callee =
+                        self.call_frame(template_name, Some(arguments), template_registry)?;
+                    *program_counter += 1;
+                    let context_value = Cow::Owned(Value::Object(context_map));
+                    return Ok(Step::Enter(callee, Some(context_value)));
                 }
+                Instruction::Include(template_name) => match template_registry.get(template_name) {
+                    Some(templ) => {
+                        let kind = FrameKind::Include {
+                            caller_text: self.original_text,
+                            name: template_name,
+                            stack_depth: render_context.context_stack.len(),
+                        };
+                        let included =
+                            templ
+                                .template_frame(template_registry, kind)
+                                .map_err(|err| {
+                                    called_template_error(self.original_text, template_name, err)
+                                })?;
+                        *program_counter += 1;
+                        return Ok(Step::Enter(included, None));
+                    }
+                    None => return Err(unknown_template(self.original_text, template_name)),
+                },
             }
         }
-        Ok(())
+        Ok(Step::Return)
     }
 
-    fn value_is_truthy(&self, value: &Value, path: PathSlice) -> Result<bool> {
-        let truthy = match value {
-            Value::Null => false,
-            Value::Bool(b) => *b,
-            Value::Number(n) => match n.as_f64() {
-                Some(float) => float != 0.0,
-                None => {
-                    return Err(truthiness_error(self.original_text, path));
-                }
+    /// Make the frame which renders the named macro or template. Macros defined or imported by
+    /// this template take precedence over registered templates. When the call passes named
+    /// arguments, they are checked against the parameters of the callee.
+    fn call_frame(
+        &'template self,
+        template_name: &'template str,
+        arguments: Option<&[NamedArgument<'template>]>,
+        template_registry: &'template HashMap<&str, Template<'template>>,
+    ) -> Result<Frame<'template>> {
+        let (templ, definition) = match self.find_macro(template_name, template_registry)? {
+            Some((templ, definition)) => (templ, Some(definition)),
+            None => match template_registry.get(template_name) {
+                Some(templ) => (templ, None),
+                None => return Err(unknown_template(self.original_text, template_name)),
             },
-            Value::String(s) => !s.is_empty(),
-            Value::Array(arr) => !arr.is_empty(),
-            Value::Object(_) => true,
         };
-        Ok(truthy)
+        let params = match definition {
+            Some(definition) => Some(&definition.params),
+            None => templ.params.as_ref(),
+        };
+        if let (Some(params), Some(arguments)) = (params, arguments) {
+            self.check_arguments(template_name, params, arguments)?;
+        }
+        let kind = FrameKind::Call {
+            caller_text: self.original_text,
+            name: template_name,
+        };
+        match definition {
+            Some(definition) => Ok(Frame {
+                template: templ,
+                program_counter: definition.start,
+                end: definition.end,
+                level: 0,
+                chain: Rc::new([templ]),
+                kind,
+            }),
+            None => templ
+                .template_frame(template_registry, kind)
+                .map_err(|err| called_template_error(self.original_text, template_name, err)),
+        }
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-    use compiler::TemplateCompiler;
 
-    fn compile(text: &'static str) -> Template<'static> {
-        Template {
-            original_text: text,
-            template_len: text.len(),
-            instructions: TemplateCompiler::new(text).compile().unwrap(),
+    /// Find the macro with the given name, either defined by this template or by one of the
+    /// templates it imports, along with the template which defines it.
+    fn find_macro(
+        &'template self,
+        name: &str,
+        template_registry: &'template HashMap<&str, Template<'template>>,
+    ) -> Result<
+        Option<(
+            &'template Template<'template>,
+            &'template MacroDefinition<'template>,
+        )>,
+    > {
+        if let Some(definition) = self.macros.get(name) {
+            return Ok(Some((self, definition)));
+        }
+        for import in &self.imports {
+            match template_registry.get(import) {
+                Some(templ) => {
+                    if let Some(definition) = templ.macros.get(name) {
+                        return Ok(Some((templ, definition)));
+                    }
+                }
+                None => return Err(unknown_import_template(self.original_text, import)),
+            }
         }
+        Ok(None)
     }
 
-    #[derive(Serialize)]
-    struct NestedContext {
-        value: usize,
+    /// Check that the named arguments passed to a template or macro match its parameters exactly.
+    fn check_arguments(
+        &self,
+        template_name: &str,
+        params: &[&str],
+        arguments: &[NamedArgument],
+    ) -> Result<()> {
+        for (name, _) in arguments {
+            if !params.contains(name) {
+                return Err(unexpected_argument_error(
+                    self.original_text,
+                    template_name,
+                    name,
+                ));
+            }
+        }
+        for param in params {
+            if !arguments.iter().any(|(name, _)| name == param) {
+                return Err(missing_argument_error(
+                    self.original_text,
+                    template_name,
+                    param,
+                ));
+            }
+        }
+        Ok(())
     }
 
-    #[derive(Serialize)]
-    struct TestContext {
-        number: usize,
-        string: &'static str,
-        boolean: bool,
-        null: Option<usize>,
-        array: Vec<usize>,
+    /// Pass a value through a chain of formatters and render the result into the output. Every
+    /// formatter but the last renders into a temporary string which is passed on to the next one
+    /// as a string value, while transformers pass their result on directly. If the chain ends with
+    /// a transformer, nothing is rendered and its result is returned instead.
+    fn render_formatted(
+        &self,
+        value: &Value,
+        formatters: &[FormatterCall],
+        render_context: &RenderContext,
+        formatter_registry: &HashMap<&str, Formatter>,
+        output: &mut String,
+    ) -> Result<Option<Value>> {
+        let mut value = Cow::Borrowed(value);
+        let mut transformed = false;
+        for (i, (name, arguments)) in formatters.iter().enumerate() {
+            let is_last = i == formatters.len() - 1;
+            let formatter = match formatter_registry.get(name) {
+                Some(formatter) => formatter,
+                None => return Err(unknown_formatter(self.original_text, name)),
+            };
+            let argument_values = arguments
+                .iter()
+                .map(|argument| render_context.lookup_value(argument))
+                .collect::<Result<Vec<_>>>()?;
+            let argument_values = argument_values
+                .iter()
+                .map(|argument| &**argument)
+                .collect::<Vec<_>>();
+
+            let formatter_result =
+                check_argument_count(formatter, argument_values.len()).and_then(|_| {
+                    if let Formatter::Transformer(transformer) = formatter {
+                        value = Cow::Owned(transformer(&value)?);
+                        transformed = is_last;
+                        return Ok(());
+                    }
+
+                    let mut formatted = String::new();
+                    {
+                        let target = if is_last {
+                            &mut *output
+                        } else {
+                            &mut formatted
+                        };
+                        match formatter {
+                            Formatter::Value(formatter) => formatter(&value, target)?,
+                            Formatter::Arguments(_, formatter) => {
+                                formatter(&value, &argument_values, target)?
+                            }
+                            Formatter::Transformer(_) => unreachable!(),
+                        }
+                    }
+                    if !is_last {
+                        value = Cow::Owned(Value::String(formatted));
+                    }
+                    Ok(())
+                });
+            if let Err(err) = formatter_result {
+                return Err(called_formatter_error(self.original_text, name, err));
+            }
+        }
+        if transformed {
+            Ok(Some(value.into_owned()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Look up the start, end or step of a range, which must be an integer.
+    fn lookup_range_bound(&self, render_context: &RenderContext, operand: &Operand) -> Result<i64> {
+        let value = render_context.lookup_value(operand)?;
+        match value.as_i64() {
+            Some(number) => Ok(number),
+            None => Err(invalid_range_error(
+                self.original_text,
+                operand_location(operand),
+                format!("Expected an integer for the range but found {}.", value),
+            )),
+        }
+    }
+
+    /// Evaluate a comparison between two values. Equality can be tested between any two values,
+    /// and values of different types are never equal. Ordering is only defined between two
+    /// numbers, two strings or two booleans; anything else is an error, reported at the location
+    /// of the left-hand operand.
+    fn compare(
+        &self,
+        left: &Value,
+        comparison: Comparison,
+        right: &Value,
+        location: &Operand,
+    ) -> Result<bool> {
+        let ordering = compare_values(left, right);
+        let holds = match comparison {
+            Comparison::Equal => values_equal(left, right, ordering),
+            Comparison::NotEqual => !values_equal(left, right, ordering),
+            _ => match ordering {
+                Some(ordering) => match comparison {
+                    Comparison::Less => ordering == Ordering::Less,
+                    Comparison::LessOrEqual => ordering != Ordering::Greater,
+                    Comparison::Greater => ordering == Ordering::Greater,
+                    Comparison::GreaterOrEqual => ordering != Ordering::Less,
+                    _ => unreachable!(),
+                },
+                None => {
+                    return Err(incomparable_error(
+                        self.original_text,
+                        operand_location(location),
+                        comparison.as_str(),
+                        left,
+                        right,
+                    ))
+                }
+            },
+        };
+        Ok(holds)
+    }
+
+    fn value_is_truthy(&self, value: &Value, operand: &Operand) -> Result<bool> {
+        let truthy = match value {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Number(n) => match n.as_f64() {
+                Some(float) => float != 0.0,
+                None => {
+                    return Err(truthiness_error(self.original_text, operand));
+                }
+            },
+            Value::String(s) => !s.is_empty(),
+            Value::Array(arr) => !arr.is_empty(),
+            Value::Object(_) => true,
+        };
+        Ok(truthy)
+    }
+}
+
+/// The registries which are used throughout the rendering of a template.
+struct RenderEnvironment<'a> {
+    template_registry: &'a HashMap<&'a str, Template<'a>>,
+    formatter_registry: &'a HashMap<&'a str, Formatter>,
+    default_formatter: &'a ValueFormatter,
+}
+
+/// A range of instructions which the interpreter runs. Blocks, included templates and called
+/// templates are rendered by running a frame of their own on top of the one which renders them,
+/// rather than by the interpreter calling itself, so that rendering can pause anywhere.
+struct Frame<'a> {
+    template: &'a Template<'a>,
+    program_counter: usize,
+    end: usize,
+    /// The position of the template in the inheritance chain.
+    level: usize,
+    /// The template being rendered, followed by the templates it inherits from.
+    chain: Rc<[&'a Template<'a>]>,
+    kind: FrameKind<'a>,
+}
+
+/// What has to be done when a frame is done, or fails.
+enum FrameKind<'a> {
+    /// The template being rendered, or a block. Blocks share the context stack of the template
+    /// which renders them.
+    Plain,
+    /// A template included by the template with the given text, which shares its context stack.
+    /// Anything left on the stack by the included template, such as its top-level let tags, is
+    /// removed once it is done so that it doesn't leak into the including template.
+    Include {
+        caller_text: &'a str,
+        name: &'a str,
+        stack_depth: usize,
+    },
+    /// A template or macro called by the template with the given text, which has a context stack
+    /// of its own.
+    Call { caller_text: &'a str, name: &'a str },
+}
+
+/// Find the position of the first template in the inheritance chain, starting from the given
+/// position, which defines a block with the given name.
+fn find_block(chain: &[&Template], name: &str, from_level: usize) -> Option<usize> {
+    (from_level..chain.len()).find(|level| chain[*level].blocks.contains_key(name))
+}
+
+/// Make the frame which renders the block with the given name as it is defined by the template at
+/// the given position in the inheritance chain.
+fn block_frame<'a>(chain: &Rc<[&'a Template<'a>]>, level: usize, name: &str) -> Frame<'a> {
+    let template = chain[level];
+    let (start, end) = template.blocks[name];
+    Frame {
+        template,
+        program_counter: start,
+        end,
+        level,
+        chain: chain.clone(),
+        kind: FrameKind::Plain,
+    }
+}
+
+/// Why the interpreter stopped running the instructions of a frame.
+enum Step<'a> {
+    /// The frame is done.
+    Return,
+    /// The output asked to pause after a literal.
+    Pause,
+    /// The given frame has to be run before this one carries on. Called templates come with the
+    /// value which they are called with.
+    Enter(Frame<'a>, Option<Cow<'a, Value>>),
+}
+
+/// A template which is being rendered. The render keeps the stack of frames which the interpreter
+/// is running, so that it can pause when the output asks it to and carry on later.
+pub(crate) struct Render<'a> {
+    frames: Vec<Frame<'a>>,
+    /// The context stacks of the template being rendered and of the templates it calls.
+    contexts: Vec<RenderContext<'a, 'a>>,
+    environment: RenderEnvironment<'a>,
+}
+impl<'a> Render<'a> {
+    /// Start rendering the given template with the given object at the bottom of its context
+    /// stack.
+    fn new(
+        template: &'a Template<'a>,
+        context: ContextElement<'a, 'a>,
+        template_registry: &'a HashMap<&'a str, Template<'a>>,
+        formatter_registry: &'a HashMap<&'a str, Formatter>,
+        default_formatter: &'a ValueFormatter,
+    ) -> Result<Render<'a>> {
+        let frame = template.template_frame(template_registry, FrameKind::Plain)?;
+        let mut render = Render {
+            frames: vec![],
+            contexts: vec![RenderContext {
+                original_text: template.original_text,
+                context_stack: vec![context],
+            }],
+            environment: RenderEnvironment {
+                template_registry,
+                formatter_registry,
+                default_formatter,
+            },
+        };
+        render.enter(frame);
+        Ok(render)
+    }
+
+    /// Render until the template is done, which returns true, or until the output asks to pause,
+    /// which returns false. Outputs which render into a string or a blocking writer never pause.
+    /// The render cannot carry on after an error.
+    pub fn resume(&mut self, output: &mut dyn Output) -> Result<bool> {
+        loop {
+            let step = match self.frames.last_mut() {
+                Some(frame) => frame.template.run(
+                    frame,
+                    &self.environment,
+                    self.contexts.last_mut().unwrap(),
+                    output,
+                ),
+                None => return Ok(true),
+            };
+            match step {
+                Ok(Step::Return) => self.leave(),
+                Ok(Step::Pause) => return Ok(false),
+                Ok(Step::Enter(frame, context)) => {
+                    if let Some(context) = context {
+                        self.contexts.push(RenderContext {
+                            original_text: frame.template.original_text,
+                            context_stack: vec![ContextElement::object(context)],
+                        });
+                    }
+                    self.enter(frame);
+                }
+                Err(err) => return Err(self.unwind(err)),
+            }
+        }
+    }
+
+    fn enter(&mut self, frame: Frame<'a>) {
+        self.contexts.last_mut().unwrap().original_text = frame.template.original_text;
+        self.frames.push(frame);
+    }
+
+    fn leave(&mut self) {
+        match self.frames.pop().unwrap().kind {
+            FrameKind::Plain => {}
+            FrameKind::Include { stack_depth, .. } => self
+                .contexts
+                .last_mut()
+                .unwrap()
+                .context_stack
+                .truncate(stack_depth),
+            FrameKind::Call { .. } => {
+                self.contexts.pop();
+            }
+        }
+        if let Some(frame) = self.frames.last() {
+            self.contexts.last_mut().unwrap().original_text = frame.template.original_text;
+        }
+    }
+
+    /// Drop all of the frames after an error, wrapping it in an error for each included or called
+    /// template that it happened in.
+    fn unwind(&mut self, mut err: Error) -> Error {
+        while let Some(frame) = self.frames.pop() {
+            match frame.kind {
+                FrameKind::Plain => {}
+                FrameKind::Include {
+                    caller_text, name, ..
+                }
+                | FrameKind::Call { caller_text, name } => {
+                    err = called_template_error(caller_text, name, err);
+                }
+            }
+        }
+        err
+    }
+}
+
+/// Check that a formatter accepts the given number of arguments. Formatters and transformers
+/// which were not registered with arguments do not accept any.
+fn check_argument_count(formatter: &Formatter, count: usize) -> Result<()> {
+    let (min, max) = match formatter {
+        Formatter::Arguments(range, _) => (*range.start(), *range.end()),
+        _ => (0, 0),
+    };
+    if count >= min && count <= max {
+        return Ok(());
+    }
+    let expected = if min == max {
+        format!("{}", min)
+    } else {
+        format!("between {} and {}", min, max)
+    };
+    Err(GenericError {
+        msg: format!("Expected {} argument(s) but found {}.", expected, count),
+    })
+}
+
+/// Order two values of the same primitive type. Returns None for nulls, arrays, objects and
+/// values of different types.
+fn compare_values(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => compare_numbers(l, r),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+        _ => None,
+    }
+}
+
+/// Order two numbers. Integers are compared exactly, and anything else is compared as a float.
+fn compare_numbers(left: &Number, right: &Number) -> Option<Ordering> {
+    if let (Some(l), Some(r)) = (left.as_i64(), right.as_i64()) {
+        return Some(l.cmp(&r));
+    }
+    if let (Some(l), Some(r)) = (left.as_u64(), right.as_u64()) {
+        return Some(l.cmp(&r));
+    }
+    left.as_f64()?.partial_cmp(&right.as_f64()?)
+}
+
+/// Check two values for equality, given their ordering as computed by compare_values. Numbers
+/// compare equal by value, so `1` and `1.0` are equal.
+fn values_equal(left: &Value, right: &Value, ordering: Option<Ordering>) -> bool {
+    match ordering {
+        Some(ordering) => ordering == Ordering::Equal,
+        None => left == right,
+    }
+}
+
+/// Find a piece of the template text to use as the location of errors involving an operand.
+fn operand_location<'template>(operand: &Operand<'template>) -> &'template str {
+    match operand {
+        Operand::Path(path) => match path[0] {
+            PathStep::Name(s) | PathStep::Index(s, _) => s,
+        },
+        Operand::Literal(text, _) => text,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lookup::Serialized;
+    use std::collections::BTreeMap;
+
+    fn compile(text: &'static str) -> Template<'static> {
+        Template::compile(text, &Delimiters::default(), false, false).unwrap()
+    }
+
+    #[derive(Serialize)]
+    struct NestedContext {
+        value: usize,
+    }
+
+    #[derive(Serialize)]
+    struct TestContext {
+        number: usize,
+        string: &'static str,
+        boolean: bool,
+        null: Option<usize>,
+        array: Vec<usize>,
+        empty_array: Vec<usize>,
+        object: BTreeMap<&'static str, usize>,
         nested: NestedContext,
         escapes: &'static str,
     }
 
-    fn context() -> Value {
-        let ctx = TestContext {
+    fn test_context() -> TestContext {
+        TestContext {
             number: 5,
             string: "test",
             boolean: true,
             null: None,
             array: vec![1, 2, 3],
+            empty_array: vec![],
+            object: vec![("b", 2), ("a", 1), ("c", 3)].into_iter().collect(),
             nested: NestedContext { value: 10 },
             escapes: "1:< 2:> 3:& 4:' 5:\"",
-        };
-        ::serde_json::to_value(&ctx).unwrap()
+        }
+    }
+
+    fn context() -> Value {
+        ::serde_json::to_value(test_context()).unwrap()
+    }
+
+    fn other_templates() -> HashMap<&'static str, Template<'static>> {
+        let mut map = HashMap::new();
+        map.insert("my_macro", compile("{value}"));
+        map
+    }
+
+    fn format(value: &Value, output: &mut String) -> Result<()> {
+        output.push('{');
+        ::format(value, output)?;
+        output.push('}');
+        Ok(())
+    }
+
+    fn formatters() -> HashMap<&'static str, Formatter> {
+        let mut map = HashMap::<&'static str, Formatter>::new();
+        map.insert("my_formatter", Formatter::Value(Box::new(format)));
+        map.insert(
+            "join",
+            Formatter::Arguments(
+                1..=2,
+                Box::new(|value: &Value, args: &[&Value], output: &mut String| {
+                    ::format(value, output)?;
+                    ::format(args[0], output)?;
+                    if let Some(arg) = args.get(1) {
+                        ::format(arg, output)?;
+                    }
+                    Ok(())
+                }),
+            ),
+        );
+        map.insert(
+            "double",
+            Formatter::Transformer(Box::new(|value: &Value| match value.as_u64() {
+                Some(n) => Ok(Value::from(n * 2)),
+                None => Err(GenericError {
+                    msg: "Expected a number".to_string(),
+                }),
+            })),
+        );
+        map
+    }
+
+    pub fn default_formatter() -> &'static ValueFormatter {
+        &::format
+    }
+
+    #[test]
+    fn test_literal() {
+        let template = compile("Hello!");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Hello!", &string);
+    }
+
+    #[test]
+    fn test_value() {
+        let template = compile("{ number }");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("5", &string);
+    }
+
+    #[test]
+    fn test_path() {
+        let template = compile("The number of the day is { nested.value }.");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("The number of the day is 10.", &string);
+    }
+
+    #[test]
+    fn test_if_taken() {
+        let template = compile("{{ if boolean }}Hello!{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Hello!", &string);
+    }
+
+    #[test]
+    fn test_if_untaken() {
+        let template = compile("{{ if null }}Hello!{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("", &string);
+    }
+
+    #[test]
+    fn test_if_else_taken() {
+        let template = compile("{{ if boolean }}Hello!{{ else }}Goodbye!{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Hello!", &string);
+    }
+
+    #[test]
+    fn test_if_else_untaken() {
+        let template = compile("{{ if null }}Hello!{{ else }}Goodbye!{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Goodbye!", &string);
+    }
+
+    #[test]
+    fn test_ifnot_taken() {
+        let template = compile("{{ if not boolean }}Hello!{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("", &string);
+    }
+
+    #[test]
+    fn test_ifnot_untaken() {
+        let template = compile("{{ if not null }}Hello!{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Hello!", &string);
+    }
+
+    #[test]
+    fn test_ifnot_else_taken() {
+        let template = compile("{{ if not boolean }}Hello!{{ else }}Goodbye!{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Goodbye!", &string);
+    }
+
+    #[test]
+    fn test_ifnot_else_untaken() {
+        let template = compile("{{ if not null }}Hello!{{ else }}Goodbye!{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Hello!", &string);
+    }
+
+    #[test]
+    fn test_nested_ifs() {
+        let template = compile(
+            "{{ if boolean }}Hi, {{ if null }}there!{{ else }}Hello!{{ endif }}{{ endif }}",
+        );
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Hi, Hello!", &string);
+    }
+
+    #[test]
+    fn test_elif_chain() {
+        let template = compile(
+            "{{ if null }}A{{ elif not boolean }}B{{ elif number }}C{{ else }}D{{ endif }}",
+        );
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("C", &string);
+    }
+
+    #[test]
+    fn test_elif_chain_falls_through_to_else() {
+        let template = compile("{{ if null }}A{{ else if not boolean }}B{{ else }}C{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("C", &string);
+    }
+
+    #[test]
+    fn test_elif_first_arm_taken() {
+        let template = compile("{{ if boolean }}A{{ elif number }}B{{ endif }}!");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("A!", &string);
+    }
+
+    #[test]
+    fn test_compare_strings() {
+        let template = compile(
+            r#"{{ if string == "test" }}A{{ endif }}{{ if string != "test" }}B{{ endif }}"#,
+        );
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("A", &string);
+    }
+
+    #[test]
+    fn test_compare_numbers() {
+        let template = compile("{{ if number > 4 }}A{{ endif }}{{ if number < 5 }}B{{ endif }}{{ if number <= nested.value }}C{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("AC", &string);
+    }
+
+    #[test]
+    fn test_compare_integer_and_float() {
+        let template =
+            compile("{{ if number == 5.0 }}A{{ endif }}{{ if number >= -1.5 }}B{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("AB", &string);
+    }
+
+    #[test]
+    fn test_compare_different_types_not_equal() {
+        let template = compile(
+            r#"{{ if number == "5" }}A{{ else }}B{{ endif }}{{ if null == null }}C{{ endif }}"#,
+        );
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("BC", &string);
+    }
+
+    #[test]
+    fn test_compare_loop_keywords() {
+        let template =
+            compile("{{ for a in array }}{{ if @index == 1 }}{ a }{{ endif }}{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("2", &string);
+    }
+
+    #[test]
+    fn test_compare_incomparable_types() {
+        let template = compile("\n{{ if string < number }}A{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let err = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap_err();
+        if let RenderError { line, column, .. } = err {
+            assert_eq!(2, line);
+            assert_eq!(6, column);
+        } else {
+            panic!("Should have returned a render error");
+        }
+    }
+
+    #[test]
+    fn test_and_or() {
+        let template = compile("{{ if boolean and number }}A{{ endif }}{{ if boolean and null }}B{{ endif }}{{ if null or number }}C{{ endif }}{{ if null or not boolean }}D{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("AC", &string);
+    }
+
+    #[test]
+    fn test_parenthesized_condition() {
+        let template = compile(
+            "{{ if not (null or number > 5) and (string == \"test\") }}A{{ else }}B{{ endif }}",
+        );
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("A", &string);
+    }
+
+    #[test]
+    fn test_short_circuit_skips_lookup() {
+        let template = compile("{{ if null and missing.value }}A{{ endif }}{{ if boolean or missing.value }}B{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("B", &string);
+    }
+
+    #[test]
+    fn test_no_short_circuit_when_needed() {
+        let template = compile("{{ if boolean and missing.value }}A{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_with() {
+        let template = compile("{{ with nested as n }}{ n.value } { number }{{endwith}}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("10 5", &string);
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let template = compile("{{ for a in array }}{ a }{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("123", &string);
+    }
+
+    #[test]
+    fn test_for_loop_else_not_taken() {
+        let template = compile("{{ for a in array }}{ a }{{ else }}Empty{{ endfor }}!");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("123!", &string);
+    }
+
+    #[test]
+    fn test_for_loop_else_taken() {
+        let template = compile(
+            "{{ for number in empty_array }}{ number }{{ else }}Empty { number }{{ endfor }}!",
+        );
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Empty 5!", &string);
+    }
+
+    #[test]
+    fn test_nested_for_loop_else() {
+        let template = compile("{{ for a in array }}{{ for b in empty_array }}{ b }{{ else }}{ a }{{ endfor }}{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("123", &string);
+    }
+
+    #[test]
+    fn test_for_loop_index() {
+        let template = compile("{{ for a in array }}{ @index }{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("012", &string);
+    }
+
+    #[test]
+    fn test_for_loop_first() {
+        let template =
+            compile("{{ for a in array }}{{if @first }}{ @index }{{ endif }}{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("0", &string);
+    }
+
+    #[test]
+    fn test_for_loop_last() {
+        let template =
+            compile("{{ for a in array }}{{ if @last}}{ @index }{{ endif }}{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("2", &string);
+    }
+
+    #[test]
+    fn test_for_loop_object() {
+        let template = compile("{{ for key, value in object }}{ key }={ value }{{ if not @last }},{{ endif }}{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("a=1,b=2,c=3", &string);
+    }
+
+    #[test]
+    fn test_for_loop_object_values() {
+        let template = compile("{{ for value in object }}{ @index }{ @key }{ value }{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("0a11b22c3", &string);
+    }
+
+    #[test]
+    fn test_for_loop_object_key() {
+        let template = compile(
+            r#"{{ for k, v in object }}{{ if k == "b" }}{{ with k as name }}{ name | my_formatter }{{ endwith }}{{ endif }}{{ endfor }}"#,
+        );
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("{b}", &string);
+    }
+
+    #[test]
+    fn test_for_loop_object_errors() {
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        for text in &[
+            "{{ for k, v in array }}{ v }{{ endfor }}",
+            "{{ for v in array }}{ @key }{{ endfor }}",
+            "{{ for k, v in number }}{ v }{{ endfor }}",
+        ] {
+            let template = compile(text);
+            template
+                .render(
+                    &context,
+                    &template_registry,
+                    &formatter_registry,
+                    &default_formatter(),
+                )
+                .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_for_loop_range() {
+        let template =
+            compile("{{ for i in range(0, 3) }}{ i }{{ if not @last }},{{ endif }}{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("0,1,2", &string);
+    }
+
+    #[test]
+    fn test_for_loop_range_step() {
+        let template = compile("{{ for i in range(1, number, 2) }}{ i }{{ endfor }} {{ for i in range(nested.value, 0, -4) }}{ i }{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("13 1062", &string);
+    }
+
+    #[test]
+    fn test_for_loop_empty_range() {
+        let template = compile("{{ for i in range(3, 3) }}{ i }{{ else }}Empty{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Empty", &string);
+    }
+
+    #[test]
+    fn test_for_loop_range_errors() {
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        for text in &[
+            "{{ for i in range(0, 3, 0) }}{ i }{{ endfor }}",
+            "{{ for i in range(0, string) }}{ i }{{ endfor }}",
+            "{{ for i in range(0.5, 3) }}{ i }{{ endfor }}",
+        ] {
+            let template = compile(text);
+            template
+                .render(
+                    &context,
+                    &template_registry,
+                    &formatter_registry,
+                    &default_formatter(),
+                )
+                .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_range_length() {
+        assert_eq!(5, Range::new(-3, 12, 3).unwrap().len());
+        assert_eq!(0, Range::new(3, -12, 3).unwrap().len());
+        let longest = Range::new(::std::i64::MIN, ::std::i64::MAX, 1);
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(::std::usize::MAX, longest.unwrap().len());
+        } else {
+            assert!(longest.is_none());
+        }
+    }
+
+    #[test]
+    fn test_for_loop_outer_index() {
+        let template = compile("{{ for row in array }}{{ for column in array }}{ row.@index }{ column.@index }{{ if not column.@last }},{{ endif }}{{ endfor }};{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("00,01,02;10,11,12;20,21,22;", &string);
+    }
+
+    #[test]
+    fn test_for_loop_outer_key() {
+        let template = compile(
+            r#"{{ for k, v in object }}{{ for a in array }}{{ if v.@first and a.@last }}{ k.@key }{ v.@key }{{ endif }}{{ endfor }}{{ endfor }}"#,
+        );
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("aa", &string);
+    }
+
+    #[test]
+    fn test_loop_keyword_on_non_loop_value() {
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        for text in &[
+            "{ number.@index }",
+            "{{ for a in array }}{{ with nested as a }}{ a.@index }{{ endwith }}{{ endfor }}",
+        ] {
+            let template = compile(text);
+            template
+                .render(
+                    &context,
+                    &template_registry,
+                    &formatter_registry,
+                    &default_formatter(),
+                )
+                .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_loop_keyword_outside_loop() {
+        let template = compile("{ @index1 }");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let error = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap_err();
+        match error {
+            GenericError { msg } => {
+                assert_eq!("Used @index1 outside of a foreach block.", &msg);
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_for_loop_counters() {
+        let template = compile("{{ for a in array }}{ @index1 } of { @length } ({ @revindex } left){{ if not @last }}, {{ endif }}{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("1 of 3 (2 left), 2 of 3 (1 left), 3 of 3 (0 left)", &string);
+    }
+
+    #[test]
+    fn test_for_loop_odd_even() {
+        let template = compile("{{ for a in array }}{{ if @even }}E{{ endif }}{{ if @odd }}O{{ endif }}{ @odd }{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("EfalseOtrueEfalse", &string);
+    }
+
+    #[test]
+    fn test_for_loop_keyword_comparison() {
+        let template = compile("{{ for a in array }}{{ if @index1 == @length }}{ a }{{ endif }}{{ if a.@revindex > 1 }}-{{ endif }}{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("-3", &string);
+    }
+
+    #[test]
+    fn test_for_loop_keyword_formatted() {
+        let template = compile(
+            "{{ for a in array }}{ @index1 | my_formatter }{ a.@even | my_formatter }{{ endfor }}",
+        );
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("{1}{true}{2}{false}{3}{true}", &string);
+    }
+
+    #[test]
+    fn test_formatted_keywords() {
+        let template =
+            compile("{{ for a in array }}{ @index | my_formatter }{ a | join(@last) }{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("{0}1false{1}2false{2}3true", &string);
+    }
+
+    #[test]
+    fn test_root_paths() {
+        let template = compile("{ @root.nested.value } {{ if @root.array.0 == 1 }}{ @root.string | my_formatter }{{ endif }} {{ for a in @root.array }}{ a }{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("10 {test} 123", &string);
+    }
+
+    #[test]
+    fn test_with_keywords() {
+        let template = compile("{{ with @root.nested as n }}{ n.value }{{ endwith }} {{ for a in array }}{{ with @index1 as i }}{ i }{{ endwith }}{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("10 123", &string);
+    }
+
+    #[test]
+    fn test_call_with_keywords() {
+        let template = compile("{{ call my_macro with @root.nested }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("10", &string);
+    }
+
+    #[test]
+    fn test_iterate_over_loop_keyword() {
+        let template = compile("{{ for a in array }}{{ for b in @index }}{{ endfor }}{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_let_literal() {
+        let template =
+            compile(r#"{{ let greeting = "hi" }}{ greeting } { greeting | my_formatter }"#);
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("hi {hi}", &string);
+    }
+
+    #[test]
+    fn test_let_path() {
+        let template =
+            compile("{{ let value = nested.value }}{{ set n = nested }}{ value }{ n.value }");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("1010", &string);
+    }
+
+    #[test]
+    fn test_let_formatted() {
+        let template = compile("{{ let n = number | my_formatter }}{ n }");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("{5}", &string);
+    }
+
+    #[test]
+    fn test_let_transformed() {
+        let template = compile("{{ let n = number | double }}{{ if n > 9 }}{ n }{{ endif }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("10", &string);
+    }
+
+    #[test]
+    fn test_let_scope() {
+        let template = compile("{{ if boolean }}{{ let number = 1 }}{ number }{{ endif }}{ number } {{ for a in array }}{{ let number = a }}{ number }{{ endfor }}{ number }");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("15 1235", &string);
+    }
+
+    #[test]
+    fn test_let_in_for_else() {
+        let template = compile("{{ for a in empty_array }}{{ let x = a }}{ x }{{ else }}{{ let x = string }}{ x }{{ endfor }}");
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("test", &string);
     }
 
-    fn other_templates() -> HashMap<&'static str, Template<'static>> {
-        let mut map = HashMap::new();
-        map.insert("my_macro", compile("{value}"));
+    fn inheritance_templates() -> HashMap<&'static str, Template<'static>> {
+        let mut map = other_templates();
+        map.insert(
+            "base",
+            compile("<{{ block title }}Base{{ endblock }}|{{ block body }}{{ for a in array }}{{ block item }}{ a }{{ endblock }}{{ endfor }}{{ endblock }}>"),
+        );
+        map.insert(
+            "child",
+            compile("{{ extends \"base\" }}Ignored{{ block title }}Child {{ super }}{{ endblock }}{{ block item }}[{ a }]{{ endblock }}"),
+        );
+        map.insert(
+            "grandchild",
+            compile("{{ extends \"child\" }}{{ block title }}Grandchild {{ super }}{{ endblock }}"),
+        );
+        map.insert(
+            "no_super",
+            compile("{{ block title }}{{ super }}{{ endblock }}"),
+        );
+        map.insert("cycle_a", compile("{{ extends \"cycle_b\" }}"));
+        map.insert("cycle_b", compile("{{ extends \"cycle_a\" }}"));
+        map.insert("orphan", compile("{{ extends \"missing\" }}"));
         map
     }
 
-    fn format(value: &Value, output: &mut String) -> Result<()> {
-        output.push_str("{");
-        ::format(value, output)?;
-        output.push_str("}");
-        Ok(())
+    #[test]
+    fn test_inheritance() {
+        let context = context();
+        let template_registry = inheritance_templates();
+        let formatter_registry = formatters();
+        for (name, expected) in &[
+            ("base", "<Base|123>"),
+            ("child", "<Child Base|[1][2][3]>"),
+            ("grandchild", "<Grandchild Child Base|[1][2][3]>"),
+        ] {
+            let string = template_registry[name]
+                .render(
+                    &context,
+                    &template_registry,
+                    &formatter_registry,
+                    &default_formatter(),
+                )
+                .unwrap();
+            assert_eq!(expected, &string);
+        }
     }
 
-    fn formatters() -> HashMap<&'static str, Box<ValueFormatter>> {
-        let mut map = HashMap::<&'static str, Box<ValueFormatter>>::new();
-        map.insert("my_formatter", Box::new(format));
-        map
+    #[test]
+    fn test_call_extending_template() {
+        let template = compile("{{ call grandchild with @root }}");
+        let context = context();
+        let template_registry = inheritance_templates();
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("<Grandchild Child Base|[1][2][3]>", &string);
     }
 
-    pub fn default_formatter() -> &'static ValueFormatter {
-        &::format
+    #[test]
+    fn test_inheritance_errors() {
+        let context = context();
+        let template_registry = inheritance_templates();
+        let formatter_registry = formatters();
+        for name in &["no_super", "cycle_a", "orphan"] {
+            template_registry[name]
+                .render(
+                    &context,
+                    &template_registry,
+                    &formatter_registry,
+                    &default_formatter(),
+                )
+                .unwrap_err();
+        }
     }
 
     #[test]
-    fn test_literal() {
-        let template = compile("Hello!");
+    fn test_whitespace_stripping_value() {
+        let template = compile("1  \n\t   {- number -}  \n   1");
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -422,12 +2811,12 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("Hello!", &string);
+        assert_eq!("151", &string);
     }
 
     #[test]
-    fn test_value() {
-        let template = compile("{ number }");
+    fn test_call() {
+        let template = compile("{{ call my_macro with nested }}");
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -439,14 +2828,20 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("5", &string);
+        assert_eq!("10", &string);
     }
 
     #[test]
-    fn test_path() {
-        let template = compile("The number of the day is { nested.value }.");
+    fn test_include() {
+        let template = compile(
+            "{{ let title = string }}{{ for a in array }}{{ include \"row\" }}{{ endfor }}",
+        );
         let context = context();
-        let template_registry = other_templates();
+        let mut template_registry = other_templates();
+        template_registry.insert(
+            "row",
+            compile("{{ let x = number }}[{ title }:{ a }:{ @index }:{ x }:{ @root.boolean }]"),
+        );
         let formatter_registry = formatters();
         let string = template
             .render(
@@ -456,14 +2851,28 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("The number of the day is 10.", &string);
+        assert_eq!(
+            "[test:1:0:5:true][test:2:1:5:true][test:3:2:5:true]",
+            &string
+        );
+
+        // Variables set in the included template are not visible after it.
+        let template = compile("{{ include \"row\" }}{ x }");
+        template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap_err();
     }
 
     #[test]
-    fn test_if_taken() {
-        let template = compile("{{ if boolean }}Hello!{{ endif }}");
+    fn test_include_extending_template() {
+        let template = compile("{{ include \"grandchild\" }}");
         let context = context();
-        let template_registry = other_templates();
+        let template_registry = inheritance_templates();
         let formatter_registry = formatters();
         let string = template
             .render(
@@ -473,31 +2882,36 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("Hello!", &string);
+        assert_eq!("<Grandchild Child Base|[1][2][3]>", &string);
     }
 
     #[test]
-    fn test_if_untaken() {
-        let template = compile("{{ if null }}Hello!{{ endif }}");
+    fn test_include_unknown_template() {
+        let template = compile("{{ include \"missing\" }}");
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
-        let string = template
+        template
             .render(
                 &context,
                 &template_registry,
                 &formatter_registry,
                 &default_formatter(),
             )
-            .unwrap();
-        assert_eq!("", &string);
+            .unwrap_err();
     }
 
     #[test]
-    fn test_if_else_taken() {
-        let template = compile("{{ if boolean }}Hello!{{ else }}Goodbye!{{ endif }}");
+    fn test_call_with_named_arguments() {
+        let template = compile(
+            r#"{{ for a in array }}{{ call card with title=string, value=a, class="wide" }}{{ endfor }}"#,
+        );
         let context = context();
-        let template_registry = other_templates();
+        let mut template_registry = other_templates();
+        template_registry.insert(
+            "card",
+            compile("{{ params title, value, class }}<{ title } { value } { class }>"),
+        );
         let formatter_registry = formatters();
         let string = template
             .render(
@@ -507,12 +2921,12 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("Hello!", &string);
+        assert_eq!("<test 1 wide><test 2 wide><test 3 wide>", &string);
     }
 
     #[test]
-    fn test_if_else_untaken() {
-        let template = compile("{{ if null }}Hello!{{ else }}Goodbye!{{ endif }}");
+    fn test_call_with_named_arguments_without_params() {
+        let template = compile("{{ call my_macro with value=nested.value, extra=true }}");
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -524,12 +2938,37 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("Goodbye!", &string);
+        assert_eq!("10", &string);
     }
 
     #[test]
-    fn test_ifnot_taken() {
-        let template = compile("{{ if not boolean }}Hello!{{ endif }}");
+    fn test_call_with_named_arguments_errors() {
+        let context = context();
+        let mut template_registry = other_templates();
+        template_registry.insert("card", compile("{{ params title, class }}{ title }"));
+        let formatter_registry = formatters();
+        for text in &[
+            "{{ call card with title=string }}",
+            "{{ call card with title=string, class=1, extra=2 }}",
+            "{{ call card with title=missing, class=1 }}",
+            "{{ call missing with title=string }}",
+        ] {
+            compile(text)
+                .render(
+                    &context,
+                    &template_registry,
+                    &formatter_registry,
+                    &default_formatter(),
+                )
+                .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_macro() {
+        let template = compile(
+            "{{ macro item(value) }}[{ value }]{{ endmacro }}{{ macro row(title, items) }}{ title }:{{ for a in items }}{{ call item with value=a }}{{ endfor }}{{ endmacro }}<{{ call row with title=string, items=array }}>",
+        );
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -541,12 +2980,14 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("", &string);
+        assert_eq!("<test:[1][2][3]>", &string);
     }
 
     #[test]
-    fn test_ifnot_untaken() {
-        let template = compile("{{ if not null }}Hello!{{ endif }}");
+    fn test_macro_takes_precedence_over_template() {
+        let template = compile(
+            "{{ macro my_macro(value) }}({ value }){{ endmacro }}{{ call my_macro with nested }}",
+        );
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -558,14 +2999,20 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("Hello!", &string);
+        assert_eq!("(10)", &string);
     }
 
     #[test]
-    fn test_ifnot_else_taken() {
-        let template = compile("{{ if not boolean }}Hello!{{ else }}Goodbye!{{ endif }}");
+    fn test_import_macro() {
+        let template = compile(
+            "{{ import \"forms\" }}{{ call field with label=string, value=number }}{{ call my_macro with nested }}",
+        );
         let context = context();
-        let template_registry = other_templates();
+        let mut template_registry = other_templates();
+        template_registry.insert(
+            "forms",
+            compile("{{ macro field(label, value) }}{{ call wrap with text=label }}={ value }{{ endmacro }}{{ macro wrap(text) }}<{ text }>{{ endmacro }}"),
+        );
         let formatter_registry = formatters();
         let string = template
             .render(
@@ -575,12 +3022,34 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("Goodbye!", &string);
+        assert_eq!("<test>=510", &string);
     }
 
     #[test]
-    fn test_ifnot_else_untaken() {
-        let template = compile("{{ if not null }}Hello!{{ else }}Goodbye!{{ endif }}");
+    fn test_macro_errors() {
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        for text in &[
+            "{{ macro card(title) }}{ title }{{ endmacro }}{{ call card with title=string, extra=1 }}",
+            "{{ macro card(title) }}{ title }{{ endmacro }}{{ call card with class=string }}",
+            "{{ macro card(title) }}{ string }{{ endmacro }}{{ call card with title=1 }}",
+            "{{ import \"missing\" }}{{ call card with title=1 }}",
+        ] {
+            compile(text)
+                .render(
+                    &context,
+                    &template_registry,
+                    &formatter_registry,
+                    &default_formatter(),
+                )
+                .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_raw() {
+        let template = compile("{{ raw }}{{#each items}}{ this }{{/each}}{{ endraw }} { number }");
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -592,13 +3061,13 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("Hello!", &string);
+        assert_eq!("{{#each items}}{ this }{{/each}} 5", &string);
     }
 
     #[test]
-    fn test_nested_ifs() {
+    fn test_literal_values() {
         let template = compile(
-            "{{ if boolean }}Hi, {{ if null }}there!{{ else }}Hello!{{ endif }}{{ endif }}",
+            r#"{ "<n/a>" } {{ let a = 42 }}{ a } {{ let b = -1.5 }}{ b } { number | join(true) }"#,
         );
         let context = context();
         let template_registry = other_templates();
@@ -611,13 +3080,13 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("Hi, Hello!", &string);
+        assert_eq!("&lt;n/a&gt; 42 -1.5 5true", &string);
     }
 
     #[test]
-    fn test_with() {
-        let template = compile("{{ with nested as n }}{ n.value } { number }{{endwith}}");
-        let context = context();
+    fn test_array_index_paths() {
+        let template = compile("{ 0 }-{ 1 }-{ 2.name }-{ 2.tags.0 }{{ if 2.name }}!{{ endif }}");
+        let context = ::serde_json::json!([7, 8, { "name": "c", "tags": ["d"] }]);
         let template_registry = other_templates();
         let formatter_registry = formatters();
         let string = template
@@ -628,12 +3097,14 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("10 5", &string);
+        assert_eq!("7-8-c-d!", &string);
     }
 
     #[test]
-    fn test_for_loop() {
-        let template = compile("{{ for a in array }}{ a }{{ endfor }}");
+    fn test_literal_conditions() {
+        let template = compile(
+            "{{ if \"a\" }}A{{ endif }}{{ if \"\" }}B{{ endif }}{{ if not \"\" }}C{{ endif }}{{ if number == 4 or boolean != true }}D{{ endif }}",
+        );
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -645,12 +3116,12 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("123", &string);
+        assert_eq!("AC", &string);
     }
 
     #[test]
-    fn test_for_loop_index() {
-        let template = compile("{{ for a in array }}{ @index }{{ endfor }}");
+    fn test_formatted_literal() {
+        let template = compile(r#"{ "a | b" | my_formatter }"#);
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -662,13 +3133,30 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("012", &string);
+        assert_eq!("{a | b}", &string);
     }
 
     #[test]
-    fn test_for_loop_first() {
-        let template =
-            compile("{{ for a in array }}{{if @first }}{ @index }{{ endif }}{{ endfor }}");
+    fn test_call_with_literal() {
+        let template = compile(r#"{{ call root_macro with "Hello" }}"#);
+        let context = context();
+        let mut template_registry = other_templates();
+        template_registry.insert("root_macro", compile("{ @root }!"));
+        let formatter_registry = formatters();
+        let string = template
+            .render(
+                &context,
+                &template_registry,
+                &formatter_registry,
+                &default_formatter(),
+            )
+            .unwrap();
+        assert_eq!("Hello!", &string);
+    }
+
+    #[test]
+    fn test_formatter() {
+        let template = compile("{ nested.value | my_formatter }");
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -680,13 +3168,12 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("0", &string);
+        assert_eq!("{10}", &string);
     }
 
     #[test]
-    fn test_for_loop_last() {
-        let template =
-            compile("{{ for a in array }}{{ if @last}}{ @index }{{ endif }}{{ endfor }}");
+    fn test_formatter_chain() {
+        let template = compile("{ nested.value | my_formatter | my_formatter }");
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -698,12 +3185,12 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("2", &string);
+        assert_eq!("{{10}}", &string);
     }
 
     #[test]
-    fn test_whitespace_stripping_value() {
-        let template = compile("1  \n\t   {- number -}  \n   1");
+    fn test_transformer_chain() {
+        let template = compile("{ number | double | double | my_formatter } { number | double }");
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -715,12 +3202,12 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("151", &string);
+        assert_eq!("{20} 10", &string);
     }
 
     #[test]
-    fn test_call() {
-        let template = compile("{{ call my_macro with nested }}");
+    fn test_formatter_arguments() {
+        let template = compile(r#"{ number | join("-") } { string | join(nested.value, "!") }"#);
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -732,12 +3219,13 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("10", &string);
+        assert_eq!("5- test10!", &string);
     }
 
     #[test]
-    fn test_formatter() {
-        let template = compile("{ nested.value | my_formatter }");
+    fn test_formatter_arguments_in_chain() {
+        let template =
+            compile(r#"{ number | join(1) | my_formatter } { number | double | join(number) }"#);
         let context = context();
         let template_registry = other_templates();
         let formatter_registry = formatters();
@@ -749,7 +3237,57 @@ mod test {
                 &default_formatter(),
             )
             .unwrap();
-        assert_eq!("{10}", &string);
+        assert_eq!("{51} 105", &string);
+    }
+
+    #[test]
+    fn test_formatter_argument_count() {
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        for text in &[
+            "\n{ number | join }",
+            "\n{ number | join(1, 2, 3) }",
+            "\n{ number | my_formatter(1) }",
+            "\n{ number | double(1) }",
+        ] {
+            let template = compile(text);
+            let err = template
+                .render(
+                    &context,
+                    &template_registry,
+                    &formatter_registry,
+                    &default_formatter(),
+                )
+                .unwrap_err();
+            if let CalledFormatterError { line, column, .. } = err {
+                assert_eq!(2, line);
+                assert_eq!(11, column);
+            } else {
+                panic!("Should have returned a called formatter error");
+            }
+        }
+    }
+
+    #[test]
+    fn test_formatter_chain_errors() {
+        let context = context();
+        let template_registry = other_templates();
+        let formatter_registry = formatters();
+        for text in &[
+            "{ number | my_formatter | missing }",
+            "{ number | my_formatter | double }",
+        ] {
+            let template = compile(text);
+            template
+                .render(
+                    &context,
+                    &template_registry,
+                    &formatter_registry,
+                    &default_formatter(),
+                )
+                .unwrap_err();
+        }
     }
 
     #[test]
@@ -791,7 +3329,7 @@ mod test {
         let context = context();
         let template_registry = other_templates();
         let mut formatter_registry = formatters();
-        formatter_registry.insert("unescaped", Box::new(::format_unescaped));
+        formatter_registry.insert("unescaped", Formatter::Value(Box::new(::format_unescaped)));
         let string = template
             .render(
                 &context,
@@ -807,7 +3345,7 @@ mod test {
     fn test_root_print() {
         let template = compile("{ @root }");
         let context = "Hello World!";
-        let context = ::serde_json::to_value(&context).unwrap();
+        let context = ::serde_json::to_value(context).unwrap();
         let template_registry = other_templates();
         let formatter_registry = formatters();
         let string = template
@@ -825,7 +3363,7 @@ mod test {
     fn test_root_branch() {
         let template = compile("{{ if @root }}Hello World!{{ endif }}");
         let context = true;
-        let context = ::serde_json::to_value(&context).unwrap();
+        let context = ::serde_json::to_value(context).unwrap();
         let template_registry = other_templates();
         let formatter_registry = formatters();
         let string = template
@@ -843,7 +3381,7 @@ mod test {
     fn test_root_iterate() {
         let template = compile("{{ for a in @root }}{ a }{{ endfor }}");
         let context = vec!["foo", "bar"];
-        let context = ::serde_json::to_value(&context).unwrap();
+        let context = ::serde_json::to_value(context).unwrap();
         let template_registry = other_templates();
         let formatter_registry = formatters();
         let string = template
@@ -861,7 +3399,7 @@ mod test {
     fn test_number_truthiness_zero() {
         let template = compile("{{ if @root }}truthy{{else}}not truthy{{ endif }}");
         let context = 0;
-        let context = ::serde_json::to_value(&context).unwrap();
+        let context = ::serde_json::to_value(context).unwrap();
         let template_registry = other_templates();
         let formatter_registry = formatters();
         let string = template
@@ -879,7 +3417,7 @@ mod test {
     fn test_number_truthiness_one() {
         let template = compile("{{ if @root }}truthy{{else}}not truthy{{ endif }}");
         let context = 1;
-        let context = ::serde_json::to_value(&context).unwrap();
+        let context = ::serde_json::to_value(context).unwrap();
         let template_registry = other_templates();
         let formatter_registry = formatters();
         let string = template
@@ -902,7 +3440,7 @@ mod test {
 
         let template = compile("{ foo.1 }{ foo.0 }");
         let context = Context { foo: (123, 456) };
-        let context = ::serde_json::to_value(&context).unwrap();
+        let context = ::serde_json::to_value(context).unwrap();
         let template_registry = other_templates();
         let formatter_registry = formatters();
         let string = template
@@ -928,7 +3466,7 @@ mod test {
         foo.insert("0", 123);
         foo.insert("1", 456);
         let context = Context { foo };
-        let context = ::serde_json::to_value(&context).unwrap();
+        let context = ::serde_json::to_value(context).unwrap();
         let template_registry = other_templates();
         let formatter_registry = formatters();
         let string = template
@@ -941,4 +3479,224 @@ mod test {
             .unwrap();
         assert_eq!("456123", &string);
     }
+
+    /// Render the template with the given context both converted to a Value and serialized
+    /// lazily, check that they agree and return the result.
+    fn render_both<C: ::serde::Serialize>(
+        template: &Template,
+        context: &C,
+        formatter_registry: &HashMap<&str, Formatter>,
+    ) -> Result<String> {
+        let template_registry = other_templates();
+        let value = ::serde_json::to_value(context).unwrap();
+        let converted = template.render(
+            &value,
+            &template_registry,
+            formatter_registry,
+            &default_formatter(),
+        );
+        let mut names = RootNames::default();
+        template.root_names(&mut names);
+        for other in template_registry.values() {
+            other.root_names(&mut names);
+        }
+        let lazy = template.render(
+            &Serialized::new(context, &names).unwrap(),
+            &template_registry,
+            formatter_registry,
+            &default_formatter(),
+        );
+        assert_eq!(
+            format!("{:?}", converted),
+            format!("{:?}", lazy),
+            "{}",
+            template.original_text
+        );
+        // Rendering with a context object which the render owns, pausing after every literal,
+        // produces the same output.
+        let owned =
+            ContextElement::OwnedObject(Box::new(Serialized::new(context, &names).unwrap()));
+        let paused = render_paused(template, owned, &template_registry, formatter_registry);
+        assert_eq!(
+            format!("{:?}", lazy),
+            format!("{:?}", paused.map(|output| output.buffer)),
+            "{}",
+            template.original_text
+        );
+        lazy
+    }
+
+    /// Output which pauses rendering after every literal, and counts the pauses.
+    struct PausingOutput {
+        buffer: String,
+        pauses: usize,
+    }
+    impl Output for PausingOutput {
+        fn buffer(&mut self) -> &mut String {
+            &mut self.buffer
+        }
+
+        fn after_literal(&mut self) -> Result<bool> {
+            self.pauses += 1;
+            Ok(true)
+        }
+    }
+
+    /// Render the template with the given object at the bottom of its context stack, resuming the
+    /// render each time it pauses.
+    fn render_paused<'a>(
+        template: &'a Template<'a>,
+        context: ContextElement<'a, 'a>,
+        template_registry: &'a HashMap<&'a str, Template<'a>>,
+        formatter_registry: &'a HashMap<&'a str, Formatter>,
+    ) -> Result<PausingOutput> {
+        let mut output = PausingOutput {
+            buffer: String::new(),
+            pauses: 0,
+        };
+        let mut render = Render::new(
+            template,
+            context,
+            template_registry,
+            formatter_registry,
+            default_formatter(),
+        )?;
+        while !render.resume(&mut output)? {}
+        Ok(output)
+    }
+
+    #[test]
+    fn test_render_pauses_after_literals() {
+        let context = context();
+        let mut template_registry = inheritance_templates();
+        template_registry.insert("row", compile("{{ let x = a }}[{ title }:{ x }]"));
+        template_registry.insert(
+            "macros",
+            compile("{{ macro item(value) }}<{ value }>{{ endmacro }}"),
+        );
+        template_registry.insert(
+            "page",
+            compile("{{ import \"macros\" }}{{ let title = string }}{{ for a in array }}{{ include \"row\" }}{{ call item with value=a }}{{ endfor }}|{{ call grandchild with @root }}|{ @root.number }"),
+        );
+        let formatter_registry = formatters();
+        for name in &["grandchild", "page"] {
+            let template = &template_registry[name];
+            let expected = template
+                .render(
+                    &context,
+                    &template_registry,
+                    &formatter_registry,
+                    &default_formatter(),
+                )
+                .unwrap();
+            let output = render_paused(
+                template,
+                ContextElement::Object(&context),
+                &template_registry,
+                &formatter_registry,
+            )
+            .unwrap();
+            assert_eq!(expected, output.buffer);
+            assert!(output.pauses > 1);
+        }
+
+        // Errors are reported the same way, wrapped in an error for each template they happened in.
+        template_registry.insert("broken", compile("|{{ call grandchild with nested }}"));
+        let template = compile("{{ include \"page\" }}{{ include \"broken\" }}");
+        let expected = template.render(
+            &context,
+            &template_registry,
+            &formatter_registry,
+            &default_formatter(),
+        );
+        let output = render_paused(
+            &template,
+            ContextElement::Object(&context),
+            &template_registry,
+            &formatter_registry,
+        );
+        assert_eq!(
+            format!("{:?}", expected.unwrap_err()),
+            format!("{:?}", output.map(|output| output.buffer).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_serialized_context() {
+        let formatter_registry = formatters();
+        for text in &[
+            "{ number } { string } { boolean } { null } { nested.value } { array.1 }",
+            "{{ for a in array }}{ a }{ @index }{{ endfor }}{{ for k, v in object }}{ k }{ v }{{ endfor }}",
+            "{{ with nested as n }}{ n.value }{{ endwith }}{{ let x = nested.value | double }}{ x }",
+            "{ @root.nested.value } {{ call my_macro with nested }}",
+            "{{ if number > 4 and boolean }}yes{{ endif }}{{ for a in empty_array }}{{ else }}empty{{ endfor }}",
+            "{ escapes }",
+        ] {
+            render_both(&compile(text), &test_context(), &formatter_registry).unwrap();
+        }
+        for text in &["{ nested.nothing }", "{{ for a in number }}{{ endfor }}"] {
+            render_both(&compile(text), &test_context(), &formatter_registry).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_nested_loops_over_owned_values() {
+        #[derive(Serialize)]
+        struct Row {
+            cells: Vec<usize>,
+            attrs: BTreeMap<&'static str, usize>,
+        }
+
+        #[derive(Serialize)]
+        struct Context {
+            table: Vec<Vec<usize>>,
+            rows: Vec<Row>,
+            cube: Vec<Vec<Vec<usize>>>,
+        }
+
+        let context = Context {
+            table: vec![vec![1, 2], vec![], vec![3]],
+            rows: vec![Row {
+                cells: vec![4, 5],
+                attrs: vec![("b", 2), ("a", 1)].into_iter().collect(),
+            }],
+            cube: vec![vec![vec![1, 2], vec![3]], vec![vec![4]]],
+        };
+        let formatter_registry = formatters();
+        let render = |text| render_both(&compile(text), &context, &formatter_registry).unwrap();
+
+        assert_eq!(
+            "0.0:1 0.1:2 |empty|2.0:3 |",
+            render("{{ for row in table }}{{ for value in row }}{ row.@index }.{ value.@index }:{ value } {{ else }}empty{{ endfor }}|{{ endfor }}")
+        );
+        assert_eq!(
+            "45a1b2;",
+            render("{{ for row in rows }}{{ for cell in row.cells }}{ cell }{{ endfor }}{{ for k, v in row.attrs }}{ k }{ v }{{ endfor }};{{ endfor }}")
+        );
+        assert_eq!(
+            "12,3,;4,;",
+            render("{{ for plane in cube }}{{ for row in plane }}{{ for v in row }}{ v }{{ endfor }},{{ endfor }};{{ endfor }}")
+        );
+        assert_eq!(
+            "1 3 ",
+            render("{{ with table as t }}{{ for row in t }}{{ for v in row }}{{ if @first }}{ v } {{ endif }}{{ endfor }}{{ endfor }}{{ endwith }}")
+        );
+    }
+
+    #[test]
+    fn test_for_loop_over_transformed_value() {
+        let template = compile(
+            "{{ let letters = string | letters }}{{ for letter in letters }}{ letter }-{{ endfor }}",
+        );
+        let mut formatter_registry = formatters();
+        formatter_registry.insert(
+            "letters",
+            Formatter::Transformer(Box::new(|value: &Value| {
+                let letters = value.as_str().unwrap_or_default().chars();
+                Ok(letters.map(|c| Value::from(c.to_string())).collect())
+            })),
+        );
+        let string = render_both(&template, &test_context(), &formatter_registry).unwrap();
+        assert_eq!("t-e-s-t-", &string);
+    }
 }